@@ -0,0 +1,231 @@
+#[macro_use]
+extern crate criterion;
+extern crate order_book;
+
+use criterion::{black_box, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use order_book::amount::Amount;
+use order_book::generate::{FeedConfig, FeedGenerator};
+use order_book::idpricecache::{
+    IdPriceCache, IdPriceCacheFnvMap, IdPriceCacheIdentityMap, IdPriceCacheRobinHood,
+    IdPriceCacheSlab, IdPriceCacheStdMap,
+};
+use order_book::orderbook::OrderBook;
+use order_book::orderids::IdMode;
+use order_book::orders::{LimitOrder, Message, ReduceOrder};
+use order_book::orderside::OrderSide;
+use order_book::pricer::Pricer;
+
+// run with
+// cargo bench --bench book
+// and to compare a change against the code before it
+// cargo bench --bench book -- --save-baseline before
+// cargo bench --bench book -- --baseline before
+
+const DEPTHS: [i64; 3] = [10, 100, 1000]; // price levels per side
+const TARGET_SIZES: [i64; 3] = [1, 200, 10000];
+const ORDERS_PER_LEVEL: i64 = 4;
+const ORDER_SIZE: i64 = 100;
+
+fn feed(messages: u64) -> Vec<String> {
+    let config = FeedConfig {
+        messages,
+        seed: 42,
+        ..FeedConfig::default()
+    };
+    FeedGenerator::new(config)
+        .map(|message| message.as_message().to_string())
+        .collect()
+}
+
+/// Bids from 44.00 down and asks from 44.01 up, depth levels each side
+/// with ORDERS_PER_LEVEL orders resting on every level
+fn orders(depth: i64) -> Vec<LimitOrder> {
+    let mut orders = Vec::new();
+    for level in 0..depth {
+        for n in 0..ORDERS_PER_LEVEL {
+            for &(side, price) in &[
+                (OrderSide::Bid, 4400 - level),
+                (OrderSide::Ask, 4401 + level),
+            ] {
+                orders.push(LimitOrder {
+                    timestamp: 28800000,
+                    id: orders.len() as u64,
+                    side,
+                    price: Amount { as_int: price },
+                    size: ORDER_SIZE + n,
+                });
+            }
+        }
+    }
+    orders
+}
+
+fn book<T: IdPriceCache>(cache: T, depth: i64) -> OrderBook<T> {
+    let mut book = OrderBook::new(200, cache);
+    for order in orders(depth) {
+        book.add(order);
+    }
+    book
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let feed = feed(100000);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(feed.len() as u64));
+    group.bench_function("text lines", |b| {
+        b.iter(|| {
+            for line in &feed {
+                black_box(Message::parse_with_seq(line).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+// every order of a book depth levels deep, into an empty book or into one that's
+// already that deep, so the adds land on existing levels
+fn bench_add(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add");
+    for &depth in &DEPTHS {
+        let adds = orders(depth);
+        group.throughput(Throughput::Elements(adds.len() as u64));
+        group.bench_with_input(BenchmarkId::new("new levels", depth), &adds, |b, adds| {
+            b.iter_batched(
+                || OrderBook::new(200, IdPriceCacheFnvMap::default()),
+                |mut book| {
+                    for order in adds {
+                        book.add(*order);
+                    }
+                    book
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        let offset = adds.len() as u64;
+        group.bench_with_input(
+            BenchmarkId::new("existing levels", depth),
+            &adds,
+            |b, adds| {
+                b.iter_batched(
+                    || book(IdPriceCacheFnvMap::default(), depth),
+                    |mut book| {
+                        for order in adds {
+                            book.add(LimitOrder {
+                                id: order.id + offset,
+                                ..*order
+                            });
+                        }
+                        book
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+// every order of a book depth levels deep reduced, half of it and then the rest
+fn bench_reduce_order(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reduce_order");
+    for &depth in &DEPTHS {
+        let reduces: Vec<ReduceOrder> = orders(depth)
+            .iter()
+            .flat_map(|order| {
+                let half = order.size / 2;
+                vec![(order.id, half), (order.id, order.size - half)]
+            })
+            .map(|(id, size)| ReduceOrder {
+                timestamp: 28800001,
+                id,
+                size,
+            })
+            .collect();
+        group.throughput(Throughput::Elements(reduces.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(depth),
+            &reduces,
+            |b, reduces| {
+                b.iter_batched(
+                    || book(IdPriceCacheFnvMap::default(), depth),
+                    |mut book| {
+                        for reduce in reduces {
+                            book.reduce_order(reduce);
+                        }
+                        book
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_summarise(c: &mut Criterion) {
+    let mut group = c.benchmark_group("summarise_target");
+    for &depth in &DEPTHS {
+        let book = book(IdPriceCacheFnvMap::default(), depth);
+        for &target_size in &TARGET_SIZES {
+            let id = BenchmarkId::new(format!("target {}", target_size), depth);
+            group.bench_with_input(id, &target_size, |b, &target_size| {
+                b.iter(|| {
+                    black_box(book.summarise_side(OrderSide::Bid, black_box(target_size)));
+                    black_box(book.summarise_side(OrderSide::Ask, black_box(target_size)))
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn replay<T: IdPriceCache>(cache: T, id_mode: IdMode, feed: &[String]) -> u64 {
+    let book = OrderBook::with_id_mode(200, cache, id_mode);
+    let mut pricer = Pricer::with_targets(book, &TARGET_SIZES);
+    for line in feed {
+        black_box(pricer.try_process(line).unwrap());
+    }
+    pricer.messages()
+}
+
+// the whole pricer on a generated feed, once per IdPriceCache
+fn bench_replay(c: &mut Criterion) {
+    let feed = feed(100000);
+    let cap = 4096;
+    let mut group = c.benchmark_group("replay generated feed");
+    group.throughput(Throughput::Elements(feed.len() as u64));
+    group.sample_size(20);
+    group.bench_function("fnv", |b| {
+        b.iter(|| replay(IdPriceCacheFnvMap::default(), IdMode::Hash, &feed))
+    });
+    group.bench_function("std", |b| {
+        b.iter(|| replay(IdPriceCacheStdMap::with_capacity(cap), IdMode::Hash, &feed))
+    });
+    group.bench_function("identity", |b| {
+        b.iter(|| replay(IdPriceCacheIdentityMap::default(), IdMode::Hash, &feed))
+    });
+    group.bench_function("robinhood", |b| {
+        b.iter(|| {
+            replay(
+                IdPriceCacheRobinHood::with_capacity(cap),
+                IdMode::Hash,
+                &feed,
+            )
+        })
+    });
+    group.bench_function("slab", |b| {
+        b.iter(|| replay(IdPriceCacheSlab::with_capacity(cap), IdMode::Intern, &feed))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_parse,
+    bench_add,
+    bench_reduce_order,
+    bench_summarise,
+    bench_replay
+);
+criterion_main!(benches);
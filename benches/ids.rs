@@ -0,0 +1,78 @@
+#[macro_use]
+extern crate criterion;
+extern crate order_book;
+
+use criterion::{black_box, Criterion};
+
+use order_book::idpricecache::{IdPriceCache, IdPriceCacheFnvMap, IdPriceCacheIdentityMap};
+use order_book::orderbook::OrderBook;
+use order_book::orderids::IdMode;
+
+// run with
+// cargo bench --bench ids
+
+/// Feed with numeric order ids, like most exchange feeds: every order gets added
+/// and then fully reduced a few messages later
+fn numeric_feed(orders: u64) -> Vec<String> {
+    let mut lines = Vec::with_capacity(orders as usize * 2);
+    for id in 0..orders {
+        let side = if id % 2 == 0 { "B" } else { "S" };
+        let price = if side == "B" {
+            4400 - id % 20
+        } else {
+            4410 + id % 20
+        };
+        lines.push(format!(
+            "{} A {} {} {}.{:02} 100",
+            28800000 + id,
+            100000 + id,
+            side,
+            price / 100,
+            price % 100
+        ));
+        if id >= 8 {
+            lines.push(format!("{} R {} 100", 28800000 + id, 100000 + id - 8));
+        }
+    }
+    lines
+}
+
+fn replay<T: IdPriceCache>(mut ob: OrderBook<T>, feed: &[String]) {
+    for line in feed {
+        ob.process(line);
+        black_box(ob.summarise_target());
+    }
+}
+
+fn bench_ids(c: &mut Criterion) {
+    let feed = numeric_feed(10000);
+    let mut group = c.benchmark_group("numeric ids");
+    group.bench_function("fnv hash", |b| {
+        b.iter(|| {
+            replay(
+                OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), IdMode::Hash),
+                &feed,
+            )
+        })
+    });
+    group.bench_function("numeric fnv", |b| {
+        b.iter(|| {
+            replay(
+                OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), IdMode::Numeric),
+                &feed,
+            )
+        })
+    });
+    group.bench_function("numeric identity", |b| {
+        b.iter(|| {
+            replay(
+                OrderBook::with_id_mode(200, IdPriceCacheIdentityMap::default(), IdMode::Numeric),
+                &feed,
+            )
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_ids);
+criterion_main!(benches);
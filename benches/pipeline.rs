@@ -0,0 +1,95 @@
+#[macro_use]
+extern crate criterion;
+extern crate order_book;
+
+use criterion::{black_box, Criterion, Throughput};
+
+use order_book::idpricecache::IdPriceCacheFnvMap;
+use order_book::orderbook::OrderBook;
+use order_book::orders::Message;
+use order_book::pipeline::price_pipelined;
+use order_book::pricer::Pricer;
+
+// run with
+// cargo bench --bench pipeline
+
+/// Feed of orders around 44.00 that mostly get fully reduced a few messages later,
+/// so the top of the book keeps moving and the pricer has something to report
+fn generated_feed(orders: u64) -> Vec<String> {
+    let mut lines = Vec::with_capacity(orders as usize * 2);
+    for id in 0..orders {
+        let side = if id % 2 == 0 { "B" } else { "S" };
+        let price = if side == "B" {
+            4400 - (id * 7) % 20
+        } else {
+            4401 + (id * 7) % 20
+        };
+        lines.push(format!(
+            "{} A o{} {} {}.{:02} {}",
+            28800000 + id,
+            id,
+            side,
+            price / 100,
+            price % 100,
+            50 + id % 200
+        ));
+        if id >= 16 {
+            let size = 50 + (id - 16) % 200;
+            lines.push(format!("{} R o{} {}", 28800000 + id, id - 16, size));
+        }
+    }
+    lines
+}
+
+fn pricer() -> Pricer<IdPriceCacheFnvMap> {
+    Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()))
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let feed = generated_feed(50000);
+    let mut group = c.benchmark_group("price generated feed");
+    group.throughput(Throughput::Elements(feed.len() as u64));
+    group.bench_function("single thread", |b| {
+        b.iter(|| {
+            let mut pricer = pricer();
+            let mut out = Vec::new();
+            for line in &feed {
+                let message = Message::parse_with_seq(line).unwrap();
+                for event in pricer.try_apply_sequenced(&message.0, message.1).unwrap() {
+                    out.push(event.to_string());
+                }
+            }
+            black_box(out)
+        })
+    });
+    for &capacity in &[64, 4096] {
+        group.bench_function(format!("pipeline {}", capacity), |b| {
+            b.iter(|| {
+                let mut out = Vec::new();
+                price_pipelined(
+                    pricer(),
+                    capacity,
+                    |feeder| {
+                        for (idx, line) in feed.iter().enumerate() {
+                            feeder.feed(idx, Message::parse_with_seq(line));
+                        }
+                        Ok(())
+                    },
+                    |_| {},
+                    |priced| {
+                        for event in priced.events.unwrap() {
+                            out.push(event.to_string());
+                        }
+                        Ok(())
+                    },
+                )
+                .unwrap();
+                black_box(out)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use order_book::amount::Amount;
+
+// new_from_str is only meant for strings that parse, it panics on the rest
+fuzz_target!(|data: &[u8]| {
+    let field = match std::str::from_utf8(data) {
+        Ok(field) => field,
+        Err(_) => return,
+    };
+    if let Ok(amount) = field.parse::<Amount>() {
+        assert_eq!(Amount::new_from_str(field), amount);
+        let written = amount.to_string();
+        assert_eq!(written.parse::<Amount>(), Ok(amount), "{:?}", written);
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use order_book::orders::{LevelUpdate, Message};
+
+// Any line at all, parsed as an order and as a price level. Whatever parses
+// has to come back the same after being written out and parsed again
+fuzz_target!(|data: &[u8]| {
+    let line = match std::str::from_utf8(data) {
+        Ok(line) => line,
+        Err(_) => return,
+    };
+    if let Ok((message, _seq)) = Message::parse_with_seq(line) {
+        let written = message.to_string();
+        assert_eq!(Message::parse(&written), Ok(message), "{:?}", written);
+    }
+    let _ = LevelUpdate::parse_with_seq(line);
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use order_book::binfeed::read_message;
+use order_book::idpricecache::IdPriceCacheFnvMap;
+use order_book::orderbook::OrderBook;
+use order_book::orderside::OrderSide;
+use order_book::pricer::Pricer;
+
+// process_feed for binary records, see order_book::binfeed. Target size first,
+// then records until the input runs out or one doesn't decode
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+    let (target, mut feed) = data.split_at(8);
+    let target = i64::from_le_bytes(target.try_into().unwrap());
+    let mut pricer = Pricer::with_targets(
+        OrderBook::new(target, IdPriceCacheFnvMap::default()),
+        &[target, 1, 200],
+    );
+    let mut buf = Vec::new();
+    while let Ok(Some(message)) = read_message(&mut feed, &mut buf) {
+        let _ = pricer.try_apply(&message);
+        let book = pricer.book();
+        for &side in &[OrderSide::Bid, OrderSide::Ask] {
+            let _ = book.summarise_side(side, target);
+        }
+    }
+});
@@ -0,0 +1,38 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use order_book::idpricecache::IdPriceCacheFnvMap;
+use order_book::orderbook::OrderBook;
+use order_book::orders::LevelUpdate;
+use order_book::orderside::OrderSide;
+use order_book::pricer::Pricer;
+
+// A whole feed through the pricer, orders, price levels and bad lines mixed,
+// priced at a target size taken from the first 8 bytes (little endian).
+// Nothing in it may panic
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+    let (target, feed) = data.split_at(8);
+    let target = i64::from_le_bytes(target.try_into().unwrap());
+    let feed = String::from_utf8_lossy(feed);
+    let mut pricer = Pricer::with_targets(
+        OrderBook::new(target, IdPriceCacheFnvMap::default()),
+        &[target, 1, 200],
+    );
+    for line in feed.lines() {
+        match LevelUpdate::parse(line) {
+            Ok(update) => drop(pricer.try_apply_level(&update)),
+            Err(_) => drop(pricer.try_process(line)),
+        }
+        let book = pricer.book();
+        for &side in &[OrderSide::Bid, OrderSide::Ask] {
+            let _ = book.summarise_side(side, target);
+            let _ = book.levels(side).count();
+        }
+        for episode in book.episodes().iter().chain(book.current_episode()) {
+            let _ = episode.duration();
+        }
+    }
+});
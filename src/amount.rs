@@ -1,5 +1,7 @@
 use std::fmt::{Display, Formatter, Result};
+use std::num::ParseFloatError;
 use std::ops::{AddAssign, Mul, MulAssign};
+use std::str::FromStr;
 
 use bidamount::BidAmount;
 
@@ -17,14 +19,33 @@ impl Amount {
     }
 
     pub fn new_from_str(input_string: &str) -> Self {
-        let float_from_input = input_string.parse::<f64>();
-        let float_res = match float_from_input {
-            Ok(number_to_round) => number_to_round,
+        match input_string.parse::<Amount>() {
+            Ok(amount) => amount,
             Err(err) => panic!("Input string {} doesn't parse as f64 {}", input_string, err),
-        };
+        }
+    }
+
+    /// None if the sum doesn't fit in an i64
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.as_int
+            .checked_add(other.as_int)
+            .map(|as_int| Amount { as_int })
+    }
+
+    /// None if the product doesn't fit in an i64
+    pub fn checked_mul(self, rhs: i64) -> Option<Self> {
+        self.as_int.checked_mul(rhs).map(|as_int| Amount { as_int })
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ParseFloatError;
+
+    fn from_str(input_string: &str) -> ::std::result::Result<Self, Self::Err> {
+        let float_res = input_string.parse::<f64>()?;
         let float_times_hundred = float_res * 100.0;
         let int_res = float_times_hundred.round() as i64;
-        Amount { as_int: int_res }
+        Ok(Amount { as_int: int_res })
     }
 }
 
@@ -51,11 +72,13 @@ impl Mul<i64> for Amount {
 
 impl Display for Amount {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let repr = self.as_int.to_string();
+        // pad so there's always a digit left of the separator, "0.05" rather than ".05"
+        let repr = format!("{:03}", self.as_int.unsigned_abs());
+        let sign = if self.as_int < 0 { "-" } else { "" };
         let decimal_points = 2;
         let idx = repr.len() - decimal_points;
         let (quot_x, rem_x) = repr.split_at(idx);
-        write!(f, "{}.{}", quot_x, rem_x)
+        write!(f, "{}{}.{}", sign, quot_x, rem_x)
     }
 }
 
@@ -76,6 +99,7 @@ impl<'a> From<&'a BidAmount> for Amount {
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
 
@@ -97,6 +121,12 @@ mod tests {
         Amount::new_from_str(&"asda");
     }
 
+    #[test]
+    fn from_str_works() {
+        assert_eq!("44.12".parse::<Amount>(), Ok(Amount { as_int: 4412 }));
+        assert!("asda".parse::<Amount>().is_err());
+    }
+
     #[test]
     fn multiply_by_zero() {
         let mut am = Amount::new_from_str(&"44.12");
@@ -129,6 +159,15 @@ mod tests {
         assert_eq!(res, input_string);
     }
 
+    #[test]
+    fn display_small_and_negative() {
+        assert_eq!(Amount { as_int: 5 }.to_string(), "0.05");
+        assert_eq!(Amount { as_int: 50 }.to_string(), "0.50");
+        assert_eq!(Amount { as_int: 0 }.to_string(), "0.00");
+        assert_eq!(Amount { as_int: -4412 }.to_string(), "-44.12");
+        assert_eq!(Amount { as_int: -5 }.to_string(), "-0.05");
+    }
+
     #[test]
     fn convert_from_bidamount_to_amount() {
         let ba = BidAmount::new();
@@ -7,7 +7,7 @@ use amount::Amount;
 // run unit tests with
 // cargo test -- amount
 
-#[derive(Copy, Clone, Debug, Eq)] // allows us to use BidAmount as a HashMap key
+#[derive(Copy, Clone, Debug, Default, Eq)] // allows us to use BidAmount as a HashMap key
 pub struct BidAmount {
     pub as_int: i64,
 }
@@ -17,6 +17,20 @@ impl BidAmount {
         BidAmount { as_int: 0 }
     }
 
+    /// None if the sum doesn't fit in an i64
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.as_int
+            .checked_add(other.as_int)
+            .map(|as_int| BidAmount { as_int })
+    }
+
+    /// None if the product doesn't fit in an i64
+    pub fn checked_mul(self, rhs: i64) -> Option<Self> {
+        self.as_int
+            .checked_mul(rhs)
+            .map(|as_int| BidAmount { as_int })
+    }
+
     #[cfg(test)]
     fn new_from_str(input_string: &str) -> Self {
         let float_from_input = input_string.parse::<f64>();
@@ -75,11 +89,13 @@ impl Mul<i64> for BidAmount {
 
 impl Display for BidAmount {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let repr = self.as_int.to_string();
+        // pad so there's always a digit left of the separator, "0.05" rather than ".05"
+        let repr = format!("{:03}", self.as_int.unsigned_abs());
+        let sign = if self.as_int < 0 { "-" } else { "" };
         let decimal_points = 2;
         let idx = repr.len() - decimal_points;
         let (quot_x, rem_x) = repr.split_at(idx);
-        write!(f, "{}.{}", quot_x, rem_x)
+        write!(f, "{}{}.{}", sign, quot_x, rem_x)
     }
 }
 
@@ -100,6 +116,7 @@ impl<'a> From<&'a Amount> for BidAmount {
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow, clippy::redundant_closure)]
 mod tests {
     use super::*;
 
@@ -153,6 +170,15 @@ mod tests {
         assert_eq!(res, input_string);
     }
 
+    #[test]
+    fn display_small_and_negative() {
+        assert_eq!(BidAmount { as_int: 5 }.to_string(), "0.05");
+        assert_eq!(BidAmount { as_int: 50 }.to_string(), "0.50");
+        assert_eq!(BidAmount { as_int: 0 }.to_string(), "0.00");
+        assert_eq!(BidAmount { as_int: -4412 }.to_string(), "-44.12");
+        assert_eq!(BidAmount { as_int: -5 }.to_string(), "-0.05");
+    }
+
     #[test]
     fn compare_equals() {
         let input_string = "44.12";
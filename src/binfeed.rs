@@ -0,0 +1,238 @@
+// Binary encoding of the feed. Every record starts with the message type byte,
+// integers are little endian and prices are whole cents:
+//
+//   Add:    b'A' timestamp:i64 side:u8 (b'B' or b'S') price:i64 size:i64 id_len:u8 id
+//   Reduce: b'R' timestamp:i64 size:i64 id_len:u8 id
+//
+// Ids are at most 255 bytes of UTF-8. Prices and sizes are held to the same
+// bounds as text lines, see orders::MAX_PRICE and orders::MAX_SIZE
+
+use std::io::{self, Read, Write};
+use std::str;
+
+use amount::Amount;
+use orders::{price_in_range, size_in_range, Message};
+use orderside::OrderSide;
+
+const ADD: u8 = b'A';
+const REDUCE: u8 = b'R';
+
+// bytes after the type byte, up to and including id_len
+const ADD_FIXED_LEN: usize = 8 + 1 + 8 + 8 + 1;
+const REDUCE_FIXED_LEN: usize = 8 + 8 + 1;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_i64(bytes: &[u8]) -> i64 {
+    let mut le = [0u8; 8];
+    le.copy_from_slice(&bytes[..8]);
+    i64::from_le_bytes(le)
+}
+
+/// Writes one record
+pub fn write_message<W: Write>(out: &mut W, message: &Message) -> io::Result<()> {
+    let id = message.id().as_bytes();
+    if id.len() > u8::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("order id {} is longer than 255 bytes", message.id()),
+        ));
+    }
+    match *message {
+        Message::Add {
+            timestamp,
+            side,
+            price,
+            size,
+            ..
+        } => {
+            out.write_all(&[ADD])?;
+            out.write_all(&timestamp.to_le_bytes())?;
+            out.write_all(&[match side {
+                OrderSide::Bid => b'B',
+                OrderSide::Ask => b'S',
+            }])?;
+            out.write_all(&price.as_int.to_le_bytes())?;
+            out.write_all(&size.to_le_bytes())?;
+        }
+        Message::Reduce {
+            timestamp, size, ..
+        } => {
+            out.write_all(&[REDUCE])?;
+            out.write_all(&timestamp.to_le_bytes())?;
+            out.write_all(&size.to_le_bytes())?;
+        }
+    }
+    out.write_all(&[id.len() as u8])?;
+    out.write_all(id)
+}
+
+/// Reads the next record into buf and decodes it, the message borrows its id from buf.
+/// Returns None at a clean end of input, a record cut short is an UnexpectedEof error
+pub fn read_message<'a, R: Read>(
+    input: &mut R,
+    buf: &'a mut Vec<u8>,
+) -> io::Result<Option<Message<'a>>> {
+    let mut tag = [0u8; 1];
+    loop {
+        match input.read(&mut tag) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    let fixed_len = match tag[0] {
+        ADD => ADD_FIXED_LEN,
+        REDUCE => REDUCE_FIXED_LEN,
+        other => return Err(invalid_data(format!("unknown record type {:#04x}", other))),
+    };
+    buf.clear();
+    buf.resize(fixed_len, 0);
+    input.read_exact(buf)?;
+    let id_len = buf[fixed_len - 1] as usize;
+    buf.resize(fixed_len + id_len, 0);
+    input.read_exact(&mut buf[fixed_len..])?;
+
+    let buf: &'a [u8] = buf;
+    let id = str::from_utf8(&buf[fixed_len..])
+        .map_err(|_| invalid_data("order id isn't valid UTF-8".to_string()))?;
+    let timestamp = read_i64(buf);
+    let size = read_i64(&buf[if tag[0] == ADD { 17 } else { 8 }..]);
+    if !size_in_range(size) {
+        return Err(invalid_data(format!("size {} out of range", size)));
+    }
+    let message = match tag[0] {
+        ADD => {
+            let price = Amount {
+                as_int: read_i64(&buf[9..]),
+            };
+            if !price_in_range(price) {
+                return Err(invalid_data(format!("price {} out of range", price)));
+            }
+            Message::Add {
+                timestamp,
+                id,
+                side: match buf[8] {
+                    b'B' => OrderSide::Bid,
+                    b'S' => OrderSide::Ask,
+                    other => return Err(invalid_data(format!("bad order side {:#04x}", other))),
+                },
+                price,
+                size,
+            }
+        }
+        _ => Message::Reduce {
+            timestamp,
+            id,
+            size,
+        },
+    };
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINES: [&str; 4] = [
+        "28800538 A b S 44.26 100",
+        "28800562 A c B 44.10 100",
+        "28800744 R b 100",
+        "28800758 A order-with-a-longer-id B 0.05 157",
+    ];
+
+    fn encoded(lines: &[&str]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for line in lines {
+            write_message(&mut out, &Message::parse(line).unwrap()).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let bytes = encoded(&LINES);
+        let mut input = &bytes[..];
+        let mut buf = Vec::new();
+        let mut decoded = Vec::new();
+        while let Some(msg) = read_message(&mut input, &mut buf).unwrap() {
+            decoded.push(msg.to_string());
+        }
+        assert_eq!(decoded, LINES);
+    }
+
+    #[test]
+    fn record_layout() {
+        let bytes = encoded(&["28800744 R b 20"]);
+        let mut expected = vec![b'R'];
+        expected.extend_from_slice(&28800744i64.to_le_bytes());
+        expected.extend_from_slice(&20i64.to_le_bytes());
+        expected.extend_from_slice(&[1, b'b']);
+        assert_eq!(bytes, expected);
+        assert_eq!(encoded(&[LINES[0]]).len(), 1 + ADD_FIXED_LEN + 1);
+    }
+
+    #[test]
+    fn truncated_record_is_an_error() {
+        let bytes = encoded(&[LINES[0]]);
+        let mut input = &bytes[..bytes.len() - 1];
+        let err = read_message(&mut input, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn corrupt_records_are_invalid_data() {
+        let mut bytes = encoded(&[LINES[0]]);
+        bytes[9] = b'X'; // side
+        let err = read_message(&mut &bytes[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = read_message(&mut &b"Z"[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut bytes = encoded(&["28800744 R b 20"]);
+        *bytes.last_mut().unwrap() = 0xff;
+        let err = read_message(&mut &bytes[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn out_of_range_records_are_invalid_data() {
+        let records: [(&str, usize); 3] = [
+            (LINES[0], 10),         // price
+            (LINES[0], 18),         // size
+            ("28800744 R b 20", 9), // size
+        ];
+        for &(line, at) in &records {
+            let mut bytes = encoded(&[line]);
+            for &value in &[-1i64, ::orders::MAX_PRICE + 1, i64::MIN] {
+                if at == 10 && value == -1 {
+                    continue; // negative prices are fine
+                }
+                bytes[at..at + 8].copy_from_slice(&value.to_le_bytes());
+                let err = read_message(&mut &bytes[..], &mut Vec::new()).unwrap_err();
+                assert_eq!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData,
+                    "{} at {}",
+                    value,
+                    at
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn long_ids_are_refused() {
+        let id = "x".repeat(256);
+        let msg = Message::Reduce {
+            timestamp: 1,
+            id: &id,
+            size: 1,
+        };
+        assert!(write_message(&mut Vec::new(), &msg).is_err());
+    }
+}
@@ -0,0 +1,843 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+
+use order_book::amount::Amount;
+use order_book::generate::FeedConfig;
+use order_book::orderids::IdMode;
+use order_book::orders::MAX_SIZE;
+use order_book::output::OutputFormat;
+use order_book::sequence::SeqPolicy;
+
+pub const USAGE: &str = "\
+Usage: order_book [price] [OPTIONS] <TARGET_SIZE>...
+       order_book validate [OPTIONS]
+       order_book stats [OPTIONS]
+       order_book snapshot --at <TIMESTAMP> [OPTIONS]
+       order_book convert [--to <FORMAT>] [OPTIONS]
+       order_book generate [--to <FORMAT>] [OPTIONS]
+
+Commands:
+  price     print the amount earned from selling, or spent on buying,
+            TARGET_SIZE shares every time it changes. Several target sizes
+            can be priced off the same book. The default command
+  validate  report malformed messages, reduces for unknown orders,
+            over-reductions and crossed books. Exits with 1 if it found any
+  stats     count messages by type, the most price levels seen per side
+            and how many ids the cache ended up holding
+  snapshot  print the price levels resting in the book at TIMESTAMP
+  convert   rewrite a feed from text to binary or the other way around
+  generate  write a synthetic feed, the same one for the same seed and options
+
+Options:
+  -i, --input <PATH>            feed to read, - for stdin [default: -]
+  -o, --output <PATH>           where to write results, - for stdout [default: -]
+      --input-format <FORMAT>   text, binary or mbp (price levels, price and snapshot only) [default: text]
+      --output-format <FORMAT>  price output, text, jsonl or csv [default: text]
+      --cache <KIND>            price cache, fnv, std, identity, robinhood or slab
+                                (slab needs --ids intern) [default: fnv]
+      --ids <MODE>              hash, checked, intern or numeric [default: hash]
+      --cache-capacity <N>      order ids to allocate room for up front [default: 50000]
+      --level-capacity <N>      price levels per side to allocate up front [default: 256]
+      --on-error <POLICY>       halt, warn or skip bad messages [default: halt]
+      --on-gap <POLICY>         sequence gaps, duplicates and timestamps going back:
+                                halt, warn or snapshot (warn and ask for one) [default: warn]
+      --when-crossed <ACTION>   price output while the book is crossed, report or suppress [default: report]
+      --at <TIMESTAMP>          snapshot after every message up to TIMESTAMP
+      --orders                  snapshot the orders resting at each level with their ids instead,
+                                needs --ids checked, intern or numeric
+      --to <FORMAT>             convert to text or binary [default: whichever the input isn't],
+                                generate text or binary [default: text]
+      --feed-listen <ADDR>      read the feed from the first TCP connection to ADDR instead of --input
+      --multicast <GROUP:PORT>  read text or binary messages off UDP multicast packets instead of --input,
+                                until the session ends
+      --multicast-if <ADDR>     interface to join the multicast group on [default: any]
+      --serve <ADDR>            price only, also stream events to every TCP connection to ADDR
+      --subscriber-buffer <N>   events a subscriber can fall behind before it's dropped [default: 1024]
+      --wait-subscribers <N>    with --serve, wait for N subscribers before reading the feed [default: 0]
+      --query-socket <PATH>     price only, answer queries about the book on a Unix domain socket at PATH
+      --pipeline <N>            price only, parse, price and write on three threads with up to N messages
+                                queued between them [default: all on one thread]
+      --shards <N>              price only, text lines starting with a symbol, one book per symbol
+                                spread over N worker threads, output lines start with the symbol
+      --latency                 price only, time parsing, applying, pricing and writing each message
+                                and print percentiles per message type to stderr at exit or on SIGUSR1
+      --metrics-listen <ADDR>   price only, serve counters and gauges in Prometheus text format
+                                over HTTP at ADDR/metrics
+      --metrics-file <PATH>     price only, write the same metrics to PATH at exit or on SIGUSR1
+
+Generate options:
+      --messages <N>            messages to write [default: 100000]
+      --live-orders <N>         most orders resting at once [default: 1000]
+      --seed <N>                random seed [default: 1]
+      --start-price <PRICE>     where the mid price starts [default: 44.00]
+      --tick <PRICE>            price increment [default: 0.01]
+      --walk <P>                chance the mid moves a tick with each message [default: 0.1]
+      --depth-ticks <N>         most ticks away from the mid orders rest [default: 20]
+      --add-ratio <P>           share of adds while under --live-orders [default: 0.5]
+      --partial-ratio <P>       share of reduces that leave part of the order [default: 0.3]
+      --max-size <N>            largest order size [default: 500]
+
+  -h, --help                    print this message
+  -V, --version                 print the version
+";
+
+/// IdPriceCache implementation to run with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CacheKind {
+    Fnv,
+    Std,
+    Identity,
+    RobinHood,
+    Slab,
+}
+
+impl FromStr for CacheKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fnv" => Ok(CacheKind::Fnv),
+            "std" => Ok(CacheKind::Std),
+            "identity" => Ok(CacheKind::Identity),
+            "robinhood" => Ok(CacheKind::RobinHood),
+            "slab" => Ok(CacheKind::Slab),
+            other => Err(format!(
+                "Unknown cache {}, expected one of fnv, std, identity, robinhood, slab",
+                other
+            )),
+        }
+    }
+}
+
+/// Feed encoding, for reading and for convert to write
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InputFormat {
+    /// "28800538 A b S 44.26 100" and "28800744 R b 20" lines
+    Text,
+    /// records laid out in order_book::binfeed
+    Binary,
+    /// market by price, "28800538 L B 44.26 500" and "28800540 D B 44.26" lines
+    Mbp,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(InputFormat::Text),
+            "binary" => Ok(InputFormat::Binary),
+            "mbp" => Ok(InputFormat::Mbp),
+            other => Err(format!(
+                "Unknown feed format {}, expected one of text, binary, mbp",
+                other
+            )),
+        }
+    }
+}
+
+/// What to run over the feed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Subcommand {
+    Price,
+    Validate,
+    Stats,
+    Snapshot,
+    Convert,
+    Generate,
+}
+
+impl FromStr for Subcommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "price" => Ok(Subcommand::Price),
+            "validate" => Ok(Subcommand::Validate),
+            "stats" => Ok(Subcommand::Stats),
+            "snapshot" => Ok(Subcommand::Snapshot),
+            "convert" => Ok(Subcommand::Convert),
+            "generate" => Ok(Subcommand::Generate),
+            other => Err(format!("Unknown command {}", other)),
+        }
+    }
+}
+
+/// What to do with a line the book can't apply
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorPolicy {
+    /// stop and exit with an error
+    Halt,
+    /// report it on stderr and carry on
+    Warn,
+    /// carry on without a word
+    Skip,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halt" => Ok(ErrorPolicy::Halt),
+            "warn" => Ok(ErrorPolicy::Warn),
+            "skip" => Ok(ErrorPolicy::Skip),
+            other => Err(format!(
+                "Unknown error policy {}, expected one of halt, warn, skip",
+                other
+            )),
+        }
+    }
+}
+
+/// What price does while best bid is above best ask
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CrossedPolicy {
+    Report,
+    Suppress,
+}
+
+impl FromStr for CrossedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(CrossedPolicy::Report),
+            "suppress" => Ok(CrossedPolicy::Suppress),
+            other => Err(format!(
+                "Unknown crossed book action {}, expected one of report, suppress",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub target_sizes: Vec<i64>,
+    pub input: Option<String>,  // None reads stdin
+    pub output: Option<String>, // None writes to stdout
+    pub input_format: InputFormat,
+    pub output_format: OutputFormat,
+    pub cache: CacheKind,
+    pub id_mode: IdMode,
+    pub cache_capacity: usize,
+    pub level_capacity: usize,
+    pub on_error: ErrorPolicy,
+    pub on_gap: SeqPolicy,
+    pub when_crossed: CrossedPolicy,
+    pub at: Option<i64>,                 // snapshot timestamp
+    pub orders: bool,                    // snapshot orders rather than levels
+    pub convert_to: Option<InputFormat>, // None converts to the other format
+    pub feed_listen: Option<String>,
+    pub multicast: Option<SocketAddrV4>,
+    pub multicast_if: Ipv4Addr,
+    pub serve: Option<String>,
+    pub subscriber_buffer: usize,
+    pub wait_subscribers: usize,
+    pub query_socket: Option<String>,
+    pub pipeline: Option<usize>, // queue capacity between the pipeline's threads
+    pub shards: Option<usize>,
+    pub latency: bool,
+    pub metrics_listen: Option<String>,
+    pub metrics_file: Option<String>,
+    pub generate: FeedConfig,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            target_sizes: Vec::new(),
+            input: None,
+            output: None,
+            input_format: InputFormat::Text,
+            output_format: OutputFormat::Text,
+            cache: CacheKind::Fnv,
+            id_mode: IdMode::Hash,
+            cache_capacity: 50000,
+            level_capacity: 256,
+            on_error: ErrorPolicy::Halt,
+            on_gap: SeqPolicy::Warn,
+            when_crossed: CrossedPolicy::Report,
+            at: None,
+            orders: false,
+            convert_to: None,
+            feed_listen: None,
+            multicast: None,
+            multicast_if: Ipv4Addr::UNSPECIFIED,
+            serve: None,
+            subscriber_buffer: 1024,
+            wait_subscribers: 0,
+            query_socket: None,
+            pipeline: None,
+            shards: None,
+            latency: false,
+            metrics_listen: None,
+            metrics_file: None,
+            generate: FeedConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Run(Subcommand, Box<Options>),
+    Help,
+    Version,
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value {} for {}", value, flag))
+}
+
+fn parse_path(value: String) -> Option<String> {
+    if value == "-" {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn check_feed_config(config: &FeedConfig) -> Result<(), String> {
+    let ratios = [
+        ("--walk", config.walk),
+        ("--add-ratio", config.add_ratio),
+        ("--partial-ratio", config.partial_ratio),
+    ];
+    if let Some(&(flag, _)) = ratios.iter().find(|&&(_, p)| !(0.0..=1.0).contains(&p)) {
+        return Err(format!("{} has to be between 0 and 1", flag));
+    }
+    if config.tick <= Amount::new() {
+        return Err("--tick has to be above 0".to_string());
+    }
+    if config.start_price <= Amount::new() {
+        return Err("--start-price has to be above 0".to_string());
+    }
+    if config.live_orders == 0 || config.depth_ticks == 0 || config.max_size <= 0 {
+        return Err(
+            "--live-orders, --depth-ticks and --max-size have to be at least 1".to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Parses the args after the program name.
+/// Without a command name up front it's price, so "order_book 200" keeps working
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut opts = Options::default();
+    let mut args = args.into_iter().peekable();
+    let subcommand = match args.peek().map(|arg| arg.parse::<Subcommand>()) {
+        Some(Ok(subcommand)) => {
+            args.next();
+            subcommand
+        }
+        _ => Subcommand::Price,
+    };
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        // accept both "--flag value" and "--flag=value"
+        let (flag, inline_value) = match arg.find('=') {
+            Some(idx) if arg.starts_with("--") => {
+                (arg[..idx].to_string(), Some(arg[idx + 1..].to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || -> Result<String, String> {
+            match inline_value.clone() {
+                Some(value) => Ok(value),
+                None => args.next().ok_or_else(|| format!("{} needs a value", flag)),
+            }
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-i" | "--input" => opts.input = parse_path(value()?),
+            "-o" | "--output" => opts.output = parse_path(value()?),
+            "--input-format" => opts.input_format = value()?.parse()?,
+            "--output-format" => opts.output_format = value()?.parse()?,
+            "--cache" => opts.cache = value()?.parse()?,
+            "--ids" => opts.id_mode = value()?.parse()?,
+            "--cache-capacity" => opts.cache_capacity = parse_value(&flag, &value()?)?,
+            "--level-capacity" => opts.level_capacity = parse_value(&flag, &value()?)?,
+            "--on-error" => opts.on_error = value()?.parse()?,
+            "--on-gap" => opts.on_gap = value()?.parse()?,
+            "--when-crossed" => opts.when_crossed = value()?.parse()?,
+            "--at" => opts.at = Some(parse_value(&flag, &value()?)?),
+            "--to" => opts.convert_to = Some(value()?.parse()?),
+            "--feed-listen" => opts.feed_listen = Some(value()?),
+            "--multicast" => opts.multicast = Some(parse_value(&flag, &value()?)?),
+            "--multicast-if" => opts.multicast_if = parse_value(&flag, &value()?)?,
+            "--serve" => opts.serve = Some(value()?),
+            "--subscriber-buffer" => opts.subscriber_buffer = parse_value(&flag, &value()?)?,
+            "--wait-subscribers" => opts.wait_subscribers = parse_value(&flag, &value()?)?,
+            "--query-socket" => opts.query_socket = Some(value()?),
+            "--pipeline" => opts.pipeline = Some(parse_value(&flag, &value()?)?),
+            "--shards" => opts.shards = Some(parse_value(&flag, &value()?)?),
+            "--orders" if inline_value.is_none() => opts.orders = true,
+            "--orders" => return Err("--orders doesn't take a value".to_string()),
+            "--latency" if inline_value.is_none() => opts.latency = true,
+            "--latency" => return Err("--latency doesn't take a value".to_string()),
+            "--metrics-listen" => opts.metrics_listen = Some(value()?),
+            "--metrics-file" => opts.metrics_file = Some(value()?),
+            "--messages" => opts.generate.messages = parse_value(&flag, &value()?)?,
+            "--live-orders" => opts.generate.live_orders = parse_value(&flag, &value()?)?,
+            "--seed" => opts.generate.seed = parse_value(&flag, &value()?)?,
+            "--start-price" => opts.generate.start_price = parse_value(&flag, &value()?)?,
+            "--tick" => opts.generate.tick = parse_value(&flag, &value()?)?,
+            "--walk" => opts.generate.walk = parse_value(&flag, &value()?)?,
+            "--depth-ticks" => opts.generate.depth_ticks = parse_value(&flag, &value()?)?,
+            "--add-ratio" => opts.generate.add_ratio = parse_value(&flag, &value()?)?,
+            "--partial-ratio" => opts.generate.partial_ratio = parse_value(&flag, &value()?)?,
+            "--max-size" => opts.generate.max_size = parse_value(&flag, &value()?)?,
+            _ if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("Unknown option {}", flag))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    match subcommand {
+        Subcommand::Price => {
+            for arg in positional {
+                let target_size: i64 = arg
+                    .parse()
+                    .map_err(|_| format!("Target size {} isn't a whole number", arg))?;
+                if target_size <= 0 {
+                    return Err(format!("Target size {} has to be positive", arg));
+                }
+                if target_size > MAX_SIZE {
+                    return Err(format!("Target size {} is over {}", arg, MAX_SIZE));
+                }
+                opts.target_sizes.push(target_size);
+            }
+            if opts.target_sizes.is_empty() {
+                return Err("Need at least one target size".to_string());
+            }
+        }
+        _ => {
+            if let Some(arg) = positional.first() {
+                return Err(format!("Unexpected argument {}", arg));
+            }
+            if subcommand == Subcommand::Snapshot && opts.at.is_none() {
+                return Err("snapshot needs --at".to_string());
+            }
+        }
+    }
+    // price levels aren't orders, they can't be validated or counted the same way
+    let levels_ok = matches!(subcommand, Subcommand::Price | Subcommand::Snapshot);
+    if opts.input_format == InputFormat::Mbp && !levels_ok {
+        return Err("mbp input only works with price and snapshot".to_string());
+    }
+    if opts.multicast.is_some() {
+        if opts.feed_listen.is_some() {
+            return Err("Pick one of --multicast and --feed-listen".to_string());
+        }
+        if opts.input_format == InputFormat::Mbp {
+            return Err("--multicast carries text or binary messages".to_string());
+        }
+    }
+    if opts.serve.is_some() && subcommand != Subcommand::Price {
+        return Err("only price can --serve".to_string());
+    }
+    if opts.query_socket.is_some() && subcommand != Subcommand::Price {
+        return Err("only price can --query-socket".to_string());
+    }
+    if opts.pipeline.is_some() {
+        if subcommand != Subcommand::Price {
+            return Err("only price can --pipeline".to_string());
+        }
+        if opts.input_format == InputFormat::Mbp {
+            return Err("--pipeline takes text or binary messages".to_string());
+        }
+        if opts.pipeline == Some(0) {
+            return Err("--pipeline has to queue at least 1 message".to_string());
+        }
+    }
+    if opts.shards.is_some() {
+        if subcommand != Subcommand::Price {
+            return Err("only price can --shards".to_string());
+        }
+        if opts.input_format != InputFormat::Text || opts.output_format != OutputFormat::Text {
+            return Err("--shards reads and writes text".to_string());
+        }
+        let other = [
+            ("--multicast", opts.multicast.is_some()),
+            ("--pipeline", opts.pipeline.is_some()),
+            ("--serve", opts.serve.is_some()),
+            ("--query-socket", opts.query_socket.is_some()),
+            ("--metrics-listen", opts.metrics_listen.is_some()),
+            ("--metrics-file", opts.metrics_file.is_some()),
+        ];
+        if let Some(&(flag, _)) = other.iter().find(|&&(_, set)| set) {
+            return Err(format!("--shards doesn't go with {}", flag));
+        }
+        if opts.shards == Some(0) {
+            return Err("--shards needs at least 1 worker".to_string());
+        }
+    }
+    if opts.latency {
+        if subcommand != Subcommand::Price {
+            return Err("only price can --latency".to_string());
+        }
+        // the stages have to run on one thread to be timed one after the other
+        if opts.pipeline.is_some() || opts.shards.is_some() {
+            return Err("--latency doesn't go with --pipeline or --shards".to_string());
+        }
+    }
+    for &(flag, set) in &[
+        ("--metrics-listen", opts.metrics_listen.is_some()),
+        ("--metrics-file", opts.metrics_file.is_some()),
+    ] {
+        if !set {
+            continue;
+        }
+        if subcommand != Subcommand::Price {
+            return Err(format!("only price can {}", flag));
+        }
+        // the counters are kept by the thread that applies and writes each message
+        if opts.pipeline.is_some() {
+            return Err(format!("{} doesn't go with --pipeline", flag));
+        }
+    }
+    if opts.orders {
+        if subcommand != Subcommand::Snapshot {
+            return Err("only snapshot can --orders".to_string());
+        }
+        if opts.input_format == InputFormat::Mbp {
+            return Err("mbp input has no orders to list".to_string());
+        }
+        // hashed ids can't be printed back
+        if opts.id_mode == IdMode::Hash {
+            return Err("--orders needs --ids checked, intern or numeric".to_string());
+        }
+    }
+    // the slab is indexed by interned handles, any other key would size it
+    if opts.cache == CacheKind::Slab && opts.id_mode != IdMode::Intern {
+        return Err("--cache slab needs --ids intern".to_string());
+    }
+    if opts.subscriber_buffer == 0 {
+        return Err("--subscriber-buffer has to be at least 1".to_string());
+    }
+    if opts.convert_to == Some(InputFormat::Mbp) {
+        return Err("Can't convert orders to mbp".to_string());
+    }
+    if subcommand == Subcommand::Generate {
+        check_feed_config(&opts.generate)?;
+    }
+    Ok(Command::Run(subcommand, Box::new(opts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        parse_args(args.split_whitespace().map(|arg| arg.to_string()))
+    }
+
+    fn options(args: &str) -> Options {
+        match parse(args) {
+            Ok(Command::Run(Subcommand::Price, opts)) => *opts,
+            other => panic!("expected options, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn target_size_only() {
+        let opts = options("200");
+        assert_eq!(opts.target_sizes, vec![200]);
+        assert_eq!(opts.input, None);
+        assert_eq!(opts.output, None);
+        assert_eq!(opts.cache, CacheKind::Fnv);
+        assert_eq!(opts.id_mode, IdMode::Hash);
+        assert_eq!(opts.cache_capacity, 50000);
+        assert_eq!(opts.level_capacity, 256);
+        assert_eq!(opts.on_error, ErrorPolicy::Halt);
+        assert_eq!(opts.on_gap, SeqPolicy::Warn);
+        assert_eq!(opts.when_crossed, CrossedPolicy::Report);
+    }
+
+    #[test]
+    fn several_target_sizes() {
+        assert_eq!(options("200 10000").target_sizes, vec![200, 10000]);
+        assert_eq!(options("1000000000").target_sizes, vec![MAX_SIZE]);
+        assert_eq!(
+            parse("10000000000"),
+            Err("Target size 10000000000 is over 1000000000".to_string())
+        );
+    }
+
+    #[test]
+    fn every_option() {
+        let opts = options(
+            "-i feed.txt -o out.jsonl --input-format text --output-format jsonl --cache slab \
+             --ids intern --cache-capacity 10 --level-capacity 20 --on-error warn --on-gap halt --when-crossed suppress 200",
+        );
+        assert_eq!(opts.input, Some("feed.txt".to_string()));
+        assert_eq!(opts.output, Some("out.jsonl".to_string()));
+        assert_eq!(opts.input_format, InputFormat::Text);
+        assert_eq!(opts.output_format, OutputFormat::JsonLines);
+        assert_eq!(opts.cache, CacheKind::Slab);
+        assert_eq!(opts.id_mode, IdMode::Intern);
+        assert_eq!(opts.cache_capacity, 10);
+        assert_eq!(opts.level_capacity, 20);
+        assert_eq!(opts.on_error, ErrorPolicy::Warn);
+        assert_eq!(opts.on_gap, SeqPolicy::Halt);
+        assert_eq!(opts.when_crossed, CrossedPolicy::Suppress);
+    }
+
+    #[test]
+    fn inline_values_and_dash_for_std_streams() {
+        let opts = options("--input=- --output=- --cache=robinhood 200");
+        assert_eq!(opts.input, None);
+        assert_eq!(opts.output, None);
+        assert_eq!(opts.cache, CacheKind::RobinHood);
+    }
+
+    #[test]
+    fn help_and_version() {
+        assert_eq!(parse("--help"), Ok(Command::Help));
+        assert_eq!(parse("200 -h"), Ok(Command::Help));
+        assert_eq!(parse("-V"), Ok(Command::Version));
+    }
+
+    #[test]
+    fn errors_instead_of_panics() {
+        assert_eq!(parse(""), Err("Need at least one target size".to_string()));
+        assert_eq!(
+            parse("lots"),
+            Err("Target size lots isn't a whole number".to_string())
+        );
+        assert_eq!(parse("-5"), Err("Unknown option -5".to_string()));
+        assert_eq!(
+            parse("0"),
+            Err("Target size 0 has to be positive".to_string())
+        );
+        assert_eq!(
+            parse("200 --cache"),
+            Err("--cache needs a value".to_string())
+        );
+        assert_eq!(
+            parse("200 --frobnicate"),
+            Err("Unknown option --frobnicate".to_string())
+        );
+        assert_eq!(
+            parse("200 --cache-capacity many"),
+            Err("Invalid value many for --cache-capacity".to_string())
+        );
+        assert_eq!(
+            parse("200 --cache slab"),
+            Err("--cache slab needs --ids intern".to_string())
+        );
+        assert!(parse("200 --ids fnv").is_err());
+        assert!(parse("200 --on-error ignore").is_err());
+        assert!(parse("200 --when-crossed hide").is_err());
+        assert!(parse("200 --on-gap ignore").is_err());
+        assert!(parse("200 --output-format xml").is_err());
+        assert!(parse("200 --input-format xml").is_err());
+    }
+
+    #[test]
+    fn subcommands() {
+        assert_eq!(
+            parse("price 200"),
+            Ok(Command::Run(Subcommand::Price, Box::new(options("200"))))
+        );
+        match parse("validate -i feed.txt --input-format binary") {
+            Ok(Command::Run(Subcommand::Validate, opts)) => {
+                assert_eq!(opts.input, Some("feed.txt".to_string()));
+                assert_eq!(opts.input_format, InputFormat::Binary);
+                assert!(opts.target_sizes.is_empty());
+            }
+            other => panic!("expected validate, got {:?}", other),
+        }
+        match parse("snapshot --at 28800758") {
+            Ok(Command::Run(Subcommand::Snapshot, opts)) => assert_eq!(opts.at, Some(28800758)),
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        match parse("convert --to=binary") {
+            Ok(Command::Run(Subcommand::Convert, opts)) => {
+                assert_eq!(opts.convert_to, Some(InputFormat::Binary))
+            }
+            other => panic!("expected convert, got {:?}", other),
+        }
+        assert!(matches!(
+            parse("stats"),
+            Ok(Command::Run(Subcommand::Stats, _))
+        ));
+    }
+
+    #[test]
+    fn subcommand_errors() {
+        assert_eq!(parse("snapshot"), Err("snapshot needs --at".to_string()));
+        assert_eq!(
+            parse("snapshot --at noon"),
+            Err("Invalid value noon for --at".to_string())
+        );
+        assert_eq!(
+            parse("stats 200"),
+            Err("Unexpected argument 200".to_string())
+        );
+        assert_eq!(
+            parse("price"),
+            Err("Need at least one target size".to_string())
+        );
+        assert!(parse("convert --to xml").is_err());
+    }
+
+    #[test]
+    fn generate() {
+        match parse("generate --messages 500 --seed 9 --tick 0.05 --start-price 12.5 --to binary") {
+            Ok(Command::Run(Subcommand::Generate, opts)) => {
+                assert_eq!(opts.generate.messages, 500);
+                assert_eq!(opts.generate.seed, 9);
+                assert_eq!(opts.generate.tick, Amount { as_int: 5 });
+                assert_eq!(opts.generate.start_price, Amount { as_int: 1250 });
+                assert_eq!(opts.generate.live_orders, 1000);
+                assert_eq!(opts.convert_to, Some(InputFormat::Binary));
+            }
+            other => panic!("expected generate, got {:?}", other),
+        }
+        assert_eq!(
+            parse("generate --add-ratio 1.5"),
+            Err("--add-ratio has to be between 0 and 1".to_string())
+        );
+        assert_eq!(
+            parse("generate --tick 0"),
+            Err("--tick has to be above 0".to_string())
+        );
+        assert!(parse("generate --live-orders 0").is_err());
+        assert!(parse("generate --seed many").is_err());
+        assert!(parse("generate 200").is_err());
+    }
+
+    #[test]
+    fn mbp_input() {
+        assert_eq!(
+            options("200 --input-format mbp").input_format,
+            InputFormat::Mbp
+        );
+        assert!(parse("snapshot --at 1 --input-format mbp").is_ok());
+        assert_eq!(
+            parse("stats --input-format mbp"),
+            Err("mbp input only works with price and snapshot".to_string())
+        );
+        assert!(parse("convert --to mbp").is_err());
+    }
+
+    #[test]
+    fn snapshot_orders() {
+        match parse("snapshot --at 1 --orders --ids intern") {
+            Ok(Command::Run(Subcommand::Snapshot, opts)) => assert!(opts.orders),
+            other => panic!("expected snapshot, got {:?}", other),
+        }
+        assert_eq!(
+            parse("snapshot --at 1 --orders"),
+            Err("--orders needs --ids checked, intern or numeric".to_string())
+        );
+        assert_eq!(
+            parse("snapshot --at 1 --orders --ids intern --input-format mbp"),
+            Err("mbp input has no orders to list".to_string())
+        );
+        assert_eq!(
+            parse("200 --orders --ids intern"),
+            Err("only snapshot can --orders".to_string())
+        );
+    }
+
+    #[test]
+    fn serving() {
+        let opts = options("200 --serve 127.0.0.1:9000 --wait-subscribers 2 --feed-listen :9001");
+        assert_eq!(opts.serve, Some("127.0.0.1:9000".to_string()));
+        assert_eq!(opts.wait_subscribers, 2);
+        assert_eq!(opts.subscriber_buffer, 1024);
+        assert_eq!(opts.feed_listen, Some(":9001".to_string()));
+        assert_eq!(
+            parse("stats --serve 127.0.0.1:9000"),
+            Err("only price can --serve".to_string())
+        );
+        assert!(parse("200 --subscriber-buffer 0").is_err());
+    }
+
+    #[test]
+    fn pipeline() {
+        assert_eq!(options("200 --pipeline 4096").pipeline, Some(4096));
+        assert_eq!(options("200").pipeline, None);
+        assert_eq!(
+            parse("stats --pipeline 16"),
+            Err("only price can --pipeline".to_string())
+        );
+        assert!(parse("200 --pipeline 16 --input-format mbp").is_err());
+        assert!(parse("200 --pipeline 0").is_err());
+    }
+
+    #[test]
+    fn shards() {
+        assert_eq!(options("200 --shards 4").shards, Some(4));
+        assert_eq!(
+            parse("200 --shards 4 --output-format csv"),
+            Err("--shards reads and writes text".to_string())
+        );
+        assert_eq!(
+            parse("200 --shards 4 --pipeline 16"),
+            Err("--shards doesn't go with --pipeline".to_string())
+        );
+        assert!(parse("stats --shards 4").is_err());
+        assert!(parse("200 --shards 0").is_err());
+    }
+
+    #[test]
+    fn latency() {
+        assert!(options("200 --latency").latency);
+        assert!(!options("200").latency);
+        assert_eq!(
+            parse("stats --latency"),
+            Err("only price can --latency".to_string())
+        );
+        assert!(parse("200 --latency --pipeline 16").is_err());
+        assert!(parse("200 --latency=yes").is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        let opts = options("200 --metrics-listen 127.0.0.1:9100 --metrics-file /tmp/book.prom");
+        assert_eq!(opts.metrics_listen, Some("127.0.0.1:9100".to_string()));
+        assert_eq!(opts.metrics_file, Some("/tmp/book.prom".to_string()));
+        assert_eq!(options("200").metrics_listen, None);
+        assert_eq!(
+            parse("stats --metrics-file /tmp/book.prom"),
+            Err("only price can --metrics-file".to_string())
+        );
+        assert_eq!(
+            parse("200 --metrics-listen :9100 --shards 2"),
+            Err("--shards doesn't go with --metrics-listen".to_string())
+        );
+        assert!(parse("200 --metrics-file /tmp/book.prom --pipeline 16").is_err());
+    }
+
+    #[test]
+    fn query_socket() {
+        let opts = options("200 --query-socket /tmp/book.sock");
+        assert_eq!(opts.query_socket, Some("/tmp/book.sock".to_string()));
+        assert_eq!(options("200").query_socket, None);
+        assert_eq!(
+            parse("validate --query-socket /tmp/book.sock"),
+            Err("only price can --query-socket".to_string())
+        );
+    }
+
+    #[test]
+    fn multicast() {
+        let opts = options("200 --multicast 239.1.1.1:5000 --multicast-if 127.0.0.1");
+        assert_eq!(opts.multicast, Some("239.1.1.1:5000".parse().unwrap()));
+        assert_eq!(opts.multicast_if, Ipv4Addr::LOCALHOST);
+        assert_eq!(options("200").multicast_if, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            parse("200 --multicast 239.1.1.1"),
+            Err("Invalid value 239.1.1.1 for --multicast".to_string())
+        );
+        assert!(parse("200 --multicast 239.1.1.1:5000 --feed-listen :1").is_err());
+        assert!(parse("200 --multicast 239.1.1.1:5000 --input-format mbp").is_err());
+    }
+}
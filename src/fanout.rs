@@ -0,0 +1,250 @@
+use std::io::{self, BufWriter, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Hands every published line to any number of subscribers. Each one gets a
+// bounded queue drained by its own writer thread, so a subscriber that stops
+// reading fills its queue and is dropped rather than holding the book up.
+
+type Line = Arc<[u8]>;
+
+struct Subscriber {
+    id: u64,
+    queue: SyncSender<Line>,
+    close: Box<dyn FnMut() + Send>, // unblocks a writer stuck on a full socket
+    writer: JoinHandle<()>,
+}
+
+/// Fans published lines out to subscribers, see accept_subscribers for TCP ones
+pub struct FanOut {
+    capacity: usize,
+    greeting: Vec<u8>,
+    subscribers: Vec<Subscriber>,
+    next_id: u64,
+    slow_disconnects: u64,
+}
+
+impl FanOut {
+    /// capacity is how many lines a subscriber can fall behind before it's dropped.
+    /// greeting goes to every subscriber before anything published, e.g. a CSV header
+    pub fn new(capacity: usize, greeting: Vec<u8>) -> Self {
+        FanOut {
+            capacity,
+            greeting,
+            subscribers: Vec::new(),
+            next_id: 0,
+            slow_disconnects: 0,
+        }
+    }
+
+    /// Streams every line published from now on to the connection
+    pub fn subscribe(&mut self, stream: TcpStream) -> io::Result<u64> {
+        let closer = stream.try_clone()?;
+        Ok(self.add(
+            stream,
+            Box::new(move || {
+                let _ = closer.shutdown(Shutdown::Both);
+            }),
+        ))
+    }
+
+    fn add<W: Write + Send + 'static>(&mut self, out: W, close: Box<dyn FnMut() + Send>) -> u64 {
+        let (queue, lines) = mpsc::sync_channel(self.capacity);
+        let greeting = self.greeting.clone();
+        let writer = thread::spawn(move || drain(out, &greeting, lines));
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscribers.push(Subscriber {
+            id,
+            queue,
+            close,
+            writer,
+        });
+        id
+    }
+
+    /// Subscribers still connected
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Subscribers dropped for not keeping up
+    pub fn slow_disconnects(&self) -> u64 {
+        self.slow_disconnects
+    }
+
+    /// Queues the line for every subscriber, dropping the ones whose queue is full
+    /// or whose connection went away. Never blocks
+    pub fn publish(&mut self, line: &[u8]) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let line: Line = Arc::from(line);
+        let mut slow = 0;
+        self.subscribers
+            .retain_mut(|subscriber| match subscriber.queue.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    (subscriber.close)();
+                    slow += 1;
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        self.slow_disconnects += slow;
+    }
+
+    /// Whether the subscriber is still connected
+    pub fn is_subscribed(&self, id: u64) -> bool {
+        self.subscribers
+            .iter()
+            .any(|subscriber| subscriber.id == id)
+    }
+
+    /// Lets every subscriber catch up on what's queued, then disconnects them
+    pub fn close(&mut self) {
+        for subscriber in self.subscribers.drain(..) {
+            drop(subscriber.queue);
+            let _ = subscriber.writer.join();
+        }
+    }
+}
+
+// writer thread, flushes whenever it runs out of queued lines
+fn drain<W: Write>(out: W, greeting: &[u8], lines: Receiver<Line>) {
+    let mut out = BufWriter::new(out);
+    if out.write_all(greeting).is_err() {
+        return;
+    }
+    while let Ok(line) = lines.recv() {
+        if out.write_all(&line).is_err() {
+            return;
+        }
+        while let Ok(line) = lines.try_recv() {
+            if out.write_all(&line).is_err() {
+                return;
+            }
+        }
+        if out.flush().is_err() {
+            return;
+        }
+    }
+    let _ = out.flush();
+}
+
+/// Subscribes every connection the listener accepts, on a thread of its own
+pub fn accept_subscribers(listener: TcpListener, fanout: Arc<Mutex<FanOut>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let subscribed = stream.and_then(|stream| fanout.lock().unwrap().subscribe(stream));
+            if let Err(err) = subscribed {
+                eprintln!("order_book: cannot take subscriber: {}", err);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::time::Duration;
+
+    // collects what a subscriber was sent
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Collect {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // never gets past its first write until the gate opens
+    struct Stuck(Receiver<()>);
+
+    impl Write for Stuck {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_subscriber_gets_every_line() {
+        let mut fanout = FanOut::new(16, b"header\n".to_vec());
+        let (a, b) = (Collect::default(), Collect::default());
+        fanout.add(a.clone(), Box::new(|| {}));
+        fanout.add(b.clone(), Box::new(|| {}));
+        fanout.publish(b"1 S 100.00\n");
+        fanout.publish(b"2 B 99.00\n");
+        assert_eq!(fanout.subscribers(), 2);
+        fanout.close();
+        for sink in &[a, b] {
+            assert_eq!(
+                &sink.0.lock().unwrap()[..],
+                &b"header\n1 S 100.00\n2 B 99.00\n"[..]
+            );
+        }
+    }
+
+    #[test]
+    fn slow_subscriber_is_dropped() {
+        let mut fanout = FanOut::new(2, Vec::new());
+        let (gate, stuck) = mpsc::channel();
+        let closed = Arc::new(Mutex::new(false));
+        let seen = closed.clone();
+        let slow = fanout.add(Stuck(stuck), Box::new(move || *seen.lock().unwrap() = true));
+        let fast = Collect::default();
+        let fast_id = fanout.add(fast.clone(), Box::new(|| {}));
+        // one line stuck in the writer, two queued, the fourth doesn't fit
+        for n in 0..10 {
+            fanout.publish(format!("{}\n", n).as_bytes());
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!fanout.is_subscribed(slow));
+        assert!(fanout.is_subscribed(fast_id));
+        assert_eq!(fanout.slow_disconnects(), 1);
+        assert!(*closed.lock().unwrap());
+        drop(gate);
+        fanout.close();
+        assert_eq!(fast.0.lock().unwrap().len(), 20);
+    }
+
+    #[test]
+    fn tcp_subscribers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fanout = Arc::new(Mutex::new(FanOut::new(16, Vec::new())));
+        accept_subscribers(listener, fanout.clone());
+        let client = TcpStream::connect(addr).unwrap();
+        while fanout.lock().unwrap().subscribers() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        fanout.lock().unwrap().publish(b"28800758 S 8832.56\n");
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        assert_eq!(line, "28800758 S 8832.56\n");
+
+        // a subscriber that hangs up is dropped on the next publish or so
+        let mut fanout = fanout.lock().unwrap();
+        for _ in 0..1000 {
+            fanout.publish(b"x\n");
+            if fanout.subscribers() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(fanout.subscribers(), 0);
+        assert_eq!(fanout.slow_disconnects(), 0);
+    }
+}
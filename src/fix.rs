@@ -0,0 +1,592 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use fnv::FnvHashMap;
+
+use amount::Amount;
+use idpricecache::IdPriceCacheFnvMap;
+use orderbook::{BookError, OrderBook};
+use orderids::IdMode;
+use orders::{parse_price, size_in_range, Message};
+use orderside::OrderSide;
+
+// Adapter for FIX 4.4 MarketDataIncrementalRefresh (35=X) messages carrying
+// individual orders. Fields are tag=value separated by SOH, e.g.
+//   8=FIX.4.4|9=..|35=X|...|52=20240102-08:00:00.538|268=1|279=0|269=0|55=ACME|270=44.10|271=100|37=b1|10=..|
+// Each MDEntry group starts with MDUpdateAction (279).
+
+pub const SOH: u8 = 0x01;
+
+const BEGIN_STRING: u32 = 8;
+const BODY_LENGTH: u32 = 9;
+const CHECKSUM: u32 = 10;
+const MSG_TYPE: u32 = 35;
+const ORDER_ID: u32 = 37;
+const SENDING_TIME: u32 = 52;
+const SYMBOL: u32 = 55;
+const NO_MD_ENTRIES: u32 = 268;
+const MD_ENTRY_TYPE: u32 = 269;
+const MD_ENTRY_PX: u32 = 270;
+const MD_ENTRY_SIZE: u32 = 271;
+const MD_ENTRY_TIME: u32 = 273;
+const MD_ENTRY_ID: u32 = 278;
+const MD_UPDATE_ACTION: u32 = 279;
+
+/// Why a FIX message couldn't be parsed or applied
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FixError {
+    /// not tag=value fields, or header and trailer out of place
+    Malformed(String),
+    BadBodyLength {
+        expected: usize,
+        found: usize,
+    },
+    BadChecksum {
+        expected: u8,
+        found: u8,
+    },
+    /// only 35=X carries book updates
+    WrongMsgType(String),
+    MissingTag(u32),
+    BadValue {
+        tag: u32,
+        value: String,
+    },
+    /// new order under an id that is still resting
+    DuplicateOrder(String),
+    /// change or delete for an order that isn't resting
+    UnknownOrder(String),
+    Book(BookError),
+}
+
+impl Display for FixError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            FixError::Malformed(ref why) => write!(f, "malformed FIX message: {}", why),
+            FixError::BadBodyLength { expected, found } => {
+                write!(f, "body length {} but body has {} bytes", expected, found)
+            }
+            FixError::BadChecksum { expected, found } => {
+                write!(
+                    f,
+                    "checksum {:03} but message sums to {:03}",
+                    expected, found
+                )
+            }
+            FixError::WrongMsgType(ref msg_type) => {
+                write!(f, "message type {} isn't an incremental refresh", msg_type)
+            }
+            FixError::MissingTag(tag) => write!(f, "tag {} missing", tag),
+            FixError::BadValue { tag, ref value } => {
+                write!(f, "bad value {:?} for tag {}", value, tag)
+            }
+            FixError::DuplicateOrder(ref id) => write!(f, "order {} added while still resting", id),
+            FixError::UnknownOrder(ref id) => write!(f, "no order under id {}", id),
+            FixError::Book(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for FixError {}
+
+impl From<BookError> for FixError {
+    fn from(err: BookError) -> Self {
+        FixError::Book(err)
+    }
+}
+
+fn bad_value(tag: u32, value: &str) -> FixError {
+    FixError::BadValue {
+        tag,
+        value: value.to_string(),
+    }
+}
+
+/// A FIX message whose body length and checksum add up, fields in wire order
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixMessage<'a> {
+    fields: Vec<(u32, &'a str)>,
+}
+
+impl<'a> FixMessage<'a> {
+    /// raw runs from 8= up to and including the SOH after the checksum
+    pub fn parse(raw: &'a str) -> Result<Self, FixError> {
+        let body = raw
+            .strip_suffix(SOH as char)
+            .ok_or_else(|| FixError::Malformed("doesn't end in SOH".to_string()))?;
+        let mut fields = Vec::new();
+        for field in body.split(SOH as char) {
+            let (tag, value) = match field.find('=') {
+                Some(idx) => (&field[..idx], &field[idx + 1..]),
+                None => return Err(FixError::Malformed(format!("no '=' in {:?}", field))),
+            };
+            let tag = tag
+                .parse::<u32>()
+                .map_err(|_| FixError::Malformed(format!("bad tag {:?}", tag)))?;
+            fields.push((tag, value));
+        }
+        let tags: Vec<u32> = fields.iter().map(|&(tag, _)| tag).collect();
+        if tags.len() < 4
+            || tags[0] != BEGIN_STRING
+            || tags[1] != BODY_LENGTH
+            || tags[2] != MSG_TYPE
+            || tags[tags.len() - 1] != CHECKSUM
+        {
+            return Err(FixError::Malformed(
+                "must start with 8, 9, 35 and end with 10".to_string(),
+            ));
+        }
+        if fields[0].1 != "FIX.4.4" {
+            return Err(bad_value(BEGIN_STRING, fields[0].1));
+        }
+
+        // body length counts from after 9= to the SOH before 10=, the checksum everything before 10=
+        let checksum_at = body.rfind("\u{1}10=").map(|idx| idx + 1).unwrap();
+        let body_start = fields[0].1.len() + fields[1].1.len() + 6; // "8=" "9=" and two SOHs
+        let expected = fields[1]
+            .1
+            .parse::<usize>()
+            .map_err(|_| bad_value(BODY_LENGTH, fields[1].1))?;
+        let found = checksum_at - body_start;
+        if expected != found {
+            return Err(FixError::BadBodyLength { expected, found });
+        }
+        let checksum = fields[fields.len() - 1].1;
+        let expected = match checksum.parse::<u8>() {
+            Ok(expected) if checksum.len() == 3 => expected,
+            _ => return Err(bad_value(CHECKSUM, checksum)),
+        };
+        let found = raw.as_bytes()[..checksum_at]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if expected != found {
+            return Err(FixError::BadChecksum { expected, found });
+        }
+        Ok(FixMessage { fields })
+    }
+
+    pub fn msg_type(&self) -> &'a str {
+        self.fields[2].1
+    }
+
+    /// First value for the tag
+    pub fn get(&self, tag: u32) -> Option<&'a str> {
+        self.fields
+            .iter()
+            .find(|&&(field, _)| field == tag)
+            .map(|&(_, value)| value)
+    }
+
+    pub fn fields(&self) -> &[(u32, &'a str)] {
+        &self.fields
+    }
+}
+
+/// MDUpdateAction (279)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UpdateAction {
+    New,
+    Change,
+    Delete,
+}
+
+/// One MDEntry of an incremental refresh
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MdEntry<'a> {
+    pub action: Option<UpdateAction>,
+    /// None for entry types other than bid and offer (trades, imbalances...)
+    pub side: Option<OrderSide>,
+    pub symbol: &'a str,
+    pub price: Option<Amount>,
+    pub size: Option<i64>,
+    /// OrderID (37), or MDEntryID (278) without one
+    pub order_id: &'a str,
+    /// MDEntryTime (273), ms since midnight
+    pub time: Option<i64>,
+}
+
+/// Milliseconds since midnight, like the text feed, from "HH:MM:SS[.sss]"
+/// or a UTCTimestamp "YYYYMMDD-HH:MM:SS[.sss]"
+fn parse_time(tag: u32, value: &str) -> Result<i64, FixError> {
+    let time = match value.find('-') {
+        Some(idx) => &value[idx + 1..],
+        None => value,
+    };
+    let (hms, millis) = match time.find('.') {
+        Some(idx) => (&time[..idx], &time[idx + 1..]),
+        None => (time, "0"),
+    };
+    let parts: Vec<&str> = hms.split(':').collect();
+    if parts.len() != 3 || millis.is_empty() || millis.len() > 3 {
+        return Err(bad_value(tag, value));
+    }
+    let mut ms = 0;
+    for (part, scale) in parts.iter().zip(&[3_600_000, 60_000, 1000]) {
+        ms += part.parse::<i64>().map_err(|_| bad_value(tag, value))? * scale;
+    }
+    let frac = millis.parse::<i64>().map_err(|_| bad_value(tag, value))?;
+    Ok(ms + frac * 10i64.pow(3 - millis.len() as u32))
+}
+
+/// SendingTime and the MDEntry groups of a 35=X message
+pub fn incremental_refresh<'a>(
+    message: &FixMessage<'a>,
+) -> Result<(i64, Vec<MdEntry<'a>>), FixError> {
+    if message.msg_type() != "X" {
+        return Err(FixError::WrongMsgType(message.msg_type().to_string()));
+    }
+    let sent = message
+        .get(SENDING_TIME)
+        .ok_or(FixError::MissingTag(SENDING_TIME))?;
+    let timestamp = parse_time(SENDING_TIME, sent)?;
+    let count = message
+        .get(NO_MD_ENTRIES)
+        .ok_or(FixError::MissingTag(NO_MD_ENTRIES))?;
+
+    let mut entries: Vec<MdEntry> = Vec::new();
+    let mut in_groups = false;
+    for &(tag, value) in message.fields() {
+        if tag == NO_MD_ENTRIES {
+            in_groups = true;
+            continue;
+        }
+        if !in_groups || tag == CHECKSUM {
+            continue;
+        }
+        if tag == MD_UPDATE_ACTION {
+            entries.push(MdEntry::default());
+        }
+        let entry = match entries.last_mut() {
+            Some(entry) => entry,
+            None => {
+                return Err(FixError::Malformed(format!(
+                    "tag {} before the first {}",
+                    tag, MD_UPDATE_ACTION
+                )))
+            }
+        };
+        match tag {
+            MD_UPDATE_ACTION => {
+                entry.action = Some(match value {
+                    "0" => UpdateAction::New,
+                    "1" => UpdateAction::Change,
+                    "2" => UpdateAction::Delete,
+                    _ => return Err(bad_value(tag, value)),
+                })
+            }
+            MD_ENTRY_TYPE => {
+                entry.side = match value {
+                    "0" => Some(OrderSide::Bid),
+                    "1" => Some(OrderSide::Ask),
+                    _ => None,
+                }
+            }
+            SYMBOL => entry.symbol = value,
+            MD_ENTRY_PX => {
+                entry.price = Some(parse_price(value).map_err(|_| bad_value(tag, value))?)
+            }
+            MD_ENTRY_SIZE => {
+                let size = value.parse::<i64>().map_err(|_| bad_value(tag, value))?;
+                if !size_in_range(size) {
+                    return Err(bad_value(tag, value));
+                }
+                entry.size = Some(size);
+            }
+            ORDER_ID => entry.order_id = value,
+            MD_ENTRY_ID if entry.order_id.is_empty() => entry.order_id = value,
+            MD_ENTRY_TIME => entry.time = Some(parse_time(tag, value)?),
+            _ => {}
+        }
+    }
+    if count.parse::<usize>() != Ok(entries.len()) {
+        return Err(bad_value(NO_MD_ENTRIES, count));
+    }
+    Ok((timestamp, entries))
+}
+
+// what's left of an order the book knows by id
+struct Resting {
+    symbol: String,
+    side: OrderSide,
+    price: Amount,
+    size: i64,
+}
+
+/// Keeps one OrderBook per symbol up to date from incremental refreshes.
+/// Changes take the order out at its old price and size and add it back at the new ones
+pub struct FixHandler {
+    target_size: i64,
+    books: FnvHashMap<String, OrderBook<IdPriceCacheFnvMap>>,
+    orders: FnvHashMap<String, Resting>,
+}
+
+impl FixHandler {
+    /// target_size is handed to every book
+    pub fn new(target_size: i64) -> Self {
+        FixHandler {
+            target_size,
+            books: FnvHashMap::default(),
+            orders: FnvHashMap::default(),
+        }
+    }
+
+    pub fn book(&self, symbol: &str) -> Option<&OrderBook<IdPriceCacheFnvMap>> {
+        self.books.get(symbol)
+    }
+
+    /// Symbols with a book, in no particular order
+    pub fn symbols(&self) -> Vec<&str> {
+        self.books.keys().map(|symbol| symbol.as_str()).collect()
+    }
+
+    /// Size left on an order
+    pub fn resting_size(&self, order_id: &str) -> Option<i64> {
+        self.orders.get(order_id).map(|resting| resting.size)
+    }
+
+    fn book_mut(&mut self, symbol: &str) -> &mut OrderBook<IdPriceCacheFnvMap> {
+        let target_size = self.target_size;
+        self.books.entry(symbol.to_string()).or_insert_with(|| {
+            OrderBook::with_id_mode(target_size, IdPriceCacheFnvMap::default(), IdMode::Intern)
+        })
+    }
+
+    /// Parses and applies one raw message
+    pub fn apply_raw(&mut self, raw: &str) -> Result<usize, FixError> {
+        self.apply(&FixMessage::parse(raw)?)
+    }
+
+    /// Applies every bid and offer entry in order, returns how many there were.
+    /// Entries before one that fails stay applied
+    pub fn apply(&mut self, message: &FixMessage) -> Result<usize, FixError> {
+        let (sent, entries) = incremental_refresh(message)?;
+        let mut applied = 0;
+        for entry in entries.iter().filter(|entry| entry.side.is_some()) {
+            self.apply_entry(entry.time.unwrap_or(sent), entry)?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    fn apply_entry(&mut self, timestamp: i64, entry: &MdEntry) -> Result<(), FixError> {
+        let id = entry.order_id;
+        if id.is_empty() {
+            return Err(FixError::MissingTag(ORDER_ID));
+        }
+        match entry.action.ok_or(FixError::MissingTag(MD_UPDATE_ACTION))? {
+            UpdateAction::New => {
+                if self.orders.contains_key(id) {
+                    return Err(FixError::DuplicateOrder(id.to_string()));
+                }
+                let resting = Resting {
+                    symbol: entry.symbol.to_string(),
+                    side: entry.side.ok_or(FixError::MissingTag(MD_ENTRY_TYPE))?,
+                    price: entry.price.ok_or(FixError::MissingTag(MD_ENTRY_PX))?,
+                    size: entry.size.ok_or(FixError::MissingTag(MD_ENTRY_SIZE))?,
+                };
+                self.add(timestamp, id, resting)
+            }
+            UpdateAction::Change => {
+                let old = self.remove(timestamp, id)?;
+                let resting = Resting {
+                    price: entry.price.unwrap_or(old.price),
+                    size: entry.size.unwrap_or(old.size),
+                    ..old
+                };
+                self.add(timestamp, id, resting)
+            }
+            UpdateAction::Delete => self.remove(timestamp, id).map(|_| ()),
+        }
+    }
+
+    fn add(&mut self, timestamp: i64, id: &str, resting: Resting) -> Result<(), FixError> {
+        self.book_mut(&resting.symbol).apply(&Message::Add {
+            timestamp,
+            id,
+            side: resting.side,
+            price: resting.price,
+            size: resting.size,
+        })?;
+        self.orders.insert(id.to_string(), resting);
+        Ok(())
+    }
+
+    fn remove(&mut self, timestamp: i64, id: &str) -> Result<Resting, FixError> {
+        let resting = self
+            .orders
+            .remove(id)
+            .ok_or_else(|| FixError::UnknownOrder(id.to_string()))?;
+        self.book_mut(&resting.symbol).apply(&Message::Reduce {
+            timestamp,
+            id,
+            size: resting.size,
+        })?;
+        Ok(resting)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // captured with | standing in for SOH
+    const NEW_BID_AND_OFFER: &str = "8=FIX.4.4|9=148|35=X|49=VENUE|56=CLIENT|34=2|52=20240102-08:00:00.538|268=2|279=0|269=0|55=ACME|270=44.10|271=100|37=b1|279=0|269=1|55=ACME|270=44.26|271=100|37=s1|10=046|";
+    const CHANGE_OFFER: &str = "8=FIX.4.4|9=120|35=X|49=VENUE|56=CLIENT|34=3|52=20240102-08:00:01.000|268=1|279=1|269=1|55=ACME|270=44.20|271=60|37=s1|273=08:00:00.900|10=211|";
+    const DELETE_BID_AND_TRADE: &str = "8=FIX.4.4|9=123|35=X|49=VENUE|56=CLIENT|34=4|52=20240102-08:00:02.000|268=2|279=2|269=0|55=ACME|37=b1|279=0|269=2|55=ACME|270=44.20|271=40|10=104|";
+    const HEARTBEAT: &str =
+        "8=FIX.4.4|9=54|35=0|49=VENUE|56=CLIENT|34=5|52=20240102-08:00:30.000|10=001|";
+
+    fn wire(captured: &str) -> String {
+        captured.replace('|', "\u{1}")
+    }
+
+    fn price(text: &str) -> Amount {
+        Amount::new_from_str(text)
+    }
+
+    #[test]
+    fn parse_and_read_entries() {
+        let raw = wire(NEW_BID_AND_OFFER);
+        let message = FixMessage::parse(&raw).unwrap();
+        assert_eq!(message.msg_type(), "X");
+        assert_eq!(message.get(49), Some("VENUE"));
+        let (sent, entries) = incremental_refresh(&message).unwrap();
+        assert_eq!(sent, 28800538);
+        assert_eq!(
+            entries[0],
+            MdEntry {
+                action: Some(UpdateAction::New),
+                side: Some(OrderSide::Bid),
+                symbol: "ACME",
+                price: Some(price("44.10")),
+                size: Some(100),
+                order_id: "b1",
+                time: None,
+            }
+        );
+        assert_eq!(entries[1].side, Some(OrderSide::Ask));
+
+        let raw = wire(CHANGE_OFFER);
+        let (_, entries) = incremental_refresh(&FixMessage::parse(&raw).unwrap()).unwrap();
+        assert_eq!(entries[0].action, Some(UpdateAction::Change));
+        assert_eq!(entries[0].time, Some(28800900));
+    }
+
+    #[test]
+    fn checksum_and_body_length() {
+        let raw = wire(&NEW_BID_AND_OFFER.replace("271=100|37=b1", "271=900|37=b1"));
+        assert_eq!(
+            FixMessage::parse(&raw),
+            Err(FixError::BadChecksum {
+                expected: 46,
+                found: 54
+            })
+        );
+        let raw = wire(&NEW_BID_AND_OFFER.replace("37=b1", "37=b12"));
+        assert_eq!(
+            FixMessage::parse(&raw),
+            Err(FixError::BadBodyLength {
+                expected: 148,
+                found: 149
+            })
+        );
+        let raw = wire(&NEW_BID_AND_OFFER.replace("10=046", "10=46"));
+        assert_eq!(
+            FixMessage::parse(&raw),
+            Err(FixError::BadValue {
+                tag: 10,
+                value: "46".to_string()
+            })
+        );
+        // the | form itself doesn't sum up
+        assert!(FixMessage::parse(NEW_BID_AND_OFFER).is_err());
+        assert!(FixMessage::parse(&wire("8=FIX.4.4|35=X|10=000|")).is_err());
+    }
+
+    #[test]
+    fn only_incremental_refreshes() {
+        let raw = wire(HEARTBEAT);
+        let message = FixMessage::parse(&raw).unwrap();
+        assert_eq!(
+            FixHandler::new(100).apply(&message),
+            Err(FixError::WrongMsgType("0".to_string()))
+        );
+    }
+
+    #[test]
+    fn drives_the_book() {
+        let mut handler = FixHandler::new(100);
+        assert_eq!(handler.apply_raw(&wire(NEW_BID_AND_OFFER)), Ok(2));
+        {
+            let book = handler.book("ACME").unwrap();
+            assert_eq!(book.best_bid(), Some((price("44.10"), 100)));
+            assert_eq!(book.best_ask(), Some((price("44.26"), 100)));
+        }
+
+        assert_eq!(handler.apply_raw(&wire(CHANGE_OFFER)), Ok(1));
+        assert_eq!(
+            handler.book("ACME").unwrap().best_ask(),
+            Some((price("44.20"), 60))
+        );
+        assert_eq!(handler.resting_size("s1"), Some(60));
+
+        // the trade entry isn't a book update
+        assert_eq!(handler.apply_raw(&wire(DELETE_BID_AND_TRADE)), Ok(1));
+        assert_eq!(handler.book("ACME").unwrap().best_bid(), None);
+        assert_eq!(handler.resting_size("b1"), None);
+        assert_eq!(handler.symbols(), vec!["ACME"]);
+
+        assert_eq!(
+            handler.apply_raw(&wire(DELETE_BID_AND_TRADE)),
+            Err(FixError::UnknownOrder("b1".to_string()))
+        );
+        assert_eq!(
+            handler.apply_raw(&wire(NEW_BID_AND_OFFER)),
+            Err(FixError::DuplicateOrder("s1".to_string()))
+        );
+    }
+
+    #[test]
+    fn prices_and_sizes_out_of_range() {
+        let refresh = |price: &'static str, size: &'static str| {
+            let message = FixMessage {
+                fields: vec![
+                    (BEGIN_STRING, "FIX.4.4"),
+                    (BODY_LENGTH, "0"),
+                    (MSG_TYPE, "X"),
+                    (SENDING_TIME, "20240102-08:00:00.538"),
+                    (NO_MD_ENTRIES, "1"),
+                    (MD_UPDATE_ACTION, "0"),
+                    (MD_ENTRY_TYPE, "0"),
+                    (SYMBOL, "ACME"),
+                    (MD_ENTRY_PX, price),
+                    (MD_ENTRY_SIZE, size),
+                    (ORDER_ID, "b1"),
+                    (CHECKSUM, "000"),
+                ],
+            };
+            incremental_refresh(&message).map(|(_, entries)| entries[0].price)
+        };
+        assert_eq!(refresh("44.10", "1000000000"), Ok(Some(price("44.10"))));
+        // NaN would rest at 0.00 and 1e300 at the top of i64
+        assert_eq!(refresh("NaN", "100"), Err(bad_value(MD_ENTRY_PX, "NaN")));
+        assert_eq!(
+            refresh("1e300", "100"),
+            Err(bad_value(MD_ENTRY_PX, "1e300"))
+        );
+        assert_eq!(
+            refresh("44.10", "1000000001"),
+            Err(bad_value(MD_ENTRY_SIZE, "1000000001"))
+        );
+        assert_eq!(refresh("44.10", "-1"), Err(bad_value(MD_ENTRY_SIZE, "-1")));
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time(52, "20240102-08:00:00.538"), Ok(28800538));
+        assert_eq!(parse_time(273, "08:00:01.5"), Ok(28801500));
+        assert_eq!(parse_time(273, "08:00:01"), Ok(28801000));
+        assert!(parse_time(273, "08:00").is_err());
+        assert!(parse_time(273, "08:00:01.1234").is_err());
+    }
+}
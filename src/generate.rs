@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+
+use amount::Amount;
+use orderside::OrderSide;
+use pipeline::OwnedMessage;
+
+// Synthetic feeds for benchmarks and tests. The mid price takes a random walk
+// in ticks, adds rest a few ticks away from it on their side (never crossing
+// the other side) and reduces take a random resting order down, in part or in
+// full. Everything comes off one seeded generator, so a seed and a config
+// always make the same feed.
+
+/// SplitMix64, small, fast and the same everywhere for a given seed
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in 0..n, n has to be above 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        // multiply and shift rather than modulo, no bias worth speaking of for small n
+        ((u128::from(self.next_u64()) * u128::from(n)) >> 64) as u64
+    }
+
+    /// Uniform in [0, 1)
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with probability p
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.unit() < p
+    }
+}
+
+/// What kind of feed to make
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeedConfig {
+    pub messages: u64,
+    /// orders resting at most, reduces take over once there are this many
+    pub live_orders: usize,
+    pub seed: u64,
+    pub start_price: Amount,
+    pub tick: Amount,
+    /// chance the mid price moves a tick up or down with each message
+    pub walk: f64,
+    /// how many ticks away from the mid orders rest, at most
+    pub depth_ticks: u64,
+    /// share of adds while there's room for more live orders
+    pub add_ratio: f64,
+    /// share of reduces that leave some of the order resting
+    pub partial_ratio: f64,
+    /// order sizes are uniform in 1..=max_size
+    pub max_size: i64,
+    pub start_timestamp: i64,
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        FeedConfig {
+            messages: 100000,
+            live_orders: 1000,
+            seed: 1,
+            start_price: Amount { as_int: 4400 },
+            tick: Amount { as_int: 1 },
+            walk: 0.1,
+            depth_ticks: 20,
+            add_ratio: 0.5,
+            partial_ratio: 0.3,
+            max_size: 500,
+            start_timestamp: 28800000, // 8am, like the sample feed
+        }
+    }
+}
+
+/// Makes config.messages messages, see FeedConfig
+pub struct FeedGenerator {
+    config: FeedConfig,
+    rng: Rng,
+    mid_ticks: i64,
+    timestamp: i64,
+    made: u64,
+    next_id: u64,
+    live: Vec<Resting>,
+    // orders resting at each price in ticks, to keep the book from crossing
+    bids: BTreeMap<i64, u32>,
+    asks: BTreeMap<i64, u32>,
+}
+
+struct Resting {
+    id: String,
+    side: OrderSide,
+    ticks: i64,
+    left: i64,
+}
+
+impl FeedGenerator {
+    pub fn new(config: FeedConfig) -> Self {
+        let tick = config.tick.as_int.max(1);
+        // keep the far side of the book above zero
+        let lowest = config.depth_ticks as i64 + 2;
+        FeedGenerator {
+            rng: Rng::new(config.seed),
+            mid_ticks: (config.start_price.as_int / tick).max(lowest),
+            timestamp: config.start_timestamp,
+            made: 0,
+            next_id: 0,
+            live: Vec::with_capacity(config.live_orders),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            config,
+        }
+    }
+
+    /// Orders resting right now
+    pub fn live_orders(&self) -> usize {
+        self.live.len()
+    }
+
+    // short letter ids like the sample feed, "b", "c", ..., "ba", "bb"
+    fn new_id(&mut self) -> String {
+        let mut n = self.next_id;
+        self.next_id += 1;
+        let mut id = Vec::new();
+        loop {
+            id.push(b'a' + (n % 26) as u8);
+            n /= 26;
+            if n == 0 {
+                break;
+            }
+        }
+        id.reverse();
+        String::from_utf8(id).unwrap()
+    }
+
+    fn levels(&mut self, side: OrderSide) -> &mut BTreeMap<i64, u32> {
+        match side {
+            OrderSide::Bid => &mut self.bids,
+            OrderSide::Ask => &mut self.asks,
+        }
+    }
+
+    fn add(&mut self) -> OwnedMessage {
+        let side = if self.rng.chance(0.5) {
+            OrderSide::Bid
+        } else {
+            OrderSide::Ask
+        };
+        let away = 1 + self.rng.below(self.config.depth_ticks.max(1)) as i64;
+        // the mid can walk past orders left behind on the other side
+        let ticks = match side {
+            OrderSide::Bid => match self.asks.keys().next() {
+                Some(&best_ask) => (self.mid_ticks - away).min(best_ask - 1),
+                None => self.mid_ticks - away,
+            },
+            OrderSide::Ask => match self.bids.keys().next_back() {
+                Some(&best_bid) => (self.mid_ticks + away).max(best_bid + 1),
+                None => self.mid_ticks + away,
+            },
+        };
+        let size = 1 + self.rng.below(self.config.max_size.max(1) as u64) as i64;
+        let id = self.new_id();
+        *self.levels(side).entry(ticks).or_insert(0) += 1;
+        self.live.push(Resting {
+            id: id.clone(),
+            side,
+            ticks,
+            left: size,
+        });
+        OwnedMessage::Add {
+            timestamp: self.timestamp,
+            id,
+            side,
+            price: self.config.tick * ticks,
+            size,
+        }
+    }
+
+    fn reduce(&mut self) -> OwnedMessage {
+        let idx = self.rng.below(self.live.len() as u64) as usize;
+        let left = self.live[idx].left;
+        let partial = left > 1 && self.rng.chance(self.config.partial_ratio);
+        let (id, size) = if partial {
+            let size = 1 + self.rng.below(left as u64 - 1) as i64;
+            self.live[idx].left -= size;
+            (self.live[idx].id.clone(), size)
+        } else {
+            let gone = self.live.swap_remove(idx);
+            let levels = self.levels(gone.side);
+            let count = levels
+                .get_mut(&gone.ticks)
+                .expect("every live order has a level");
+            *count -= 1;
+            if *count == 0 {
+                levels.remove(&gone.ticks);
+            }
+            (gone.id, gone.left)
+        };
+        OwnedMessage::Reduce {
+            timestamp: self.timestamp,
+            id,
+            size,
+        }
+    }
+}
+
+impl Iterator for FeedGenerator {
+    type Item = OwnedMessage;
+
+    fn next(&mut self) -> Option<OwnedMessage> {
+        if self.made == self.config.messages {
+            return None;
+        }
+        self.made += 1;
+        self.timestamp += self.rng.below(4) as i64;
+        if self.rng.chance(self.config.walk) {
+            let lowest = self.config.depth_ticks as i64 + 2;
+            let step = if self.rng.chance(0.5) { 1 } else { -1 };
+            self.mid_ticks = (self.mid_ticks + step).max(lowest);
+        }
+        let room = self.live.len() < self.config.live_orders;
+        if self.live.is_empty() || (room && self.rng.chance(self.config.add_ratio)) {
+            Some(self.add())
+        } else {
+            Some(self.reduce())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use orderbook::OrderBook;
+    use orders::Message;
+    use validate::Validator;
+
+    fn lines(config: FeedConfig) -> Vec<String> {
+        FeedGenerator::new(config)
+            .map(|message| message.as_message().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn rng_is_seeded() {
+        let (mut a, mut b, mut c) = (Rng::new(7), Rng::new(7), Rng::new(8));
+        for _ in 0..100 {
+            let n = a.next_u64();
+            assert_eq!(n, b.next_u64());
+            assert_ne!(n, c.next_u64());
+        }
+        for _ in 0..100 {
+            assert!(a.below(10) < 10);
+            assert!((0.0..1.0).contains(&a.unit()));
+        }
+    }
+
+    #[test]
+    fn same_seed_same_feed() {
+        let config = FeedConfig {
+            messages: 2000,
+            ..FeedConfig::default()
+        };
+        let feed = lines(config.clone());
+        assert_eq!(feed.len(), 2000);
+        assert_eq!(feed, lines(config.clone()));
+        assert_ne!(feed, lines(FeedConfig { seed: 2, ..config }));
+        assert!(feed[0].starts_with("28800"));
+    }
+
+    #[test]
+    fn feeds_are_valid_and_stay_in_shape() {
+        let config = FeedConfig {
+            messages: 20000,
+            live_orders: 200,
+            tick: Amount { as_int: 5 },
+            ..FeedConfig::default()
+        };
+        let mut generator = FeedGenerator::new(config);
+        let mut book = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        let mut validator = Validator::new();
+        let mut reduces = 0;
+        for message in &mut generator {
+            let message = message.as_message();
+            if let Message::Add { price, .. } = message {
+                assert_eq!(price.as_int % 5, 0);
+                assert!(price.as_int > 0);
+            } else {
+                reduces += 1;
+            }
+            assert_eq!(validator.check(&message), Vec::new());
+            book.apply(&message).unwrap();
+        }
+        assert!(generator.live_orders() <= 200);
+        assert!(reduces > 5000);
+        assert_eq!(
+            book.total_size(OrderSide::Bid) + book.total_size(OrderSide::Ask),
+            generator.live.iter().map(|order| order.left).sum::<i64>()
+        );
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+use std::mem;
+
+use amount::Amount;
+
+use orderside::OrderSide;
+
+use orders::LimitOrder;
+
+/// Price cache strategy (for benchmarking)
+pub trait IdPriceCache {
+    fn insert(&mut self, order: &LimitOrder);
+    fn contains_key(&self, key: &u64) -> bool;
+    fn get(&self, key: &u64) -> Option<&(Amount, OrderSide)>;
+    fn remove(&mut self, key: &u64) -> Option<(Amount, OrderSide)>;
+    /// Number of orders cached
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub type IdPriceCacheFnvMap = fnv::FnvHashMap<u64, (Amount, OrderSide)>;
+pub type IdPriceCacheStdMap = HashMap<u64, (Amount, OrderSide)>;
+
+pub type IdPriceCacheIdentityMap =
+    HashMap<u64, (Amount, OrderSide), BuildHasherDefault<IdentityHasher>>;
+
+/// Hasher that skips hashing u64 keys, only spreading their bits with one multiply.
+/// Meant for numeric order ids (see IdMode::Numeric), which are unique already
+#[derive(Default)]
+pub struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        // hashbrown takes its probe tags from the top 7 bits, all zero on small ids as they are.
+        // Fibonacci hashing like the robin-hood table, odd so keys still never share a hash
+        self.0.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        // only here to satisfy the trait, the cache only ever hashes u64s
+        for byte in bytes {
+            self.0 = (self.0 << 8) | u64::from(*byte);
+        }
+    }
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+// covers the fnv, identity and std baseline maps - they only differ in hasher
+impl<S: BuildHasher> IdPriceCache for HashMap<u64, (Amount, OrderSide), S> {
+    fn insert(&mut self, order: &LimitOrder) {
+        self.insert(order.id, (order.price, order.side));
+    }
+    fn contains_key(&self, key: &u64) -> bool {
+        self.contains_key(key)
+    }
+    fn get(&self, key: &u64) -> Option<&(Amount, OrderSide)> {
+        self.get(key)
+    }
+    fn remove(&mut self, key: &u64) -> Option<(Amount, OrderSide)> {
+        self.remove(key)
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct RobinHoodSlot {
+    key: u64,
+    value: (Amount, OrderSide),
+    // how far the slot is from where its key hashes to
+    dist: usize,
+}
+
+/// Open-addressing table with robin-hood probing and backward-shift deletion.
+/// Everything lives in one flat Vec, so lookups walk contiguous memory.
+pub struct IdPriceCacheRobinHood {
+    slots: Vec<Option<RobinHoodSlot>>,
+    len: usize,
+    mask: usize,
+}
+
+impl IdPriceCacheRobinHood {
+    pub fn with_capacity(capacity: usize) -> Self {
+        // keep the load factor under 7/8
+        let slots = (capacity * 8 / 7 + 1).next_power_of_two().max(8);
+        IdPriceCacheRobinHood {
+            slots: vec![None; slots],
+            len: 0,
+            mask: slots - 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn ideal_slot(&self, key: u64) -> usize {
+        // fibonacci hashing spreads sequential keys across the table
+        (key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize & self.mask
+    }
+
+    fn find(&self, key: u64) -> Option<usize> {
+        let mut idx = self.ideal_slot(key);
+        let mut dist = 0;
+        while let Some(ref slot) = self.slots[idx] {
+            if slot.key == key {
+                return Some(idx);
+            }
+            if slot.dist < dist {
+                // a resident closer to home means our key would have taken this slot
+                return None;
+            }
+            idx = (idx + 1) & self.mask;
+            dist += 1;
+        }
+        None
+    }
+
+    fn grow(&mut self) {
+        let new_size = self.slots.len() * 2;
+        let old = mem::replace(&mut self.slots, vec![None; new_size]);
+        self.mask = new_size - 1;
+        self.len = 0;
+        for slot in old.into_iter().flatten() {
+            self.insert_slot(slot.key, slot.value);
+        }
+    }
+
+    fn insert_slot(&mut self, key: u64, value: (Amount, OrderSide)) {
+        if (self.len + 1) * 8 > self.slots.len() * 7 {
+            self.grow();
+        }
+        let mut idx = self.ideal_slot(key);
+        let mut carried = RobinHoodSlot {
+            key,
+            value,
+            dist: 0,
+        };
+        loop {
+            match self.slots[idx] {
+                None => {
+                    self.slots[idx] = Some(carried);
+                    self.len += 1;
+                    return;
+                }
+                Some(ref mut resident) => {
+                    if resident.key == carried.key {
+                        resident.value = carried.value;
+                        return;
+                    }
+                    if resident.dist < carried.dist {
+                        // take from the rich, the resident keeps probing instead
+                        mem::swap(resident, &mut carried);
+                    }
+                }
+            }
+            idx = (idx + 1) & self.mask;
+            carried.dist += 1;
+        }
+    }
+}
+
+impl IdPriceCache for IdPriceCacheRobinHood {
+    fn insert(&mut self, order: &LimitOrder) {
+        self.insert_slot(order.id, (order.price, order.side));
+    }
+    fn contains_key(&self, key: &u64) -> bool {
+        self.find(*key).is_some()
+    }
+    fn get(&self, key: &u64) -> Option<&(Amount, OrderSide)> {
+        self.find(*key)
+            .and_then(|idx| self.slots[idx].as_ref())
+            .map(|slot| &slot.value)
+    }
+    fn remove(&mut self, key: &u64) -> Option<(Amount, OrderSide)> {
+        let mut idx = self.find(*key)?;
+        let removed = self.slots[idx].take().map(|slot| slot.value);
+        self.len -= 1;
+        // shift the following cluster back by one, so probes never stop at the hole
+        loop {
+            let next = (idx + 1) & self.mask;
+            match self.slots[next] {
+                Some(ref slot) if slot.dist > 0 => {}
+                _ => break,
+            }
+            let mut moved = self.slots[next].take();
+            if let Some(ref mut slot) = moved {
+                slot.dist -= 1;
+            }
+            self.slots[idx] = moved;
+            idx = next;
+        }
+        removed
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+// Interned handles are given out one by one, anything this high isn't one
+const MAX_SLAB_KEY: u64 = u32::MAX as u64;
+
+/// Flat Vec indexed straight by the dense handles IdMode::Intern gives out,
+/// so nothing is hashed. Only works with interned ids: any other key is a slot
+/// number too, hashed ids would need a Vec as big as the hash space.
+/// Handles are never reused, so the Vec grows with the number of distinct ids.
+pub struct IdPriceCacheSlab {
+    entries: Vec<Option<(Amount, OrderSide)>>,
+    len: usize,
+}
+
+impl IdPriceCacheSlab {
+    pub fn with_capacity(capacity: usize) -> Self {
+        IdPriceCacheSlab {
+            entries: Vec::with_capacity(capacity),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl IdPriceCache for IdPriceCacheSlab {
+    /// Panics on a key that can't be an interned handle
+    fn insert(&mut self, order: &LimitOrder) {
+        assert!(
+            order.id <= MAX_SLAB_KEY,
+            "IdPriceCacheSlab needs interned ids, got key {}",
+            order.id
+        );
+        let slot = order.id as usize;
+        if slot >= self.entries.len() {
+            self.entries.resize(slot + 1, None);
+        }
+        if self.entries[slot].is_none() {
+            self.len += 1;
+        }
+        self.entries[slot] = Some((order.price, order.side));
+    }
+    fn contains_key(&self, key: &u64) -> bool {
+        self.get(key).is_some()
+    }
+    fn get(&self, key: &u64) -> Option<&(Amount, OrderSide)> {
+        self.entries
+            .get(*key as usize)
+            .and_then(|entry| entry.as_ref())
+    }
+    fn remove(&mut self, key: &u64) -> Option<(Amount, OrderSide)> {
+        let removed = self.entries.get_mut(*key as usize)?.take();
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orders::hash;
+    use std::collections::HashSet;
+
+    fn hashed(id: &str) -> u64 {
+        hash(id)
+    }
+
+    // stands in for the interned handles the slab needs, one per letter
+    fn interned(id: &str) -> u64 {
+        u64::from(id.as_bytes()[0] - b'a')
+    }
+
+    fn order(id: u64, side: OrderSide, price: &str) -> LimitOrder {
+        LimitOrder {
+            timestamp: 28800538,
+            id,
+            side,
+            price: Amount::new_from_str(price),
+            size: 100,
+        }
+    }
+
+    fn insert_then_get<T: IdPriceCache>(mut cache: T, key: fn(&str) -> u64) {
+        cache.insert(&order(key("b"), OrderSide::Ask, "44.26"));
+        assert!(cache.contains_key(&key("b")));
+        assert_eq!(
+            cache.get(&key("b")),
+            Some(&(Amount::new_from_str("44.26"), OrderSide::Ask))
+        );
+    }
+
+    fn missing_key<T: IdPriceCache>(mut cache: T, key: fn(&str) -> u64) {
+        cache.insert(&order(key("b"), OrderSide::Ask, "44.26"));
+        assert!(!cache.contains_key(&key("c")));
+        assert_eq!(cache.get(&key("c")), None);
+        assert_eq!(cache.remove(&key("c")), None);
+    }
+
+    fn insert_overwrites<T: IdPriceCache>(mut cache: T, key: fn(&str) -> u64) {
+        cache.insert(&order(key("b"), OrderSide::Ask, "44.26"));
+        cache.insert(&order(key("b"), OrderSide::Bid, "44.10"));
+        assert_eq!(
+            cache.get(&key("b")),
+            Some(&(Amount::new_from_str("44.10"), OrderSide::Bid))
+        );
+        assert_eq!(cache.len(), 1);
+    }
+
+    fn remove_returns_value<T: IdPriceCache>(mut cache: T, key: fn(&str) -> u64) {
+        cache.insert(&order(key("b"), OrderSide::Ask, "44.26"));
+        cache.insert(&order(key("c"), OrderSide::Bid, "44.10"));
+        assert_eq!(
+            cache.remove(&key("b")),
+            Some((Amount::new_from_str("44.26"), OrderSide::Ask))
+        );
+        assert!(!cache.contains_key(&key("b")));
+        assert!(cache.contains_key(&key("c")));
+        assert_eq!(cache.remove(&key("b")), None);
+        assert_eq!(cache.len(), 1);
+        cache.remove(&key("c"));
+        assert!(cache.is_empty());
+    }
+
+    fn reinsert_after_remove<T: IdPriceCache>(mut cache: T, key: fn(&str) -> u64) {
+        cache.insert(&order(key("b"), OrderSide::Ask, "44.26"));
+        cache.remove(&key("b"));
+        cache.insert(&order(key("b"), OrderSide::Bid, "43.00"));
+        assert_eq!(
+            cache.get(&key("b")),
+            Some(&(Amount::new_from_str("43.00"), OrderSide::Bid))
+        );
+    }
+
+    fn matches_fnv_map_under_churn<T: IdPriceCache>(mut cache: T) {
+        // sequential keys cluster in any table with a weak hash,
+        // so this walks the probing and backward shifting paths
+        let mut model = IdPriceCacheFnvMap::default();
+        for i in 0..5000u64 {
+            let lo = LimitOrder {
+                timestamp: i as i64,
+                id: i % 1500,
+                side: if i % 2 == 0 {
+                    OrderSide::Bid
+                } else {
+                    OrderSide::Ask
+                },
+                price: Amount { as_int: i as i64 },
+                size: 1,
+            };
+            if i % 3 == 0 {
+                let key = (i * 7) % 1500;
+                assert_eq!(cache.remove(&key), model.remove(&key));
+            } else {
+                cache.insert(&lo);
+                IdPriceCache::insert(&mut model, &lo);
+            }
+        }
+        for key in 0..1600u64 {
+            assert_eq!(cache.get(&key), model.get(&key));
+        }
+    }
+
+    macro_rules! cache_test_suite {
+        ($name:ident, $ctor:expr, $key:expr) => {
+            mod $name {
+                use super::*;
+
+                #[test]
+                fn insert_then_get_works() {
+                    insert_then_get($ctor, $key);
+                }
+
+                #[test]
+                fn missing_key_is_none() {
+                    missing_key($ctor, $key);
+                }
+
+                #[test]
+                fn insert_overwrites_existing() {
+                    insert_overwrites($ctor, $key);
+                }
+
+                #[test]
+                fn remove_returns_removed_value() {
+                    remove_returns_value($ctor, $key);
+                }
+
+                #[test]
+                fn reinsert_after_remove_works() {
+                    reinsert_after_remove($ctor, $key);
+                }
+
+                #[test]
+                fn matches_fnv_map() {
+                    matches_fnv_map_under_churn($ctor);
+                }
+            }
+        };
+    }
+
+    cache_test_suite!(fnv_map, IdPriceCacheFnvMap::default(), hashed);
+    cache_test_suite!(std_map, IdPriceCacheStdMap::new(), hashed);
+    cache_test_suite!(identity_map, IdPriceCacheIdentityMap::default(), hashed);
+    cache_test_suite!(robin_hood, IdPriceCacheRobinHood::with_capacity(4), hashed);
+    cache_test_suite!(slab, IdPriceCacheSlab::with_capacity(4), interned);
+
+    #[test]
+    fn identity_hasher_spreads_small_keys() {
+        let tags: HashSet<u64> = (0..128u64)
+            .map(|key| {
+                let mut hasher = IdentityHasher::default();
+                hasher.write_u64(key);
+                hasher.finish() >> 57
+            })
+            .collect();
+        assert!(tags.len() > 64, "{} tags", tags.len());
+    }
+
+    #[test]
+    fn robin_hood_grows_past_capacity() {
+        let mut cache = IdPriceCacheRobinHood::with_capacity(4);
+        for i in 0..100 {
+            cache.insert(&order(hashed(&i.to_string()), OrderSide::Bid, "1.00"));
+        }
+        assert_eq!(cache.len(), 100);
+        assert!(cache.slots.len() * 7 >= cache.len() * 8);
+    }
+
+    #[test]
+    fn slab_is_indexed_by_handle() {
+        let mut cache = IdPriceCacheSlab::with_capacity(4);
+        cache.insert(&order(2, OrderSide::Ask, "44.26"));
+        assert_eq!(cache.entries.len(), 3);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get(&hash("b")), None);
+        assert_eq!(cache.remove(&hash("b")), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "IdPriceCacheSlab needs interned ids")]
+    fn slab_refuses_hashed_ids() {
+        IdPriceCacheSlab::with_capacity(4).insert(&order(hash("b"), OrderSide::Ask, "44.26"));
+    }
+}
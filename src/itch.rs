@@ -0,0 +1,739 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use fnv::FnvHashMap;
+
+use amount::Amount;
+use idpricecache::IdPriceCacheFnvMap;
+use orderbook::{BookError, OrderBook};
+use orders::{LimitOrder, ReduceOrder};
+use orderside::OrderSide;
+
+// Decoder for the NASDAQ TotalView-ITCH 5.0 messages that move the book.
+// Fields are big endian, every message starts with
+//   type:u8 stock_locate:u16 tracking_number:u16 timestamp:u48 (ns since midnight)
+// and the file format frames each message with a u16 big endian length.
+
+/// An ITCH message the book cares about. Prices have 4 implied decimals
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ItchMessage {
+    /// 'R', names the stock behind a locate code
+    StockDirectory {
+        locate: u16,
+        timestamp: u64,
+        stock: String,
+    },
+    /// 'A' and 'F', the latter carries the market participant
+    AddOrder {
+        locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        side: OrderSide,
+        shares: u32,
+        stock: String,
+        price: u32,
+        attribution: Option<String>,
+    },
+    /// 'E' and 'C', the latter at a price other than the order's
+    OrderExecuted {
+        locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        shares: u32,
+        match_number: u64,
+        price: Option<u32>,
+    },
+    /// 'X', part of the order is cancelled
+    OrderCancel {
+        locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        shares: u32,
+    },
+    /// 'D', what's left of the order is gone
+    OrderDelete {
+        locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+    },
+    /// 'U', the order is pulled and a new one added on the same side
+    OrderReplace {
+        locate: u16,
+        timestamp: u64,
+        original_ref: u64,
+        new_ref: u64,
+        shares: u32,
+        price: u32,
+    },
+    /// every other message type, skipped
+    Other(u8),
+}
+
+/// Why an ITCH message couldn't be decoded or applied
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ItchError {
+    Empty,
+    Truncated {
+        msg_type: u8,
+        expected: usize,
+        found: usize,
+    },
+    BadSide(u8),
+    /// executed, cancelled, deleted or replaced an order that isn't resting
+    UnknownOrder(u64),
+    Book(BookError),
+}
+
+impl Display for ItchError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ItchError::Empty => write!(f, "empty message"),
+            ItchError::Truncated {
+                msg_type,
+                expected,
+                found,
+            } => write!(
+                f,
+                "message {:?} needs {} bytes, found {}",
+                msg_type as char, expected, found
+            ),
+            ItchError::BadSide(side) => write!(f, "bad buy/sell indicator {:#04x}", side),
+            ItchError::UnknownOrder(order_ref) => {
+                write!(f, "no order under reference {}", order_ref)
+            }
+            ItchError::Book(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ItchError {}
+
+impl From<BookError> for ItchError {
+    fn from(err: BookError) -> Self {
+        ItchError::Book(err)
+    }
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from(bytes[at]) << 8 | u16::from(bytes[at + 1])
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> u32 {
+    let mut be = [0u8; 4];
+    be.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_be_bytes(be)
+}
+
+fn be_u48(bytes: &[u8], at: usize) -> u64 {
+    let mut be = [0u8; 8];
+    be[2..].copy_from_slice(&bytes[at..at + 6]);
+    u64::from_be_bytes(be)
+}
+
+fn be_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut be = [0u8; 8];
+    be.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_be_bytes(be)
+}
+
+// alpha fields are ASCII, left justified and padded with spaces
+fn alpha(bytes: &[u8], at: usize, len: usize) -> String {
+    String::from_utf8_lossy(&bytes[at..at + len])
+        .trim_end()
+        .to_string()
+}
+
+fn expected_len(msg_type: u8) -> Option<usize> {
+    match msg_type {
+        b'R' => Some(39),
+        b'A' => Some(36),
+        b'F' => Some(40),
+        b'E' => Some(31),
+        b'C' => Some(36),
+        b'X' => Some(23),
+        b'D' => Some(19),
+        b'U' => Some(35),
+        _ => None,
+    }
+}
+
+/// Decodes one message, without its length prefix
+pub fn decode(bytes: &[u8]) -> Result<ItchMessage, ItchError> {
+    let msg_type = *bytes.first().ok_or(ItchError::Empty)?;
+    let expected = match expected_len(msg_type) {
+        Some(expected) => expected,
+        None => return Ok(ItchMessage::Other(msg_type)),
+    };
+    if bytes.len() < expected {
+        return Err(ItchError::Truncated {
+            msg_type,
+            expected,
+            found: bytes.len(),
+        });
+    }
+    let locate = be_u16(bytes, 1);
+    let timestamp = be_u48(bytes, 5);
+    let message = match msg_type {
+        b'R' => ItchMessage::StockDirectory {
+            locate,
+            timestamp,
+            stock: alpha(bytes, 11, 8),
+        },
+        b'A' | b'F' => ItchMessage::AddOrder {
+            locate,
+            timestamp,
+            order_ref: be_u64(bytes, 11),
+            side: match bytes[19] {
+                b'B' => OrderSide::Bid,
+                b'S' => OrderSide::Ask,
+                other => return Err(ItchError::BadSide(other)),
+            },
+            shares: be_u32(bytes, 20),
+            stock: alpha(bytes, 24, 8),
+            price: be_u32(bytes, 32),
+            attribution: if msg_type == b'F' {
+                Some(alpha(bytes, 36, 4))
+            } else {
+                None
+            },
+        },
+        b'E' | b'C' => ItchMessage::OrderExecuted {
+            locate,
+            timestamp,
+            order_ref: be_u64(bytes, 11),
+            shares: be_u32(bytes, 19),
+            match_number: be_u64(bytes, 23),
+            price: if msg_type == b'C' {
+                Some(be_u32(bytes, 32))
+            } else {
+                None
+            },
+        },
+        b'X' => ItchMessage::OrderCancel {
+            locate,
+            timestamp,
+            order_ref: be_u64(bytes, 11),
+            shares: be_u32(bytes, 19),
+        },
+        b'D' => ItchMessage::OrderDelete {
+            locate,
+            timestamp,
+            order_ref: be_u64(bytes, 11),
+        },
+        b'U' => ItchMessage::OrderReplace {
+            locate,
+            timestamp,
+            original_ref: be_u64(bytes, 11),
+            new_ref: be_u64(bytes, 19),
+            shares: be_u32(bytes, 27),
+            price: be_u32(bytes, 31),
+        },
+        _ => unreachable!("expected_len covers every decoded type"),
+    };
+    Ok(message)
+}
+
+/// Reads the next length-prefixed message into buf.
+/// Returns false at a clean end of input
+pub fn read_frame<R: Read>(input: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let mut len = [0u8; 2];
+    match input.read_exact(&mut len[..1]) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(err) => return Err(err),
+    }
+    input.read_exact(&mut len[1..])?;
+    buf.clear();
+    buf.resize(be_u16(&len, 0) as usize, 0);
+    input.read_exact(buf)?;
+    Ok(true)
+}
+
+// ITCH prices have 4 decimals, Amount has 2. Sub-penny prices are truncated to the cent
+fn to_amount(price: u32) -> Amount {
+    Amount {
+        as_int: i64::from(price / 100),
+    }
+}
+
+// what the book doesn't keep about a resting order
+struct Resting {
+    locate: u16,
+    shares: u32,
+}
+
+/// Keeps one OrderBook per stock locate up to date from ITCH messages.
+/// Order reference numbers are unique for the day, so they are the books' cache keys as they are
+pub struct ItchHandler {
+    target_size: i64,
+    books: FnvHashMap<u16, OrderBook<IdPriceCacheFnvMap>>,
+    stocks: FnvHashMap<u16, String>,
+    orders: FnvHashMap<u64, Resting>,
+}
+
+impl ItchHandler {
+    /// target_size is handed to every book
+    pub fn new(target_size: i64) -> Self {
+        ItchHandler {
+            target_size,
+            books: FnvHashMap::default(),
+            stocks: FnvHashMap::default(),
+            orders: FnvHashMap::default(),
+        }
+    }
+
+    pub fn book(&self, locate: u16) -> Option<&OrderBook<IdPriceCacheFnvMap>> {
+        self.books.get(&locate)
+    }
+
+    /// Stock symbol, from the directory or the first add for the locate
+    pub fn stock(&self, locate: u16) -> Option<&str> {
+        self.stocks.get(&locate).map(|stock| stock.as_str())
+    }
+
+    /// Locate code for a stock symbol
+    pub fn locate(&self, stock: &str) -> Option<u16> {
+        self.stocks
+            .iter()
+            .find(|&(_, name)| name == stock)
+            .map(|(&locate, _)| locate)
+    }
+
+    /// Locates with a book, in no particular order
+    pub fn locates(&self) -> Vec<u16> {
+        self.books.keys().cloned().collect()
+    }
+
+    /// Shares left on an order
+    pub fn resting_shares(&self, order_ref: u64) -> Option<u32> {
+        self.orders.get(&order_ref).map(|resting| resting.shares)
+    }
+
+    fn book_mut(&mut self, locate: u16) -> &mut OrderBook<IdPriceCacheFnvMap> {
+        let target_size = self.target_size;
+        self.books
+            .entry(locate)
+            .or_insert_with(|| OrderBook::new(target_size, IdPriceCacheFnvMap::default()))
+    }
+
+    /// Applies a message, returns the locate of the book it changed
+    pub fn apply(&mut self, message: &ItchMessage) -> Result<Option<u16>, ItchError> {
+        match *message {
+            ItchMessage::StockDirectory {
+                locate, ref stock, ..
+            } => {
+                self.stocks.insert(locate, stock.clone());
+                self.book_mut(locate);
+                Ok(None)
+            }
+            ItchMessage::AddOrder {
+                locate,
+                timestamp,
+                order_ref,
+                side,
+                shares,
+                ref stock,
+                price,
+                ..
+            } => {
+                self.stocks.entry(locate).or_insert_with(|| stock.clone());
+                self.add(locate, timestamp, order_ref, side, shares, price)?;
+                Ok(Some(locate))
+            }
+            ItchMessage::OrderExecuted {
+                timestamp,
+                order_ref,
+                shares,
+                ..
+            }
+            | ItchMessage::OrderCancel {
+                timestamp,
+                order_ref,
+                shares,
+                ..
+            } => self.reduce(timestamp, order_ref, Some(shares)).map(Some),
+            ItchMessage::OrderDelete {
+                timestamp,
+                order_ref,
+                ..
+            } => self.reduce(timestamp, order_ref, None).map(Some),
+            ItchMessage::OrderReplace {
+                timestamp,
+                original_ref,
+                new_ref,
+                shares,
+                price,
+                ..
+            } => {
+                let side = {
+                    let locate = match self.orders.get(&original_ref) {
+                        Some(resting) => resting.locate,
+                        None => return Err(ItchError::UnknownOrder(original_ref)),
+                    };
+                    match self.book_mut(locate).cache().get(&original_ref) {
+                        Some(&(_, side)) => side,
+                        None => return Err(ItchError::UnknownOrder(original_ref)),
+                    }
+                };
+                let locate = self.reduce(timestamp, original_ref, None)?;
+                self.add(locate, timestamp, new_ref, side, shares, price)?;
+                Ok(Some(locate))
+            }
+            ItchMessage::Other(_) => Ok(None),
+        }
+    }
+
+    // the order is only kept once the book has taken it, so the two never disagree
+    fn add(
+        &mut self,
+        locate: u16,
+        timestamp: u64,
+        order_ref: u64,
+        side: OrderSide,
+        shares: u32,
+        price: u32,
+    ) -> Result<(), ItchError> {
+        self.book_mut(locate).try_add(LimitOrder {
+            timestamp: timestamp as i64,
+            id: order_ref,
+            side,
+            price: to_amount(price),
+            size: i64::from(shares),
+        })?;
+        self.orders.insert(order_ref, Resting { locate, shares });
+        Ok(())
+    }
+
+    // None takes off whatever is left
+    fn reduce(
+        &mut self,
+        timestamp: u64,
+        order_ref: u64,
+        shares: Option<u32>,
+    ) -> Result<u16, ItchError> {
+        let (locate, size) = match self.orders.get(&order_ref) {
+            Some(resting) => (
+                resting.locate,
+                shares.unwrap_or(resting.shares).min(resting.shares),
+            ),
+            None => return Err(ItchError::UnknownOrder(order_ref)),
+        };
+        self.book_mut(locate).try_reduce_order(&ReduceOrder {
+            timestamp: timestamp as i64,
+            id: order_ref,
+            size: i64::from(size),
+        })?;
+        let left = match self.orders.get_mut(&order_ref) {
+            Some(resting) => {
+                resting.shares -= size;
+                resting.shares
+            }
+            None => unreachable!("looked up above"),
+        };
+        if left == 0 {
+            self.orders.remove(&order_ref);
+        }
+        Ok(locate)
+    }
+
+    /// Decodes and applies every length-prefixed message in input.
+    /// Returns how many messages were read
+    pub fn replay<R: Read>(&mut self, input: &mut R) -> Result<u64, Box<dyn Error>> {
+        let mut buf = Vec::new();
+        let mut count = 0;
+        while read_frame(input, &mut buf)? {
+            count += 1;
+            let message = decode(&buf).map_err(|err| format!("message {}: {}", count, err))?;
+            self.apply(&message)
+                .map_err(|err| format!("message {}: {}", count, err))?;
+        }
+        Ok(count)
+    }
+
+    /// replay for a file on disk
+    pub fn replay_file<P: AsRef<Path>>(&mut self, path: P) -> Result<u64, Box<dyn Error>> {
+        let mut input = BufReader::new(File::open(path)?);
+        self.replay(&mut input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hand-built messages, laid out as in the ITCH 5.0 spec
+
+    fn header(msg_type: u8, locate: u16, timestamp: u64) -> Vec<u8> {
+        let mut msg = vec![msg_type];
+        msg.extend_from_slice(&locate.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes()); // tracking number
+        msg.extend_from_slice(&timestamp.to_be_bytes()[2..]);
+        msg
+    }
+
+    fn padded(text: &str, len: usize) -> Vec<u8> {
+        let mut field = text.as_bytes().to_vec();
+        field.resize(len, b' ');
+        field
+    }
+
+    fn stock_directory(locate: u16, stock: &str) -> Vec<u8> {
+        let mut msg = header(b'R', locate, 1);
+        msg.extend(padded(stock, 8));
+        msg.extend_from_slice(b"QN"); // market category, financial status
+        msg.extend_from_slice(&100u32.to_be_bytes()); // round lot size
+        msg.extend_from_slice(b"NCZ ");
+        msg.extend_from_slice(b"PN 1N");
+        msg.extend_from_slice(&0u32.to_be_bytes());
+        msg.push(b'N');
+        msg
+    }
+
+    fn add_order(locate: u16, order_ref: u64, side: u8, shares: u32, price: u32) -> Vec<u8> {
+        let mut msg = header(b'A', locate, 34200000000000 + order_ref);
+        msg.extend_from_slice(&order_ref.to_be_bytes());
+        msg.push(side);
+        msg.extend_from_slice(&shares.to_be_bytes());
+        msg.extend(padded("AAPL", 8));
+        msg.extend_from_slice(&price.to_be_bytes());
+        msg
+    }
+
+    fn add_order_mpid(locate: u16, order_ref: u64, side: u8, shares: u32, price: u32) -> Vec<u8> {
+        let mut msg = add_order(locate, order_ref, side, shares, price);
+        msg[0] = b'F';
+        msg.extend_from_slice(b"GSCO");
+        msg
+    }
+
+    fn executed(locate: u16, order_ref: u64, shares: u32) -> Vec<u8> {
+        let mut msg = header(b'E', locate, 34300000000000);
+        msg.extend_from_slice(&order_ref.to_be_bytes());
+        msg.extend_from_slice(&shares.to_be_bytes());
+        msg.extend_from_slice(&77u64.to_be_bytes());
+        msg
+    }
+
+    fn executed_with_price(locate: u16, order_ref: u64, shares: u32, price: u32) -> Vec<u8> {
+        let mut msg = executed(locate, order_ref, shares);
+        msg[0] = b'C';
+        msg.push(b'Y');
+        msg.extend_from_slice(&price.to_be_bytes());
+        msg
+    }
+
+    fn cancel(locate: u16, order_ref: u64, shares: u32) -> Vec<u8> {
+        let mut msg = header(b'X', locate, 34400000000000);
+        msg.extend_from_slice(&order_ref.to_be_bytes());
+        msg.extend_from_slice(&shares.to_be_bytes());
+        msg
+    }
+
+    fn delete(locate: u16, order_ref: u64) -> Vec<u8> {
+        let mut msg = header(b'D', locate, 34500000000000);
+        msg.extend_from_slice(&order_ref.to_be_bytes());
+        msg
+    }
+
+    fn replace(locate: u16, original: u64, new: u64, shares: u32, price: u32) -> Vec<u8> {
+        let mut msg = header(b'U', locate, 34600000000000);
+        msg.extend_from_slice(&original.to_be_bytes());
+        msg.extend_from_slice(&new.to_be_bytes());
+        msg.extend_from_slice(&shares.to_be_bytes());
+        msg.extend_from_slice(&price.to_be_bytes());
+        msg
+    }
+
+    fn framed(messages: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for msg in messages {
+            out.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+            out.extend_from_slice(msg);
+        }
+        out
+    }
+
+    fn price(text: &str) -> Amount {
+        Amount::new_from_str(text)
+    }
+
+    #[test]
+    fn fixtures_have_spec_lengths() {
+        assert_eq!(stock_directory(1, "AAPL").len(), 39);
+        assert_eq!(add_order(1, 1, b'B', 1, 1).len(), 36);
+        assert_eq!(add_order_mpid(1, 1, b'B', 1, 1).len(), 40);
+        assert_eq!(executed(1, 1, 1).len(), 31);
+        assert_eq!(executed_with_price(1, 1, 1, 1).len(), 36);
+        assert_eq!(cancel(1, 1, 1).len(), 23);
+        assert_eq!(delete(1, 1).len(), 19);
+        assert_eq!(replace(1, 1, 2, 1, 1).len(), 35);
+    }
+
+    #[test]
+    fn decode_each_type() {
+        assert_eq!(
+            decode(&stock_directory(7, "AAPL")),
+            Ok(ItchMessage::StockDirectory {
+                locate: 7,
+                timestamp: 1,
+                stock: "AAPL".to_string()
+            })
+        );
+        assert_eq!(
+            decode(&add_order_mpid(7, 42, b'S', 300, 1_502_500)),
+            Ok(ItchMessage::AddOrder {
+                locate: 7,
+                timestamp: 34200000000042,
+                order_ref: 42,
+                side: OrderSide::Ask,
+                shares: 300,
+                stock: "AAPL".to_string(),
+                price: 1_502_500,
+                attribution: Some("GSCO".to_string()),
+            })
+        );
+        assert_eq!(
+            decode(&executed_with_price(7, 42, 100, 1_502_400)),
+            Ok(ItchMessage::OrderExecuted {
+                locate: 7,
+                timestamp: 34300000000000,
+                order_ref: 42,
+                shares: 100,
+                match_number: 77,
+                price: Some(1_502_400),
+            })
+        );
+        assert_eq!(
+            decode(&replace(7, 42, 43, 50, 1_502_600)),
+            Ok(ItchMessage::OrderReplace {
+                locate: 7,
+                timestamp: 34600000000000,
+                original_ref: 42,
+                new_ref: 43,
+                shares: 50,
+                price: 1_502_600,
+            })
+        );
+        assert_eq!(decode(b"S\0\0"), Ok(ItchMessage::Other(b'S')));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode(&[]), Err(ItchError::Empty));
+        assert_eq!(
+            decode(&delete(1, 1)[..18]),
+            Err(ItchError::Truncated {
+                msg_type: b'D',
+                expected: 19,
+                found: 18
+            })
+        );
+        let mut bad_side = add_order(1, 1, b'B', 1, 1);
+        bad_side[19] = b'?';
+        assert_eq!(decode(&bad_side), Err(ItchError::BadSide(b'?')));
+    }
+
+    #[test]
+    fn drives_a_book_per_locate() {
+        let feed = framed(&[
+            stock_directory(1, "AAPL"),
+            stock_directory(2, "MSFT"),
+            add_order(1, 10, b'B', 300, 1_500_000),
+            add_order_mpid(1, 11, b'S', 200, 1_501_000),
+            add_order(2, 12, b'B', 100, 3_000_000),
+            executed(1, 10, 100),
+            executed_with_price(1, 11, 50, 1_500_900),
+            cancel(2, 12, 40),
+            replace(1, 11, 13, 400, 1_502_000),
+            delete(1, 10),
+        ]);
+        let mut handler = ItchHandler::new(100);
+        assert_eq!(handler.replay(&mut &feed[..]).unwrap(), 10);
+
+        assert_eq!(handler.stock(2), Some("MSFT"));
+        assert_eq!(handler.locate("AAPL"), Some(1));
+        let aapl = handler.book(1).unwrap();
+        assert_eq!(aapl.best_bid(), None);
+        assert_eq!(aapl.best_ask(), Some((price("150.20"), 400)));
+        let msft = handler.book(2).unwrap();
+        assert_eq!(msft.best_bid(), Some((price("300.00"), 60)));
+        assert_eq!(msft.best_ask(), None);
+
+        assert_eq!(handler.resting_shares(10), None);
+        assert_eq!(handler.resting_shares(11), None);
+        assert_eq!(handler.resting_shares(13), Some(400));
+        assert_eq!(handler.resting_shares(12), Some(60));
+        let mut locates = handler.locates();
+        locates.sort();
+        assert_eq!(locates, vec![1, 2]);
+    }
+
+    #[test]
+    fn unknown_orders_are_errors() {
+        let mut handler = ItchHandler::new(100);
+        let err = handler
+            .replay(&mut &framed(&[delete(1, 99)])[..])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "message 1: no order under reference 99");
+        assert_eq!(
+            handler.apply(&decode(&replace(1, 99, 100, 1, 1)).unwrap()),
+            Err(ItchError::UnknownOrder(99))
+        );
+    }
+
+    #[test]
+    fn executions_never_go_below_zero() {
+        let mut handler = ItchHandler::new(100);
+        for msg in &[add_order(1, 10, b'B', 100, 1_500_000), executed(1, 10, 150)] {
+            handler.apply(&decode(msg).unwrap()).unwrap();
+        }
+        assert_eq!(handler.book(1).unwrap().best_bid(), None);
+        assert_eq!(handler.resting_shares(10), None);
+    }
+
+    #[test]
+    fn orders_only_change_once_the_book_takes_them() {
+        let mut handler = ItchHandler::new(100);
+        // the next bid overflows the side's total
+        handler.book_mut(1).add(LimitOrder {
+            timestamp: 0,
+            id: 1,
+            side: OrderSide::Bid,
+            price: price("100.00"),
+            size: i64::MAX,
+        });
+        assert_eq!(
+            handler.apply(&decode(&add_order(1, 10, b'B', 100, 1_500_000)).unwrap()),
+            Err(ItchError::Book(BookError::Overflow))
+        );
+        assert_eq!(handler.resting_shares(10), None);
+
+        handler
+            .apply(&decode(&add_order(2, 20, b'S', 100, 1_500_000)).unwrap())
+            .unwrap();
+        // a book that never saw the order refuses the cancel, which leaves it resting here too
+        handler
+            .books
+            .insert(2, OrderBook::new(100, IdPriceCacheFnvMap::default()));
+        assert_eq!(
+            handler.apply(&decode(&cancel(2, 20, 40)).unwrap()),
+            Err(ItchError::Book(BookError::UnknownOrder("20".to_string())))
+        );
+        assert_eq!(handler.resting_shares(20), Some(100));
+    }
+
+    #[test]
+    fn truncated_file() {
+        let mut feed = framed(&[add_order(1, 10, b'B', 100, 1_500_000)]);
+        feed.pop();
+        let mut handler = ItchHandler::new(100);
+        assert!(handler.replay(&mut &feed[..]).is_err());
+        // a lone length byte is cut short too
+        let mut buf = Vec::new();
+        assert!(read_frame(&mut &[0u8][..], &mut buf).is_err());
+        assert!(!read_frame(&mut &[][..], &mut buf).unwrap());
+    }
+}
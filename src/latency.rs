@@ -0,0 +1,432 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use orders::{LevelUpdate, Message};
+
+// Where the time goes for each message: parsing it, applying it to the book,
+// working out the new amounts and writing them out. Each stage of each kind of
+// message gets its own histogram, kept HDR style: exact below 128ns, and past
+// that 64 buckets per power of two, so any value reported is within 1.6% of
+// the one recorded. Percentiles come out as the top of their bucket, like
+// HdrHistogram's highest equivalent value.
+
+const SUB_BUCKET_BITS: u32 = 7;
+const HALF_BUCKET: usize = 1 << (SUB_BUCKET_BITS - 1);
+// up to 2^40ns, a good 18 minutes, anything longer is counted as that
+const MAX_VALUE: u64 = (1 << 40) - 1;
+const BUCKETS: usize = (40 - SUB_BUCKET_BITS as usize + 2) * HALF_BUCKET;
+
+/// Counts of values in log-linear buckets
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+}
+
+// shift of the bucket the value falls in, 0 for the exact ones
+fn shift_of(value: u64) -> u32 {
+    let msb = 63 - (value | 1).leading_zeros();
+    msb.saturating_sub(SUB_BUCKET_BITS - 1)
+}
+
+fn index_of(value: u64) -> usize {
+    let shift = shift_of(value);
+    shift as usize * HALF_BUCKET + (value >> shift) as usize
+}
+
+// largest value that lands in the bucket at idx
+fn highest_in(idx: usize) -> u64 {
+    let shift = if idx < 2 * HALF_BUCKET {
+        0
+    } else {
+        idx / HALF_BUCKET - 1
+    };
+    let lowest = ((idx - shift * HALF_BUCKET) as u64) << shift;
+    lowest + (1 << shift) - 1
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let value = value.min(MAX_VALUE);
+        self.counts[index_of(value)] += 1;
+        self.total += 1;
+        self.max = self.max.max(value);
+    }
+
+    /// How many values were recorded
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Largest value recorded, exactly
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Value at or below which quantile (0 to 1) of the recorded values fall, 0 if there are none
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let wanted = ((quantile * self.total as f64).ceil() as u64).clamp(1, self.total);
+        let mut seen = 0;
+        for (idx, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return highest_in(idx).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Cheap timestamps. The time stamp counter where there is one, taken to be invariant
+/// like on any x86_64 from the last decade, calibrated against Instant once
+#[derive(Copy, Clone, Debug)]
+pub struct Clock {
+    nanos_per_tick: f64,
+    #[cfg(not(target_arch = "x86_64"))]
+    base: Instant,
+}
+
+impl Clock {
+    #[cfg(target_arch = "x86_64")]
+    pub fn new() -> Self {
+        let (start, ticks) = (Instant::now(), read_tsc());
+        while start.elapsed() < Duration::from_millis(10) {}
+        let (elapsed, ticks) = (start.elapsed(), read_tsc() - ticks);
+        Clock {
+            nanos_per_tick: elapsed.as_nanos() as f64 / ticks.max(1) as f64,
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn new() -> Self {
+        Clock {
+            nanos_per_tick: 1.0,
+            base: Instant::now(),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn now(&self) -> u64 {
+        read_tsc()
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn now(&self) -> u64 {
+        self.base.elapsed().as_nanos() as u64
+    }
+
+    /// Nanoseconds between two readings of now()
+    pub fn nanos(&self, from: u64, to: u64) -> u64 {
+        (to.saturating_sub(from) as f64 * self.nanos_per_tick) as u64
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_tsc() -> u64 {
+    #[allow(unused_unsafe)]
+    unsafe {
+        ::std::arch::x86_64::_rdtsc()
+    }
+}
+
+/// What the message was, for the histograms
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MessageKind {
+    Add,
+    Reduce,
+    Level,
+    /// didn't parse
+    Bad,
+}
+
+impl MessageKind {
+    const ALL: [MessageKind; 4] = [
+        MessageKind::Add,
+        MessageKind::Reduce,
+        MessageKind::Level,
+        MessageKind::Bad,
+    ];
+
+    pub fn of<E>(parsed: &Result<(Message, Option<u64>), E>) -> Self {
+        match *parsed {
+            Ok((Message::Add { .. }, _)) => MessageKind::Add,
+            Ok((Message::Reduce { .. }, _)) => MessageKind::Reduce,
+            Err(_) => MessageKind::Bad,
+        }
+    }
+
+    pub fn of_level<E>(parsed: &Result<(LevelUpdate, Option<u64>), E>) -> Self {
+        match *parsed {
+            Ok(_) => MessageKind::Level,
+            Err(_) => MessageKind::Bad,
+        }
+    }
+}
+
+impl Display for MessageKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match *self {
+            MessageKind::Add => "add",
+            MessageKind::Reduce => "reduce",
+            MessageKind::Level => "level",
+            MessageKind::Bad => "bad",
+        };
+        f.pad(name)
+    }
+}
+
+const STAGES: [&str; 5] = ["parse", "apply", "summarise", "output", "total"];
+
+/// Clock readings taken as one message went through, see Latency::record
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MessageTimes {
+    pub start: u64,
+    pub parsed: u64,
+    pub applied: u64,
+    pub summarised: u64,
+    pub written: u64,
+}
+
+/// Histograms for every stage of every kind of message
+pub struct Latency {
+    clock: Clock,
+    histograms: Vec<[Histogram; 5]>, // by MessageKind, then STAGES
+}
+
+impl Latency {
+    pub fn new(clock: Clock) -> Self {
+        Latency {
+            clock,
+            histograms: MessageKind::ALL
+                .iter()
+                .map(|_| Default::default())
+                .collect(),
+        }
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    pub fn record(&mut self, kind: MessageKind, times: &MessageTimes) {
+        let clock = self.clock;
+        let idx = MessageKind::ALL.iter().position(|&k| k == kind).unwrap();
+        let stages = &mut self.histograms[idx];
+        let marks = [
+            times.start,
+            times.parsed,
+            times.applied,
+            times.summarised,
+            times.written,
+        ];
+        for (stage, pair) in stages.iter_mut().zip(marks.windows(2)) {
+            stage.record(clock.nanos(pair[0], pair[1]));
+        }
+        stages[4].record(clock.nanos(times.start, times.written));
+    }
+
+    pub fn histogram(&self, kind: MessageKind, stage: &str) -> Option<&Histogram> {
+        let idx = MessageKind::ALL.iter().position(|&k| k == kind)?;
+        let stage = STAGES.iter().position(|&s| s == stage)?;
+        Some(&self.histograms[idx][stage])
+    }
+
+    /// A table of p50, p99, p99.9 and max in nanoseconds, a row for every stage
+    /// of every kind of message seen
+    pub fn write_summary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:<8} {:<10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "message", "stage", "count", "p50 ns", "p99 ns", "p99.9 ns", "max ns"
+        )?;
+        for (kind, stages) in MessageKind::ALL.iter().zip(self.histograms.iter()) {
+            if stages[4].count() == 0 {
+                continue;
+            }
+            for (stage, histogram) in STAGES.iter().zip(stages.iter()) {
+                writeln!(
+                    out,
+                    "{:<8} {:<10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                    kind,
+                    stage,
+                    histogram.count(),
+                    histogram.quantile(0.5),
+                    histogram.quantile(0.99),
+                    histogram.quantile(0.999),
+                    histogram.max()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+static SUMMARY_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// SIGUSR1 on the targets it was looked up for, it isn't the same number everywhere
+#[cfg(unix)]
+fn sigusr1() -> Option<std::os::raw::c_int> {
+    let linux = cfg!(any(target_os = "linux", target_os = "android"));
+    if linux && cfg!(any(target_arch = "mips", target_arch = "mips64")) {
+        Some(16)
+    } else if linux && cfg!(any(target_arch = "sparc", target_arch = "sparc64")) {
+        Some(30)
+    } else if linux
+        && cfg!(any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "powerpc",
+            target_arch = "powerpc64",
+            target_arch = "s390x",
+            target_arch = "loongarch64"
+        ))
+    {
+        Some(10)
+    } else if cfg!(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    )) {
+        Some(30)
+    } else if cfg!(any(target_os = "solaris", target_os = "illumos")) {
+        Some(16)
+    } else {
+        None
+    }
+}
+
+/// Makes SIGUSR1 ask for a summary, see summary_requested.
+/// False if that couldn't be set up, on a target whose SIGUSR1 isn't known here
+#[cfg(unix)]
+pub fn summary_on_signal() -> bool {
+    use std::os::raw::c_int;
+
+    // a handler's address, or SIG_DFL, SIG_IGN and SIG_ERR, as size_t like libc has it
+    #[allow(non_camel_case_types)]
+    type sighandler_t = usize;
+    const SIG_ERR: sighandler_t = !0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: sighandler_t) -> sighandler_t;
+    }
+
+    // only touches an atomic, which is all a signal handler should do
+    extern "C" fn on_signal(_: c_int) {
+        SUMMARY_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    let signum = match sigusr1() {
+        Some(signum) => signum,
+        None => return false,
+    };
+    let handler = on_signal as extern "C" fn(c_int) as sighandler_t;
+    unsafe { signal(signum, handler) != SIG_ERR }
+}
+
+/// Whether a summary was asked for since the last call
+pub fn summary_requested() -> bool {
+    SUMMARY_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_line_up() {
+        for &value in &[0, 1, 127, 128, 129, 255, 256, 1000, 123456, MAX_VALUE] {
+            let idx = index_of(value);
+            assert!(idx < BUCKETS);
+            let top = highest_in(idx);
+            assert!(top >= value, "{} in a bucket topping out at {}", value, top);
+            // within 1/64 of the value, exact below 128
+            assert!(top - value <= value / 64, "{} reported as {}", value, top);
+            assert_eq!(index_of(top), idx);
+            if idx > 0 {
+                assert_eq!(
+                    highest_in(idx - 1) + 1,
+                    value - value % (1 << shift_of(value))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), 0);
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.max(), 1000);
+        assert_eq!(histogram.quantile(0.5), 503); // 500 is in the 500..503 bucket
+        assert_eq!(histogram.quantile(0.999), 999);
+        assert_eq!(histogram.quantile(1.0), 1000);
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.max(), MAX_VALUE);
+    }
+
+    #[test]
+    fn stages_and_summary() {
+        let mut latency = Latency::new(Clock::new());
+        let clock = latency.clock();
+        let start = clock.now();
+        while clock.nanos(start, clock.now()) < 2000 {}
+        let end = clock.now();
+        let times = MessageTimes {
+            start,
+            parsed: start,
+            applied: end,
+            summarised: end,
+            written: end,
+        };
+        latency.record(MessageKind::Add, &times);
+        let apply = latency.histogram(MessageKind::Add, "apply").unwrap();
+        assert_eq!(apply.count(), 1);
+        assert!(apply.max() >= 2000);
+        assert_eq!(
+            latency.histogram(MessageKind::Add, "parse").unwrap().max(),
+            0
+        );
+        assert_eq!(
+            latency.histogram(MessageKind::Add, "total").unwrap().max(),
+            apply.max()
+        );
+
+        let mut out = Vec::new();
+        latency.write_summary(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // a header and a row per stage of the adds, nothing for the rest
+        assert_eq!(out.lines().count(), 6);
+        assert!(out.lines().nth(2).unwrap().starts_with("add      apply"));
+    }
+}
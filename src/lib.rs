@@ -0,0 +1,31 @@
+extern crate fnv;
+#[cfg(test)]
+extern crate proptest;
+
+pub mod amount;
+pub mod bidamount;
+pub mod binfeed;
+pub mod fanout;
+pub mod fix;
+pub mod generate;
+pub mod idpricecache;
+pub mod itch;
+pub mod latency;
+pub mod metrics;
+pub mod multicast;
+pub mod orderbook;
+pub mod orderids;
+pub mod orders;
+pub mod orderside;
+pub mod output;
+pub mod pipeline;
+pub mod pricer;
+#[cfg(unix)]
+pub mod query;
+pub mod reference;
+pub mod sequence;
+pub mod shard;
+pub mod spsc;
+pub mod stats;
+pub mod summary;
+pub mod validate;
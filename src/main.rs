@@ -1,95 +1,946 @@
-extern crate fnv;
+extern crate order_book;
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
 use std::env;
+use std::fmt::Display;
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::TcpListener;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-mod amount;
-use amount::Amount;
+use order_book::binfeed::{read_message, write_message};
+use order_book::fanout::{accept_subscribers, FanOut};
+use order_book::generate::FeedGenerator;
+use order_book::idpricecache::{
+    IdPriceCache, IdPriceCacheFnvMap, IdPriceCacheIdentityMap, IdPriceCacheRobinHood,
+    IdPriceCacheSlab, IdPriceCacheStdMap,
+};
+use order_book::latency::{self, Clock, Latency, MessageKind, MessageTimes};
+use order_book::metrics::{Metrics, MetricsServer};
+use order_book::multicast::{parse_text, MulticastReceiver};
+use order_book::orderbook::{BookError, OrderBook};
+use order_book::orders::{LevelUpdate, Message, ParseError};
+use order_book::orderside::OrderSide;
+use order_book::output::{write_event, write_header, OutputFormat};
+use order_book::pipeline::{price_pipelined, read_apart, OwnedMessage};
+use order_book::pricer::{Pricer, PricerEvent};
+use order_book::query::QueryServer;
+use order_book::sequence::{SeqIssue, SeqPolicy, SequenceTracker};
+use order_book::shard::{price_sharded, SymbolPriced};
+use order_book::stats::StatsCollector;
+use order_book::validate::{Issue, Validator};
 
-mod bidamount;
+mod cli;
+use cli::{
+    CacheKind, Command, CrossedPolicy, ErrorPolicy, InputFormat, Options, Subcommand, USAGE,
+};
 
-mod orderside;
-use orderside::OrderSide;
+// exit codes
+const EXIT_FAILURE: i32 = 1; // bad input, i/o error, or validate found issues
+const EXIT_USAGE: i32 = 2; // bad command line
 
-mod orders;
+// messages queued on the way to and from each --shards worker
+const SHARD_QUEUE: usize = 4096;
+// messages read ahead of the book when the feed is read apart for --query-socket
+const READ_AHEAD: usize = 4096;
 
-mod orderbook;
-use orderbook::{IdPriceCacheFnvMap, OrderBook};
+fn open_input(path: &Option<String>) -> Result<Box<dyn BufRead>, String> {
+    match *path {
+        None => Ok(Box::new(io::stdin().lock())),
+        Some(ref path) => File::open(path)
+            .map(|file| Box::new(BufReader::new(file)) as Box<dyn BufRead>)
+            .map_err(|err| format!("cannot open {}: {}", path, err)),
+    }
+}
+
+/// --input, or the first connection to --feed-listen
+fn open_feed(opts: &Options) -> Result<Box<dyn BufRead>, String> {
+    let addr = match opts.feed_listen {
+        Some(ref addr) => addr,
+        None => return open_input(&opts.input),
+    };
+    let listener =
+        TcpListener::bind(addr).map_err(|err| format!("cannot listen on {}: {}", addr, err))?;
+    let (stream, _) = listener
+        .accept()
+        .map_err(|err| format!("cannot take feed connection: {}", err))?;
+    Ok(Box::new(BufReader::new(stream)))
+}
+
+fn open_output(path: &Option<String>) -> Result<BufWriter<Box<dyn Write>>, String> {
+    let out: Box<dyn Write> = match *path {
+        None => Box::new(io::stdout().lock()),
+        Some(ref path) => {
+            Box::new(File::create(path).map_err(|err| format!("cannot create {}: {}", path, err))?)
+        }
+    };
+    Ok(BufWriter::new(out))
+}
 
-/// Returns the target size for the order book.
-/// Takes env args and parses them into a i64
-/// Panics when no target size is provided or parsing fails
-fn get_target_size() -> i64 {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Need target size input");
+fn write_error(err: io::Error) -> String {
+    format!("cannot write output: {}", err)
+}
+
+/// Where a message sits in the feed, for error messages
+fn position(format: InputFormat, idx: usize) -> String {
+    match format {
+        InputFormat::Text | InputFormat::Mbp => format!("line {}", idx + 1),
+        InputFormat::Binary => format!("record {}", idx + 1),
     }
-    match args[1].parse::<i64>() {
-        Ok(res) => res,
-        Err(_e) => panic!("Couldn't parse input into i64"),
+}
+
+/// What events caused by the message at idx carry, see PricerEvent
+fn event_sequence(idx: usize, seq: Option<u64>) -> u64 {
+    seq.unwrap_or(idx as u64 + 1)
+}
+
+/// Applies --on-error to a message that couldn't be used
+fn bad_message<E: Display>(opts: &Options, idx: usize, err: E) -> Result<(), String> {
+    let pos = position(opts.input_format, idx);
+    match opts.on_error {
+        ErrorPolicy::Halt => Err(format!("{}: {}", pos, err)),
+        ErrorPolicy::Warn => {
+            eprintln!("order_book: {}: {}", pos, err);
+            Ok(())
+        }
+        ErrorPolicy::Skip => Ok(()),
     }
 }
 
-fn prepare_reports() -> HashMap<OrderSide, Option<Amount>> {
-    let mut hm = HashMap::with_capacity(2);
-    hm.insert(OrderSide::Ask, None);
-    hm.insert(OrderSide::Bid, None);
+/// Sets the book's sequence tracker up as --on-gap asks
+fn track_sequence<T: IdPriceCache>(opts: &Options, book: &mut OrderBook<T>) {
+    let tracker = book.sequence_mut();
+    tracker.set_policy(opts.on_gap);
+    if opts.on_gap == SeqPolicy::RequestSnapshot {
+        // nowhere to get one from yet, so say so
+        tracker
+            .on_snapshot_request(|issue| eprintln!("order_book: snapshot needed after {}", issue));
+    }
+}
 
-    hm
+/// Handles what the sequence tracker made of the last message.
+/// Refused messages stop the run whatever --on-error says
+fn sequence_issue(opts: &Options, idx: usize, issue: Option<SeqIssue>) -> Result<(), String> {
+    match issue {
+        Some(issue) if opts.on_gap == SeqPolicy::Halt => {
+            Err(format!("{}: {}", position(opts.input_format, idx), issue))
+        }
+        Some(issue) => {
+            eprintln!(
+                "order_book: {}: {}",
+                position(opts.input_format, idx),
+                issue
+            );
+            Ok(())
+        }
+        None => Ok(()),
+    }
 }
 
-fn main() {
-    let target_size = get_target_size();
-    let cache_capacity = 50000;
-    let mut ob = OrderBook::new(
-        target_size,
-        IdPriceCacheFnvMap::with_capacity_and_hasher(
-            cache_capacity,
-            std::hash::BuildHasherDefault::<fnv::FnvHasher>::default(),
-        ),
-    );
-    let mut reports = prepare_reports();
-    let stdout = io::stdout();
-    let stdin = io::stdin();
-    for order_line in stdin.lock().lines() {
-        let unwrapped_line: &str = &order_line.unwrap();
-        ob.process(unwrapped_line);
-
-        let cur = ob.summarise_target();
-        let mut prev = reports.get_mut(&!ob.last_action_side).unwrap();
-        if cur.is_some() && prev.is_some() {
-            if cur.unwrap() != prev.unwrap() {
-                writeln!(
-                    stdout.lock(),
-                    "{} {} {}",
-                    ob.last_action_timestamp,
-                    !ob.last_action_side,
-                    cur.unwrap()
-                )
-                .expect("cannot lock");
-            } else {
+/// Calls on_message with the index and parsed form of every message in the feed,
+/// with its sequence number if it has one, until the input runs out or on_message returns false
+fn for_each_message<F>(opts: &Options, mut on_message: F) -> Result<(), String>
+where
+    F: FnMut(usize, Result<(Message, Option<u64>), ParseError>) -> Result<bool, String>,
+{
+    for_each_message_timed(opts, None, |idx, parsed, _| on_message(idx, parsed))
+}
+
+/// Clock readings from just before and just after parsing, 0 without a clock
+type ParseTimes = (u64, u64);
+
+fn stamp(clock: Option<Clock>) -> u64 {
+    clock.map_or(0, |clock| clock.now())
+}
+
+/// for_each_message, also handing on_message the ParseTimes of each message
+fn for_each_message_timed<F>(
+    opts: &Options,
+    clock: Option<Clock>,
+    mut on_message: F,
+) -> Result<(), String>
+where
+    F: FnMut(usize, Result<(Message, Option<u64>), ParseError>, ParseTimes) -> Result<bool, String>,
+{
+    if opts.multicast.is_some() {
+        return for_each_packet_message(opts, clock, on_message);
+    }
+    let mut input = open_feed(opts)?;
+    match opts.input_format {
+        InputFormat::Text => {
+            for (idx, line) in input.lines().enumerate() {
+                let line = line.map_err(|err| format!("cannot read input: {}", err))?;
+                let start = stamp(clock);
+                let parsed = Message::parse_with_seq(&line);
+                if !on_message(idx, parsed, (start, stamp(clock)))? {
+                    break;
+                }
+            }
+        }
+        InputFormat::Binary => {
+            let mut buf = Vec::new();
+            for idx in 0.. {
+                // buffered, so this is mostly decoding
+                let start = stamp(clock);
+                let message = read_message(&mut input, &mut buf).map_err(|err| {
+                    format!("cannot read {}: {}", position(opts.input_format, idx), err)
+                })?;
+                match message {
+                    Some(message) => {
+                        if !on_message(idx, Ok((message, None)), (start, stamp(clock)))? {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+        InputFormat::Mbp => unreachable!("mbp feeds go through for_each_level"),
+    }
+    Ok(())
+}
+
+/// for_each_message for --multicast, every message carries the sequence number
+/// its packet gives it
+fn for_each_packet_message<F>(
+    opts: &Options,
+    clock: Option<Clock>,
+    mut on_message: F,
+) -> Result<(), String>
+where
+    F: FnMut(usize, Result<(Message, Option<u64>), ParseError>, ParseTimes) -> Result<bool, String>,
+{
+    let group = opts.multicast.expect("only called for --multicast");
+    let mut receiver = MulticastReceiver::join(*group.ip(), group.port(), opts.multicast_if)
+        .map_err(|err| format!("cannot join {}: {}", group, err))?;
+    let mut idx = 0;
+    let mut buf = Vec::new();
+    loop {
+        let packet = match receiver.recv() {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return trailing_gap(opts, idx, receiver.trailing_gap()),
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("order_book: bad packet: {}", err);
                 continue;
             }
-        } else if cur.is_some() && prev.is_none() {
-            writeln!(
-                stdout.lock(),
-                "{} {} {}",
-                ob.last_action_timestamp,
-                !ob.last_action_side,
-                cur.unwrap()
-            )
-            .expect("cannot lock");
-        } else if cur.is_none() && prev.is_some() {
-            writeln!(
-                stdout.lock(),
-                "{} {} NA",
-                ob.last_action_timestamp,
-                !ob.last_action_side
-            )
-            .expect("cannot lock");
-        }
-        *prev = cur;
+            Err(err) => return Err(format!("cannot read from {}: {}", group, err)),
+        };
+        if packet.is_heartbeat() {
+            trailing_gap(opts, idx, receiver.trailing_gap())?;
+            continue;
+        }
+        for (n, bytes) in packet.messages().iter().enumerate() {
+            // Packet::parse checked the last one fits
+            let seq = Some(packet.sequence + n as u64);
+            let start = stamp(clock);
+            let parsed = match opts.input_format {
+                InputFormat::Binary => match read_message(&mut &bytes[..], &mut buf) {
+                    Ok(Some(message)) => Ok(message),
+                    Ok(None) => {
+                        return Err(format!("{} is empty", position(opts.input_format, idx)))
+                    }
+                    Err(err) => {
+                        let pos = position(opts.input_format, idx);
+                        return Err(format!("cannot read {}: {}", pos, err));
+                    }
+                },
+                InputFormat::Text | InputFormat::Mbp => parse_text(bytes),
+            };
+            let parsed_at = stamp(clock);
+            if !on_message(
+                idx,
+                parsed.map(|message| (message, seq)),
+                (start, parsed_at),
+            )? {
+                return Ok(());
+            }
+            idx += 1;
+        }
+    }
+}
+
+// gaps only a heartbeat or the end of session shows, the book never hears of them
+fn trailing_gap(opts: &Options, idx: usize, gap: Option<SeqIssue>) -> Result<(), String> {
+    if let Some(ref issue) = gap {
+        if opts.on_gap == SeqPolicy::RequestSnapshot {
+            eprintln!("order_book: snapshot needed after {}", issue);
+        }
+    }
+    sequence_issue(opts, idx, gap)
+}
+
+/// for_each_message for a market by price feed
+fn for_each_level<F>(opts: &Options, mut on_update: F) -> Result<(), String>
+where
+    F: FnMut(usize, Result<(LevelUpdate, Option<u64>), ParseError>) -> Result<bool, String>,
+{
+    for_each_level_timed(opts, None, |idx, parsed, _| on_update(idx, parsed))
+}
+
+fn for_each_level_timed<F>(
+    opts: &Options,
+    clock: Option<Clock>,
+    mut on_update: F,
+) -> Result<(), String>
+where
+    F: FnMut(
+        usize,
+        Result<(LevelUpdate, Option<u64>), ParseError>,
+        ParseTimes,
+    ) -> Result<bool, String>,
+{
+    let input = open_feed(opts)?;
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.map_err(|err| format!("cannot read input: {}", err))?;
+        let start = stamp(clock);
+        let parsed = LevelUpdate::parse_with_seq(&line);
+        if !on_update(idx, parsed, (start, stamp(clock)))? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A message or level update as the for_each_*_or_idle functions hand it on
+type Timed<M> = (usize, Result<(M, Option<u64>), ParseError>, ParseTimes);
+
+/// for_each_message_timed, but with idle set the feed is read on a thread of its own
+/// and on_message(None) stands for every IDLE_WAIT it stays quiet
+fn for_each_message_or_idle<F>(
+    opts: &Options,
+    clock: Option<Clock>,
+    idle: bool,
+    mut on_message: F,
+) -> Result<(), String>
+where
+    F: FnMut(Option<Timed<Message>>) -> Result<bool, String>,
+{
+    if !idle {
+        return for_each_message_timed(opts, clock, |idx, parsed, parse_times| {
+            on_message(Some((idx, parsed, parse_times)))
+        });
+    }
+    let reader_opts = opts.clone();
+    read_apart(
+        READ_AHEAD,
+        move |send| {
+            for_each_message_timed(&reader_opts, clock, |idx, parsed, parse_times| {
+                let parsed = parsed.map(|(message, seq)| (OwnedMessage::from(message), seq));
+                Ok(send((idx, parsed, parse_times)))
+            })
+        },
+        |read: Option<Timed<OwnedMessage>>| match read {
+            Some((idx, Ok((message, seq)), parse_times)) => {
+                on_message(Some((idx, Ok((message.as_message(), seq)), parse_times)))
+            }
+            Some((idx, Err(err), parse_times)) => on_message(Some((idx, Err(err), parse_times))),
+            None => on_message(None),
+        },
+    )
+}
+
+/// for_each_level_timed, the way for_each_message_or_idle is for_each_message_timed
+fn for_each_level_or_idle<F>(
+    opts: &Options,
+    clock: Option<Clock>,
+    idle: bool,
+    mut on_update: F,
+) -> Result<(), String>
+where
+    F: FnMut(Option<Timed<LevelUpdate>>) -> Result<bool, String>,
+{
+    if !idle {
+        return for_each_level_timed(opts, clock, |idx, parsed, parse_times| {
+            on_update(Some((idx, parsed, parse_times)))
+        });
+    }
+    let reader_opts = opts.clone();
+    read_apart(
+        READ_AHEAD,
+        move |send| {
+            for_each_level_timed(&reader_opts, clock, |idx, parsed, parse_times| {
+                Ok(send((idx, parsed, parse_times)))
+            })
+        },
+        on_update,
+    )
+}
+
+/// Histograms for --latency
+fn latency(opts: &Options) -> Option<Latency> {
+    if !opts.latency {
+        return None;
+    }
+    Some(Latency::new(Clock::new()))
+}
+
+/// Records the message's times
+fn record_latency(
+    latency: &mut Option<Latency>,
+    kind: MessageKind,
+    (start, parsed): ParseTimes,
+    applied: u64,
+    summarised: u64,
+) {
+    if let Some(ref mut latency) = *latency {
+        let written = latency.clock().now();
+        let times = MessageTimes {
+            start,
+            parsed,
+            applied,
+            summarised,
+            written,
+        };
+        latency.record(kind, &times);
+    }
+}
+
+fn latency_summary(latency: &Latency) {
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    // nowhere left to report a failure to write to stderr
+    let _ = latency.write_summary(&mut stderr);
+}
+
+/// Counters for --metrics-listen and --metrics-file
+fn metrics(opts: &Options) -> Option<Metrics> {
+    if opts.metrics_listen.is_some() || opts.metrics_file.is_some() {
+        Some(Metrics::new())
+    } else {
+        None
+    }
+}
+
+fn metrics_server(opts: &Options) -> Result<Option<MetricsServer>, String> {
+    match opts.metrics_listen {
+        Some(ref addr) => MetricsServer::bind(addr.as_str())
+            .map(Some)
+            .map_err(|err| format!("cannot listen on {}: {}", addr, err)),
+        None => Ok(None),
+    }
+}
+
+/// Hands --metrics-listen a fresh text if a scrape is waiting on one
+fn publish_metrics<T: IdPriceCache>(
+    server: &Option<MetricsServer>,
+    metrics: &Option<Metrics>,
+    book: &OrderBook<T>,
+) {
+    if let (Some(server), Some(metrics)) = (server.as_ref(), metrics.as_ref()) {
+        server.poll(metrics, book);
+    }
+}
+
+/// Makes SIGUSR1 ask for the summaries, when there are any to ask for
+fn summaries_on_signal(opts: &Options) {
+    #[cfg(unix)]
+    {
+        if (opts.latency || opts.metrics_file.is_some()) && !latency::summary_on_signal() {
+            eprintln!("order_book: SIGUSR1 isn't known here, summaries only come at exit");
+        }
+    }
+}
+
+/// The latency summary so far to stderr and the metrics so far to --metrics-file
+fn write_summaries<T: IdPriceCache>(
+    opts: &Options,
+    latency: &Option<Latency>,
+    metrics: &Option<Metrics>,
+    book: &OrderBook<T>,
+) -> Result<(), String> {
+    if let Some(ref latency) = *latency {
+        latency_summary(latency);
+    }
+    if let (Some(metrics), Some(path)) = (metrics.as_ref(), opts.metrics_file.as_ref()) {
+        metrics
+            .write_file(book, path)
+            .map_err(|err| format!("cannot write {}: {}", path, err))?;
+    }
+    Ok(())
+}
+
+/// write_summaries, if SIGUSR1 asked for them since the last message
+fn summaries_requested<T: IdPriceCache>(
+    opts: &Options,
+    latency: &Option<Latency>,
+    metrics: &Option<Metrics>,
+    book: &OrderBook<T>,
+) -> Result<(), String> {
+    let asked = latency.is_some() || opts.metrics_file.is_some();
+    if asked && latency::summary_requested() {
+        write_summaries(opts, latency, metrics, book)?;
+    }
+    Ok(())
+}
+
+/// Starts taking subscribers for --serve, events go to them as they are written out
+fn serve(opts: &Options) -> Result<Option<Arc<Mutex<FanOut>>>, String> {
+    let addr = match opts.serve {
+        Some(ref addr) => addr,
+        None => return Ok(None),
+    };
+    let listener =
+        TcpListener::bind(addr).map_err(|err| format!("cannot listen on {}: {}", addr, err))?;
+    let mut header = Vec::new();
+    write_header(&mut header, opts.output_format).map_err(write_error)?;
+    let fanout = Arc::new(Mutex::new(FanOut::new(opts.subscriber_buffer, header)));
+    accept_subscribers(listener, fanout.clone());
+    while fanout.lock().unwrap().subscribers() < opts.wait_subscribers {
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(Some(fanout))
+}
+
+fn query_server(opts: &Options) -> Result<Option<QueryServer>, String> {
+    match opts.query_socket {
+        Some(ref path) => QueryServer::bind(path)
+            .map(Some)
+            .map_err(|err| format!("cannot listen on {}: {}", path, err)),
+        None => Ok(None),
+    }
+}
+
+/// Writes out what the pricer made of a message, or what was wrong with it
+fn write_events<W: Write>(
+    opts: &Options,
+    output: &mut W,
+    fanout: &Option<Arc<Mutex<FanOut>>>,
+    idx: usize,
+    priced: Result<&[PricerEvent], BookError>,
+) -> Result<(), String> {
+    match priced {
+        Ok(events) => {
+            for event in events {
+                write_event(output, opts.output_format, event, opts.target_sizes.len())
+                    .map_err(write_error)?;
+            }
+            if let (Some(fanout), false) = (fanout.as_ref(), events.is_empty()) {
+                let mut lines = Vec::new();
+                for event in events {
+                    write_event(
+                        &mut lines,
+                        opts.output_format,
+                        event,
+                        opts.target_sizes.len(),
+                    )
+                    .map_err(write_error)?;
+                }
+                fanout.lock().unwrap().publish(&lines);
+            }
+            Ok(())
+        }
+        // already reported through sequence_issue
+        Err(BookError::Sequence(_)) => Ok(()),
+        Err(err) => bad_message(opts, idx, err),
+    }
+}
+
+fn run<T: IdPriceCache + Send>(opts: &Options, cache: T) -> Result<(), String> {
+    let book = OrderBook::with_capacity(
+        opts.target_sizes[0],
+        cache,
+        opts.id_mode,
+        opts.level_capacity,
+    );
+    let mut pricer = Pricer::with_targets(book, &opts.target_sizes);
+    pricer.suppress_while_crossed(opts.when_crossed == CrossedPolicy::Suppress);
+    track_sequence(opts, pricer.book_mut());
+    let mut output = open_output(&opts.output)?;
+    write_header(&mut output, opts.output_format).map_err(write_error)?;
+    let fanout = serve(opts)?;
+    let queries = query_server(opts)?;
+    let mut latency = latency(opts);
+    let clock = latency.as_ref().map(Latency::clock);
+    let mut metrics = metrics(opts);
+    let metrics_server = metrics_server(opts)?;
+    if let (Some(server), Some(metrics)) = (metrics_server.as_ref(), metrics.as_ref()) {
+        server.publish(metrics.render(pricer.book()));
+    }
+    summaries_on_signal(opts);
+    // queries are answered between messages, never while one is being applied,
+    // and while the feed is quiet
+    let idle = queries.is_some();
+    let answer = move |book: &OrderBook<T>| {
+        if let Some(ref queries) = queries {
+            queries.poll(book);
+        }
+    };
+    let pricer = if let Some(capacity) = opts.pipeline {
+        price_pipelined(
+            pricer,
+            capacity,
+            |feeder| for_each_message(opts, |idx, parsed| Ok(feeder.feed(idx, parsed))),
+            move |pricer: &Pricer<T>| answer(pricer.book()),
+            |priced| {
+                let events = match priced.events {
+                    Ok(ref events) => Ok(&events[..]),
+                    Err(err) => Err(err),
+                };
+                write_events(opts, &mut output, &fanout, priced.idx, events)?;
+                sequence_issue(opts, priced.idx, priced.issue)
+            },
+        )?
+    } else if opts.input_format == InputFormat::Mbp {
+        // applying and pricing are done apart so --latency can time them
+        for_each_level_or_idle(opts, clock, idle, |update| {
+            let (idx, parsed, parse_times) = match update {
+                Some(update) => update,
+                None => {
+                    answer(pricer.book());
+                    return Ok(true);
+                }
+            };
+            let kind = MessageKind::of_level(&parsed);
+            let applied = parsed.map_err(BookError::from).and_then(|(update, seq)| {
+                let book = pricer.book_mut();
+                book.apply_level_sequenced(&update, seq)
+                    .map(|()| event_sequence(idx, seq))
+            });
+            let applied_at = stamp(clock);
+            let priced = applied.map(|sequence| pricer.reprice(sequence));
+            let summarised_at = stamp(clock);
+            if let Some(ref mut metrics) = metrics {
+                metrics.record(kind, &priced);
+            }
+            write_events(opts, &mut output, &fanout, idx, priced)?;
+            record_latency(&mut latency, kind, parse_times, applied_at, summarised_at);
+            sequence_issue(opts, idx, pricer.book().sequence().last_issue())?;
+            answer(pricer.book());
+            publish_metrics(&metrics_server, &metrics, pricer.book());
+            summaries_requested(opts, &latency, &metrics, pricer.book())?;
+            Ok(true)
+        })?;
+        pricer
+    } else {
+        for_each_message_or_idle(opts, clock, idle, |message| {
+            let (idx, parsed, parse_times) = match message {
+                Some(message) => message,
+                None => {
+                    answer(pricer.book());
+                    return Ok(true);
+                }
+            };
+            let kind = MessageKind::of(&parsed);
+            let applied = parsed.map_err(BookError::from).and_then(|(message, seq)| {
+                let book = pricer.book_mut();
+                book.apply_sequenced(&message, seq)
+                    .map(|()| event_sequence(idx, seq))
+            });
+            let applied_at = stamp(clock);
+            let priced = applied.map(|sequence| pricer.reprice(sequence));
+            let summarised_at = stamp(clock);
+            if let Some(ref mut metrics) = metrics {
+                metrics.record(kind, &priced);
+            }
+            write_events(opts, &mut output, &fanout, idx, priced)?;
+            record_latency(&mut latency, kind, parse_times, applied_at, summarised_at);
+            sequence_issue(opts, idx, pricer.book().sequence().last_issue())?;
+            answer(pricer.book());
+            publish_metrics(&metrics_server, &metrics, pricer.book());
+            summaries_requested(opts, &latency, &metrics, pricer.book())?;
+            Ok(true)
+        })?;
+        pricer
+    };
+    if let Some(fanout) = fanout {
+        let mut fanout = fanout.lock().unwrap();
+        if fanout.slow_disconnects() > 0 {
+            eprintln!(
+                "order_book: dropped {} slow subscribers",
+                fanout.slow_disconnects()
+            );
+        }
+        fanout.close();
+    }
+    output.flush().map_err(write_error)?;
+    for collision in pricer.book().ids().collisions() {
+        eprintln!("order_book: id collision: {}", collision);
+    }
+    write_summaries(opts, &latency, &metrics, pricer.book())
+}
+
+fn price(opts: &Options) -> Result<(), String> {
+    let cap = opts.cache_capacity;
+    match opts.cache {
+        CacheKind::Fnv => price_with(opts, || {
+            IdPriceCacheFnvMap::with_capacity_and_hasher(cap, Default::default())
+        }),
+        CacheKind::Std => price_with(opts, || IdPriceCacheStdMap::with_capacity(cap)),
+        CacheKind::Identity => price_with(opts, || {
+            IdPriceCacheIdentityMap::with_capacity_and_hasher(cap, Default::default())
+        }),
+        CacheKind::RobinHood => price_with(opts, || IdPriceCacheRobinHood::with_capacity(cap)),
+        CacheKind::Slab => price_with(opts, || IdPriceCacheSlab::with_capacity(cap)),
+    }
+}
+
+fn price_with<T, F>(opts: &Options, new_cache: F) -> Result<(), String>
+where
+    T: IdPriceCache + Send,
+    F: Fn() -> T + Sync,
+{
+    match opts.shards {
+        Some(shards) => run_sharded(opts, shards, new_cache),
+        None => run(opts, new_cache()),
+    }
+}
+
+/// price --shards, a book per symbol spread over the workers
+fn run_sharded<T, F>(opts: &Options, shards: usize, new_cache: F) -> Result<(), String>
+where
+    T: IdPriceCache + Send,
+    F: Fn() -> T + Sync,
+{
+    let new_pricer = || {
+        let book = OrderBook::with_capacity(
+            opts.target_sizes[0],
+            new_cache(),
+            opts.id_mode,
+            opts.level_capacity,
+        );
+        let mut pricer = Pricer::with_targets(book, &opts.target_sizes);
+        pricer.suppress_while_crossed(opts.when_crossed == CrossedPolicy::Suppress);
+        pricer
+    };
+    // sequence numbers run across the whole feed, not per symbol
+    let mut tracker = SequenceTracker::new(opts.on_gap);
+    if opts.on_gap == SeqPolicy::RequestSnapshot {
+        tracker
+            .on_snapshot_request(|issue| eprintln!("order_book: snapshot needed after {}", issue));
+    }
+    let mut output = open_output(&opts.output)?;
+    let targets = opts.target_sizes.len();
+    let pricers = price_sharded(
+        shards,
+        SHARD_QUEUE,
+        new_pricer,
+        tracker,
+        |feeder| {
+            for (idx, line) in open_feed(opts)?.lines().enumerate() {
+                let line = line.map_err(|err| format!("cannot read input: {}", err))?;
+                if !feeder.feed(idx, &line) {
+                    break;
+                }
+            }
+            Ok(())
+        },
+        |SymbolPriced { symbol, priced }| {
+            match priced.events {
+                Ok(events) => {
+                    for event in &events {
+                        write!(output, "{} ", symbol).map_err(write_error)?;
+                        write_event(&mut output, OutputFormat::Text, event, targets)
+                            .map_err(write_error)?;
+                    }
+                }
+                Err(BookError::Sequence(_)) => {}
+                Err(err) => bad_message(opts, priced.idx, err)?,
+            }
+            sequence_issue(opts, priced.idx, priced.issue)
+        },
+    )?;
+    output.flush().map_err(write_error)?;
+    for pricer in pricers.values() {
+        for collision in pricer.book().ids().collisions() {
+            eprintln!("order_book: id collision: {}", collision);
+        }
     }
+    Ok(())
+}
+
+/// Book for the commands that don't price anything, target size is never used
+fn plain_book(opts: &Options) -> OrderBook<IdPriceCacheFnvMap> {
+    OrderBook::with_capacity(
+        1,
+        IdPriceCacheFnvMap::with_capacity_and_hasher(opts.cache_capacity, Default::default()),
+        opts.id_mode,
+        opts.level_capacity,
+    )
+}
+
+fn validate(opts: &Options) -> Result<(), String> {
+    let mut validator = Validator::new();
+    let mut output = open_output(&opts.output)?;
+    let mut found = 0;
+    for_each_message(opts, |idx, parsed| {
+        let issues = match parsed {
+            Ok((message, seq)) => validator.check_sequenced(&message, seq),
+            Err(err) => vec![Issue::Malformed(err)],
+        };
+        for issue in issues {
+            found += 1;
+            writeln!(output, "{}: {}", position(opts.input_format, idx), issue)
+                .map_err(write_error)?;
+        }
+        Ok(true)
+    })?;
+    output.flush().map_err(write_error)?;
+    match found {
+        0 => Ok(()),
+        1 => Err("found 1 issue".to_string()),
+        n => Err(format!("found {} issues", n)),
+    }
+}
+
+fn stats(opts: &Options) -> Result<(), String> {
+    let mut collector = StatsCollector::new(plain_book(opts));
+    for_each_message(opts, |_, parsed| {
+        collector.record(parsed);
+        Ok(true)
+    })?;
+    let mut output = open_output(&opts.output)?;
+    write!(output, "{}", collector.stats()).map_err(write_error)?;
+    output.flush().map_err(write_error)
+}
+
+fn snapshot(opts: &Options, at: i64) -> Result<(), String> {
+    let mut book = plain_book(opts);
+    track_sequence(opts, &mut book);
+    if opts.orders {
+        book.track_orders();
+    }
+    if opts.input_format == InputFormat::Mbp {
+        for_each_level(opts, |idx, parsed| {
+            let (update, seq) = match parsed {
+                Ok(parsed) => parsed,
+                Err(err) => return bad_message(opts, idx, err).map(|()| true),
+            };
+            if update.timestamp() > at {
+                return Ok(false);
+            }
+            // only ever refused for its sequence number
+            let _ = book.apply_level_sequenced(&update, seq);
+            sequence_issue(opts, idx, book.sequence().last_issue())?;
+            Ok(true)
+        })?;
+    } else {
+        for_each_message(opts, |idx, parsed| {
+            let (message, seq) = match parsed {
+                Ok(parsed) => parsed,
+                Err(err) => return bad_message(opts, idx, err).map(|()| true),
+            };
+            if message.timestamp() > at {
+                return Ok(false);
+            }
+            match book.apply_sequenced(&message, seq) {
+                Ok(()) | Err(BookError::Sequence(_)) => {}
+                Err(err) => bad_message(opts, idx, err)?,
+            }
+            sequence_issue(opts, idx, book.sequence().last_issue())?;
+            Ok(true)
+        })?;
+    }
+    // laid out like a ladder, highest price on top
+    let mut output = open_output(&opts.output)?;
+    if opts.orders {
+        return write_resting_orders(&mut output, &book);
+    }
+    let asks: Vec<_> = book.levels(OrderSide::Ask).collect();
+    for &(price, depth) in asks.iter().rev() {
+        writeln!(output, "{} {} {}", OrderSide::Ask, price, depth).map_err(write_error)?;
+    }
+    for (price, depth) in book.levels(OrderSide::Bid) {
+        writeln!(output, "{} {} {}", OrderSide::Bid, price, depth).map_err(write_error)?;
+    }
+    output.flush().map_err(write_error)
+}
+
+// snapshot --orders, each order on its own line after its price and size left,
+// in the order they came in at each price
+fn write_resting_orders<T: IdPriceCache>(
+    output: &mut dyn Write,
+    book: &OrderBook<T>,
+) -> Result<(), String> {
+    let mut asks = book.resting_orders(OrderSide::Ask);
+    asks.sort_by_key(|&(price, _, _)| Reverse(price)); // stable, so arrival order holds
+    for &(side, ref orders) in &[
+        (OrderSide::Ask, asks),
+        (OrderSide::Bid, book.resting_orders(OrderSide::Bid)),
+    ] {
+        for &(price, size, key) in orders {
+            let id = book
+                .ids()
+                .original(key)
+                .expect("parse_args refuses --ids hash");
+            writeln!(output, "{} {} {} {}", side, price, size, id).map_err(write_error)?;
+        }
+    }
+    output.flush().map_err(write_error)
+}
+
+fn convert(opts: &Options) -> Result<(), String> {
+    let to = opts.convert_to.unwrap_or(match opts.input_format {
+        InputFormat::Text => InputFormat::Binary,
+        InputFormat::Binary | InputFormat::Mbp => InputFormat::Text,
+    });
+    let mut output = open_output(&opts.output)?;
+    for_each_message(opts, |idx, parsed| {
+        match parsed {
+            // binary records have no room for sequence numbers
+            Ok((message, seq)) => match (to, seq) {
+                (InputFormat::Text, Some(seq)) => writeln!(output, "{} {}", message, seq),
+                (InputFormat::Text, None) => writeln!(output, "{}", message),
+                (InputFormat::Binary, _) => write_message(&mut output, &message),
+                (InputFormat::Mbp, _) => unreachable!("parse_args refuses --to mbp"),
+            }
+            .map_err(write_error)?,
+            Err(err) => bad_message(opts, idx, err)?,
+        }
+        Ok(true)
+    })?;
+    output.flush().map_err(write_error)
+}
+
+fn generate(opts: &Options) -> Result<(), String> {
+    let mut output = open_output(&opts.output)?;
+    for message in FeedGenerator::new(opts.generate.clone()) {
+        let message = message.as_message();
+        match opts.convert_to {
+            Some(InputFormat::Binary) => write_message(&mut output, &message),
+            _ => writeln!(output, "{}", message),
+        }
+        .map_err(write_error)?;
+    }
+    output.flush().map_err(write_error)
+}
+
+fn dispatch(subcommand: Subcommand, opts: &Options) -> Result<(), String> {
+    match subcommand {
+        Subcommand::Price => price(opts),
+        Subcommand::Validate => validate(opts),
+        Subcommand::Stats => stats(opts),
+        Subcommand::Snapshot => snapshot(opts, opts.at.expect("parse_args checks --at")),
+        Subcommand::Convert => convert(opts),
+        Subcommand::Generate => generate(opts),
+    }
+}
+
+fn main() {
+    let code = match cli::parse_args(env::args().skip(1)) {
+        Ok(Command::Help) => {
+            print!("{}", USAGE);
+            0
+        }
+        Ok(Command::Version) => {
+            println!("order_book {}", env!("CARGO_PKG_VERSION"));
+            0
+        }
+        Ok(Command::Run(subcommand, opts)) => match dispatch(subcommand, &opts) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("order_book: {}", err);
+                EXIT_FAILURE
+            }
+        },
+        Err(err) => {
+            eprintln!("order_book: {}\n\n{}", err, USAGE);
+            EXIT_USAGE
+        }
+    };
+    process::exit(code);
 }
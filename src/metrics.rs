@@ -0,0 +1,469 @@
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use idpricecache::IdPriceCache;
+use latency::MessageKind;
+use orderbook::{BookError, BookState, OrderBook};
+use orderside::OrderSide;
+use pricer::PricerEvent;
+
+// Counters and gauges for a long running book, in the Prometheus text
+// exposition format. Counters are kept here as whoever drives the book hands
+// over each message and what became of it, gauges are read off the book when
+// the text is rendered. The text can be written to a file, atomically so a
+// textfile collector never sees half of it, or scraped over HTTP: the server
+// answers GET /metrics on threads of its own and asks the book's thread for a
+// fresh text, which gets rendered between messages like query answers. A
+// scrape that isn't answered within FRESH_WAIT, the feed being quiet, gets the
+// last text rendered instead, and the text is rendered after each of the next
+// EAGER_RENDERS messages so the next scrape is up to date whenever it comes,
+// without the book's thread rendering forever if the scraper went away. Once
+// a scrape is answered fresh again, the feed is busy enough to go back to
+// rendering only when asked.
+
+const FRESH_WAIT: Duration = Duration::from_millis(100);
+const EAGER_RENDERS: usize = 1000;
+// request line and headers, anything longer isn't a scrape
+const MAX_REQUEST: u64 = 8192;
+
+/// Counts of what the book was handed, see record
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Metrics {
+    adds: u64,
+    reduces: u64,
+    levels: u64,
+    parse_errors: u64,
+    unknown_orders: u64,
+    refused: u64,
+    output_lines: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Counts a message and what became of it, a line of output for every event it priced
+    pub fn record(&mut self, kind: MessageKind, priced: &Result<&[PricerEvent], BookError>) {
+        match kind {
+            MessageKind::Add => self.adds += 1,
+            MessageKind::Reduce => self.reduces += 1,
+            MessageKind::Level => self.levels += 1,
+            MessageKind::Bad => {}
+        }
+        match *priced {
+            Ok(events) => self.output_lines += events.len() as u64,
+            Err(BookError::Parse(_)) | Err(BookError::Overflow) => self.parse_errors += 1,
+            Err(BookError::UnknownOrder(_)) => self.unknown_orders += 1,
+            Err(BookError::Sequence(_)) | Err(BookError::IdCollision(_)) => self.refused += 1,
+        }
+    }
+
+    /// Messages of the kind that parsed, whether or not the book took them
+    pub fn messages(&self, kind: MessageKind) -> u64 {
+        match kind {
+            MessageKind::Add => self.adds,
+            MessageKind::Reduce => self.reduces,
+            MessageKind::Level => self.levels,
+            MessageKind::Bad => self.parse_errors,
+        }
+    }
+
+    /// The counters and the book's gauges as Prometheus text
+    pub fn render<T: IdPriceCache>(&self, book: &OrderBook<T>) -> String {
+        let mut text = String::new();
+        let name = "order_book_messages_total";
+        family(&mut text, name, "counter", "Messages read, by type");
+        for &(kind, label) in &[
+            (MessageKind::Add, "add"),
+            (MessageKind::Reduce, "reduce"),
+            (MessageKind::Level, "level"),
+        ] {
+            sample(&mut text, name, Some(("type", label)), self.messages(kind));
+        }
+        let episodes = book.cleared_episodes() + book.current_episode().iter().count() as u64;
+        let counters = [
+            (
+                "parse_errors_total",
+                "Messages that didn't parse or were out of range",
+                self.parse_errors,
+            ),
+            (
+                "unknown_orders_total",
+                "Reduces for orders the book never saw",
+                self.unknown_orders,
+            ),
+            (
+                "refused_total",
+                "Messages refused for their sequence number or a colliding order id",
+                self.refused,
+            ),
+            (
+                "output_lines_total",
+                "Lines of price output written",
+                self.output_lines,
+            ),
+            (
+                "crossed_episodes_total",
+                "Times the book got locked or crossed",
+                episodes,
+            ),
+        ];
+        for &(name, help, value) in &counters {
+            let name = format!("order_book_{}", name);
+            family(&mut text, &name, "counter", help);
+            sample(&mut text, &name, None, value);
+        }
+
+        by_side(
+            &mut text,
+            "order_book_price_levels",
+            "Price levels with something resting on them",
+            |side| book.levels(side).count() as u64,
+        );
+        by_side(
+            &mut text,
+            "order_book_allocated_price_levels",
+            "Price levels held, emptied ones included",
+            |side| book.allocated_levels(side) as u64,
+        );
+        by_side(
+            &mut text,
+            "order_book_resting_size",
+            "Shares resting",
+            |side| book.total_size(side).max(0) as u64,
+        );
+        let gauges = [
+            (
+                "cached_orders",
+                "Order ids in the price cache",
+                book.cache().len() as u64,
+            ),
+            (
+                "crossed",
+                "1 while the book is locked or crossed",
+                (book.state() != BookState::Normal) as u64,
+            ),
+        ];
+        for &(name, help, value) in &gauges {
+            let name = format!("order_book_{}", name);
+            family(&mut text, &name, "gauge", help);
+            sample(&mut text, &name, None, value);
+        }
+        text
+    }
+
+    /// Writes render() to path by way of a file next to it, so readers get all of it or none
+    pub fn write_file<T: IdPriceCache, P: AsRef<Path>>(
+        &self,
+        book: &OrderBook<T>,
+        path: P,
+    ) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        fs::write(&partial, self.render(book))?;
+        fs::rename(&partial, path)
+    }
+}
+
+fn family(text: &mut String, name: &str, kind: &str, help: &str) {
+    // a String takes whatever it's given
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+fn by_side<F: Fn(OrderSide) -> u64>(text: &mut String, name: &str, help: &str, value: F) {
+    family(text, name, "gauge", help);
+    for &(side, label) in &[(OrderSide::Bid, "bid"), (OrderSide::Ask, "ask")] {
+        sample(text, name, Some(("side", label)), value(side));
+    }
+}
+
+fn sample(text: &mut String, name: &str, label: Option<(&str, &str)>, value: u64) {
+    let _ = match label {
+        Some((label, value_of)) => {
+            writeln!(text, "{}{{{}=\"{}\"}} {}", name, label, value_of, value)
+        }
+        None => writeln!(text, "{} {}", name, value),
+    };
+}
+
+struct Shared {
+    text: Mutex<(u64, String)>, // how many texts were published, and the latest
+    published: Condvar,
+    wanted: AtomicBool, // a scrape is waiting on a fresh text
+    eager: AtomicUsize, // renders left since the last scrape timed out waiting
+}
+
+/// Serves the latest metrics text over HTTP, see publish
+pub struct MetricsServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl MetricsServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            text: Mutex::new((0, String::new())),
+            published: Condvar::new(),
+            wanted: AtomicBool::new(false),
+            eager: AtomicUsize::new(0),
+        });
+        let scrapes = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let shared = scrapes.clone();
+                        thread::spawn(move || serve_scrape(stream, &shared));
+                    }
+                    Err(err) => eprintln!("order_book: cannot take metrics scrape: {}", err),
+                }
+            }
+        });
+        Ok(MetricsServer { addr, shared })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Whether a scrape asked for a fresh text since the last call
+    pub fn wanted(&self) -> bool {
+        self.shared.wanted.swap(false, Ordering::Relaxed)
+    }
+
+    /// Makes text what scrapes get from now on, and hands it to any waiting for it
+    pub fn publish(&self, text: String) {
+        let mut latest = self.shared.text.lock().unwrap();
+        *latest = (latest.0 + 1, text);
+        self.shared.published.notify_all();
+    }
+
+    /// publish(metrics.render(book)) if a scrape is waiting on it, or the last one timed out
+    /// less than EAGER_RENDERS publishes ago. Call between messages, returns whether it published
+    pub fn poll<T: IdPriceCache>(&self, metrics: &Metrics, book: &OrderBook<T>) -> bool {
+        let eager = self
+            .shared
+            .eager
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        let wanted = self.wanted() || eager;
+        if wanted {
+            self.publish(metrics.render(book));
+        }
+        wanted
+    }
+}
+
+// the text published next, or the latest if that takes longer than FRESH_WAIT
+fn fresh_text(shared: &Shared) -> String {
+    let latest = shared.text.lock().unwrap();
+    let seen = latest.0;
+    shared.wanted.store(true, Ordering::Relaxed);
+    let (latest, waited) = shared
+        .published
+        .wait_timeout_while(latest, FRESH_WAIT, |latest| latest.0 == seen)
+        .unwrap();
+    let eager = if waited.timed_out() { EAGER_RENDERS } else { 0 };
+    shared.eager.store(eager, Ordering::Relaxed);
+    latest.1.clone()
+}
+
+fn serve_scrape(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = BufReader::new((&stream).take(MAX_REQUEST));
+    let mut line = String::new();
+    request.read_line(&mut line)?;
+    // the headers say nothing a scrape needs, read up to the blank line after them
+    let mut header = String::new();
+    while request.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut fields = line.split_whitespace();
+    let (method, path) = (
+        fields.next(),
+        fields.next().and_then(|p| p.split('?').next()),
+    );
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            fresh_text(shared),
+        ),
+        (Some("GET"), _) => (
+            "404 Not Found",
+            "text/plain",
+            "metrics are at /metrics\n".to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "only GET\n".to_string(),
+        ),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use pricer::Pricer;
+    use std::env;
+    use std::process;
+
+    // the basic feed through a pricer, with a reduce for an unknown order and a bad line
+    fn priced() -> (Metrics, Pricer<IdPriceCacheFnvMap>) {
+        let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        let mut metrics = Metrics::new();
+        let lines = include_str!("../data/basic.in.txt")
+            .lines()
+            .chain(vec!["28815000 R zz 10", "28815001 X"]);
+        for line in lines {
+            let parsed = ::orders::Message::parse_with_seq(line);
+            let kind = MessageKind::of(&parsed);
+            let priced = parsed
+                .map_err(BookError::from)
+                .and_then(|(message, seq)| pricer.try_apply_sequenced(&message, seq));
+            metrics.record(kind, &priced);
+        }
+        (metrics, pricer)
+    }
+
+    fn value(text: &str, sample: &str) -> u64 {
+        let line = text
+            .lines()
+            .find(|line| line.starts_with(sample) && line[sample.len()..].starts_with(' '))
+            .unwrap_or_else(|| panic!("no {} in\n{}", sample, text));
+        line[sample.len() + 1..].parse().unwrap()
+    }
+
+    #[test]
+    fn counters_and_gauges() {
+        let (metrics, pricer) = priced();
+        let book = pricer.book();
+        let text = metrics.render(book);
+        let messages = include_str!("../data/basic.in.txt").lines().count() as u64 + 1;
+        assert_eq!(
+            metrics.messages(MessageKind::Add) + metrics.messages(MessageKind::Reduce),
+            messages
+        );
+        assert_eq!(
+            value(&text, "order_book_messages_total{type=\"add\"}"),
+            metrics.messages(MessageKind::Add)
+        );
+        assert_eq!(value(&text, "order_book_messages_total{type=\"level\"}"), 0);
+        assert_eq!(value(&text, "order_book_parse_errors_total"), 1);
+        assert_eq!(value(&text, "order_book_unknown_orders_total"), 1);
+        assert_eq!(value(&text, "order_book_refused_total"), 0);
+        let expected = include_str!("../data/basic.out.txt").lines().count() as u64;
+        assert_eq!(value(&text, "order_book_output_lines_total"), expected);
+        assert_eq!(
+            value(&text, "order_book_price_levels{side=\"bid\"}"),
+            book.levels(OrderSide::Bid).count() as u64
+        );
+        assert_eq!(
+            value(&text, "order_book_resting_size{side=\"ask\"}"),
+            book.total_size(OrderSide::Ask) as u64
+        );
+        assert_eq!(
+            value(&text, "order_book_cached_orders"),
+            book.cache().len() as u64
+        );
+        assert_eq!(value(&text, "order_book_crossed"), 0);
+
+        // every sample comes after its family's HELP and TYPE
+        let mut family = "";
+        for line in text.lines() {
+            if line.starts_with("# HELP ") {
+                family = line.split_whitespace().nth(2).unwrap();
+            } else if !line.starts_with("# TYPE ") {
+                assert!(line.starts_with(family), "{} outside {}", line, family);
+            }
+        }
+    }
+
+    #[test]
+    fn crossed_episodes() {
+        let mut book = OrderBook::new(1, IdPriceCacheFnvMap::default());
+        let metrics = Metrics::new();
+        for line in &["1 A a B 10.00 10", "2 A b S 9.00 10", "3 R b 10"] {
+            book.process(line);
+        }
+        let text = metrics.render(&book);
+        assert_eq!(value(&text, "order_book_crossed_episodes_total"), 1);
+        assert_eq!(value(&text, "order_book_crossed"), 0);
+        book.process("4 A c S 10.00 10");
+        let text = metrics.render(&book);
+        assert_eq!(value(&text, "order_book_crossed_episodes_total"), 2);
+        assert_eq!(value(&text, "order_book_crossed"), 1);
+    }
+
+    #[test]
+    fn file_dump() {
+        let (metrics, pricer) = priced();
+        let path = env::temp_dir().join(format!("order_book_metrics_{}.prom", process::id()));
+        metrics.write_file(pricer.book(), &path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            metrics.render(pricer.book())
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{}\r\nHost: localhost\r\n\r\n", request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn scrapes() {
+        let server = MetricsServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        server.publish("stale 1\n".to_string());
+        let scrape = thread::spawn(move || get(addr, "GET /metrics HTTP/1.1"));
+        // stands in for the ingestion loop, polling between messages
+        let (metrics, pricer) = priced();
+        while !server.poll(&metrics, pricer.book()) {
+            thread::yield_now();
+        }
+        let response = scrape.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, metrics.render(pricer.book()));
+
+        // nobody polling, so the last text, and a fresh one after each of the next messages
+        let response = get(addr, "GET /metrics?name[]=x HTTP/1.0");
+        assert!(response.ends_with(body));
+        for _ in 0..EAGER_RENDERS {
+            assert!(server.poll(&metrics, pricer.book()));
+        }
+        // the scraper went away, stop rendering for it
+        assert!(!server.poll(&metrics, pricer.book()));
+        assert!(get(addr, "GET / HTTP/1.1").starts_with("HTTP/1.1 404 "));
+        assert!(get(addr, "POST /metrics HTTP/1.1").starts_with("HTTP/1.1 405 "));
+    }
+}
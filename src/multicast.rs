@@ -0,0 +1,401 @@
+use std::cmp::max;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::str;
+use std::time::Duration;
+
+use orders::{Message, ParseError};
+
+use sequence::SeqIssue;
+
+// Packets as they come off the multicast group, laid out like MoldUDP64:
+//   session:[u8; 10] sequence:u64 count:u16, then count times length:u16 message
+// big endian. sequence numbers the first message, the rest follow on from it.
+// A count of 0 is a heartbeat, 0xFFFF ends the session.
+
+pub const SESSION_LEN: usize = 10;
+pub const HEADER_LEN: usize = SESSION_LEN + 8 + 2;
+const END_OF_SESSION: u16 = 0xFFFF;
+const MAX_DATAGRAM: usize = 65536;
+
+pub type Session = [u8; SESSION_LEN];
+
+/// Session name padded out with spaces, or cut down to SESSION_LEN bytes
+pub fn session_id(name: &str) -> Session {
+    let mut session = [b' '; SESSION_LEN];
+    for (byte, name_byte) in session.iter_mut().zip(name.bytes()) {
+        *byte = name_byte;
+    }
+    session
+}
+
+/// Why a datagram isn't a packet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PacketError {
+    /// shorter than the header
+    TooShort(usize),
+    /// the header promised more messages than the datagram holds
+    Truncated { count: u16, found: u16 },
+    /// bytes left over after the last message
+    TrailingBytes(usize),
+    /// the messages' sequence numbers would run past what a u64 holds
+    SequenceOverflow { sequence: u64, count: u16 },
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            PacketError::TooShort(len) => write!(f, "{} byte packet is shorter than a header", len),
+            PacketError::Truncated { count, found } => {
+                write!(f, "packet says {} messages, holds {}", count, found)
+            }
+            PacketError::TrailingBytes(len) => {
+                write!(f, "{} bytes after the last message", len)
+            }
+            PacketError::SequenceOverflow { sequence, count } => write!(
+                f,
+                "{} messages from sequence number {} don't fit in a u64",
+                count, sequence
+            ),
+        }
+    }
+}
+
+impl Error for PacketError {}
+
+/// A datagram split into its messages
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet<'a> {
+    pub session: Session,
+    pub sequence: u64,
+    messages: Vec<&'a [u8]>,
+    end_of_session: bool,
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(datagram: &'a [u8]) -> Result<Self, PacketError> {
+        if datagram.len() < HEADER_LEN {
+            return Err(PacketError::TooShort(datagram.len()));
+        }
+        let mut session = [0u8; SESSION_LEN];
+        session.copy_from_slice(&datagram[..SESSION_LEN]);
+        let mut sequence = [0u8; 8];
+        sequence.copy_from_slice(&datagram[SESSION_LEN..SESSION_LEN + 8]);
+        let count = u16::from_be_bytes([datagram[HEADER_LEN - 2], datagram[HEADER_LEN - 1]]);
+        let sequence = u64::from_be_bytes(sequence);
+        let end_of_session = count == END_OF_SESSION;
+        let expected = if end_of_session { 0 } else { count };
+        // next_sequence has to fit too
+        if sequence.checked_add(u64::from(expected)).is_none() {
+            return Err(PacketError::SequenceOverflow { sequence, count });
+        }
+
+        let mut messages = Vec::with_capacity(expected as usize);
+        let mut rest = &datagram[HEADER_LEN..];
+        while messages.len() < expected as usize {
+            let truncated = PacketError::Truncated {
+                count,
+                found: messages.len() as u16,
+            };
+            if rest.len() < 2 {
+                return Err(truncated);
+            }
+            let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            if rest.len() < 2 + len {
+                return Err(truncated);
+            }
+            messages.push(&rest[2..2 + len]);
+            rest = &rest[2 + len..];
+        }
+        if !rest.is_empty() {
+            return Err(PacketError::TrailingBytes(rest.len()));
+        }
+        Ok(Packet {
+            session,
+            sequence,
+            messages,
+            end_of_session,
+        })
+    }
+
+    pub fn messages(&self) -> &[&'a [u8]] {
+        &self.messages
+    }
+
+    /// Sequence number of the message after this packet's last,
+    /// what a heartbeat says should come next. parse makes sure it fits
+    pub fn next_sequence(&self) -> u64 {
+        self.sequence + self.messages.len() as u64
+    }
+
+    pub fn is_heartbeat(&self) -> bool {
+        self.messages.is_empty() && !self.end_of_session
+    }
+
+    pub fn is_end_of_session(&self) -> bool {
+        self.end_of_session
+    }
+}
+
+/// Lays a packet out for sending, an empty messages is a heartbeat
+pub fn encode_packet(session: &Session, sequence: u64, messages: &[&[u8]]) -> Vec<u8> {
+    let mut packet = encode_header(session, sequence, messages.len() as u16);
+    for message in messages {
+        packet.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        packet.extend_from_slice(message);
+    }
+    packet
+}
+
+/// Packet telling receivers the session is over
+pub fn encode_end_of_session(session: &Session, sequence: u64) -> Vec<u8> {
+    encode_header(session, sequence, END_OF_SESSION)
+}
+
+fn encode_header(session: &Session, sequence: u64, count: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN);
+    packet.extend_from_slice(session);
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(&count.to_be_bytes());
+    packet
+}
+
+/// A text message out of a packet, "28800538 A b S 44.26 100" without the newline
+pub fn parse_text(message: &[u8]) -> Result<Message<'_>, ParseError> {
+    match str::from_utf8(message) {
+        Ok(line) => Message::parse(line),
+        Err(_) => Err(ParseError::UnknownMessage(
+            String::from_utf8_lossy(message).into_owned(),
+        )),
+    }
+}
+
+/// Reads packets off a multicast group. Sticks to the session of the first packet,
+/// packets from any other are skipped. Sequence numbers of messages are left to the book,
+/// see OrderBook::apply_sequenced, so gaps go through its SeqPolicy.
+/// Heartbeats and the end of session only show gaps here, see trailing_gap
+pub struct MulticastReceiver {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+    session: Option<Session>,
+    foreign_packets: u64,
+    next_sequence: Option<u64>, // after the last message received
+    trailing_gap: Option<SeqIssue>,
+}
+
+impl MulticastReceiver {
+    /// Binds port on every interface and joins group on interface,
+    /// Ipv4Addr::UNSPECIFIED lets the kernel pick
+    pub fn join(group: Ipv4Addr, port: u16, interface: Ipv4Addr) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.join_multicast_v4(&group, &interface)?;
+        Ok(MulticastReceiver::from_socket(socket))
+    }
+
+    /// Reads from a socket set up elsewhere, multicast or not
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        MulticastReceiver {
+            socket,
+            buf: vec![0; MAX_DATAGRAM],
+            session: None,
+            foreign_packets: 0,
+            next_sequence: None,
+            trailing_gap: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// None blocks for as long as it takes
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// The session being followed, once the first packet is in
+    pub fn session(&self) -> Option<Session> {
+        self.session
+    }
+
+    /// Packets skipped for belonging to another session
+    pub fn foreign_packets(&self) -> u64 {
+        self.foreign_packets
+    }
+
+    /// Messages a heartbeat or the end of session says were sent after the last ones
+    /// received, found by the last recv. Nothing else would notice them missing,
+    /// the book only sees the gap if more messages come
+    pub fn trailing_gap(&self) -> Option<SeqIssue> {
+        self.trailing_gap
+    }
+
+    /// Waits for the next packet of the session, None once it has ended.
+    /// Datagrams that aren't packets are InvalidData errors, the receiver can carry on after one
+    pub fn recv(&mut self) -> io::Result<Option<Packet<'_>>> {
+        self.trailing_gap = None;
+        let len = loop {
+            let len = self.socket.recv(&mut self.buf)?;
+            match self.session {
+                Some(ours) if len >= SESSION_LEN && ours[..] != self.buf[..SESSION_LEN] => {
+                    self.foreign_packets += 1
+                }
+                _ => break len, // short ones are for parse to complain about
+            }
+        };
+        let packet = Packet::parse(&self.buf[..len])
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        // only a datagram that parsed can pick the session
+        self.session.get_or_insert(packet.session);
+        if packet.messages().is_empty() {
+            if let Some(expected) = self.next_sequence {
+                if packet.sequence > expected {
+                    self.trailing_gap = Some(SeqIssue::Gap {
+                        expected,
+                        found: packet.sequence,
+                    });
+                }
+            }
+        }
+        let next = match self.next_sequence {
+            Some(next) => max(next, packet.next_sequence()),
+            None => packet.next_sequence(),
+        };
+        self.next_sequence = Some(next);
+        if packet.is_end_of_session() {
+            return Ok(None);
+        }
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use orderbook::OrderBook;
+
+    const LINES: [&[u8]; 3] = [
+        b"28800538 A b S 44.26 100",
+        b"28800562 A c B 44.10 100",
+        b"28800744 R b 100",
+    ];
+
+    #[test]
+    fn packet_round_trip() {
+        let session = session_id("ORDERBOOK1");
+        let bytes = encode_packet(&session, 7, &LINES);
+        let packet = Packet::parse(&bytes).unwrap();
+        assert_eq!(&packet.session, b"ORDERBOOK1");
+        assert_eq!(packet.sequence, 7);
+        assert_eq!(packet.messages(), &LINES[..]);
+        assert_eq!(packet.next_sequence(), 10);
+        assert!(!packet.is_heartbeat());
+
+        let heartbeat = encode_packet(&session, 10, &[]);
+        assert!(Packet::parse(&heartbeat).unwrap().is_heartbeat());
+        let end = encode_end_of_session(&session, 10);
+        assert!(Packet::parse(&end).unwrap().is_end_of_session());
+        assert_eq!(&session_id("S1"), b"S1        ");
+    }
+
+    #[test]
+    fn bad_packets() {
+        let bytes = encode_packet(&session_id("S1"), 1, &LINES);
+        assert_eq!(Packet::parse(&bytes[..5]), Err(PacketError::TooShort(5)));
+        assert_eq!(
+            Packet::parse(&bytes[..bytes.len() - 1]),
+            Err(PacketError::Truncated { count: 3, found: 2 })
+        );
+        let mut long = bytes.clone();
+        long.push(0);
+        assert_eq!(Packet::parse(&long), Err(PacketError::TrailingBytes(1)));
+        assert_eq!(
+            Packet::parse(&encode_packet(&session_id("S1"), u64::MAX - 2, &LINES)),
+            Err(PacketError::SequenceOverflow {
+                sequence: u64::MAX - 2,
+                count: 3
+            })
+        );
+        let last = encode_packet(&session_id("S1"), u64::MAX - 3, &LINES);
+        assert_eq!(Packet::parse(&last).unwrap().next_sequence(), u64::MAX);
+    }
+
+    #[test]
+    fn packets_drive_the_book_and_gaps_show() {
+        let mut book = OrderBook::new(100, IdPriceCacheFnvMap::default());
+        let first = encode_packet(&session_id("S1"), 1, &LINES[..2]);
+        // message 3 is lost, 4 comes in the next packet
+        let second = encode_packet(&session_id("S1"), 4, &LINES[2..]);
+        for bytes in &[first, second] {
+            let packet = Packet::parse(bytes).unwrap();
+            for (n, message) in packet.messages().iter().enumerate() {
+                let message = parse_text(message).unwrap();
+                book.apply_sequenced(&message, Some(packet.sequence + n as u64))
+                    .unwrap();
+            }
+        }
+        assert_eq!(
+            book.sequence().last_issue(),
+            Some(SeqIssue::Gap {
+                expected: 3,
+                found: 4
+            })
+        );
+        assert_eq!(book.best_ask(), None);
+        assert!(parse_text(b"\xff A").is_err());
+    }
+
+    #[test]
+    fn receive_from_the_group() {
+        let group = Ipv4Addr::new(239, 255, 42, 99);
+        let mut receiver = MulticastReceiver::join(group, 0, Ipv4Addr::UNSPECIFIED).unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        let sender = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        sender.set_multicast_loop_v4(true).unwrap();
+        let send = |packet: &[u8]| {
+            // hosts without a multicast route still get it over plain loopback
+            if sender.send_to(packet, (group, port)).is_err() {
+                sender.send_to(packet, (Ipv4Addr::LOCALHOST, port)).unwrap();
+            }
+        };
+
+        send(b"JUNKJUNKJUNK");
+        send(&encode_packet(&session_id("S1"), 1, &LINES[..1]));
+        send(&encode_packet(&session_id("OTHER"), 1, &LINES[..1]));
+        send(&encode_packet(&session_id("S1"), 2, &LINES[1..]));
+        // messages 4 and 5 are lost, with nothing after them but a heartbeat
+        send(&encode_packet(&session_id("S1"), 6, &[]));
+        send(&encode_end_of_session(&session_id("S1"), 6));
+
+        let err = receiver.recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(receiver.session(), None);
+        let mut received = Vec::new();
+        let mut gaps = Vec::new();
+        while let Some(packet) = receiver.recv().unwrap() {
+            received.push((packet.sequence, packet.messages().len()));
+            gaps.push(receiver.trailing_gap());
+        }
+        assert_eq!(received, vec![(1, 1), (2, 2), (6, 0)]);
+        assert_eq!(
+            gaps,
+            vec![
+                None,
+                None,
+                Some(SeqIssue::Gap {
+                    expected: 4,
+                    found: 6
+                })
+            ]
+        );
+        assert_eq!(receiver.trailing_gap(), None);
+        assert_eq!(receiver.session(), Some(session_id("S1")));
+        assert_eq!(receiver.foreign_packets(), 1);
+    }
+}
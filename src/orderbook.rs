@@ -1,4 +1,10 @@
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use fnv::FnvHashMap;
 
 use amount::Amount;
 
@@ -6,35 +12,150 @@ use bidamount::BidAmount;
 
 use orderside::OrderSide;
 
-use orders::{LimitOrder, ReduceOrder};
+use orders::{LevelUpdate, LimitOrder, Message, ParseError, ReduceOrder};
+
+use idpricecache::IdPriceCache;
+
+use orderids::{IdCollision, IdMode, OrderIds};
 
-/// Price cache strategy (for benchmarking)
-pub trait IdPriceCache {
-    fn insert(&mut self, order: &LimitOrder);
-    fn contains_key(&self, key: &u64) -> bool;
-    fn get(&self, key: &u64) -> Option<&(Amount, OrderSide)>;
+use sequence::{SeqIssue, SeqVerdict, SequenceTracker};
+
+/// Why a message couldn't be applied to the book
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BookError {
+    Parse(ParseError),
+    /// reduce for an order the book has never seen
+    UnknownOrder(String),
+    /// refused under SeqPolicy::Halt
+    Sequence(SeqIssue),
+    /// the message would take a level's depth or a side's total size past what an i64 holds
+    Overflow,
+    /// add refused under IdMode::CheckedHash, its id collides with an earlier one
+    IdCollision(IdCollision),
 }
 
-pub type IdPriceCacheFnvMap = fnv::FnvHashMap<u64, (Amount, OrderSide)>;
-impl IdPriceCache for IdPriceCacheFnvMap {
-    fn insert(&mut self, order: &LimitOrder) {
-        self.insert(order.id, (order.price, order.side));
+impl Display for BookError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            BookError::Parse(ref err) => write!(f, "{}", err),
+            BookError::UnknownOrder(ref id) => write!(f, "no order under id {}", id),
+            BookError::Sequence(ref issue) => write!(f, "{}", issue),
+            BookError::Overflow => write!(f, "size out of range for the book"),
+            BookError::IdCollision(ref collision) => write!(f, "{}", collision),
+        }
     }
-    fn contains_key(&self, key: &u64) -> bool {
-        self.contains_key(key)
+}
+
+impl Error for BookError {}
+
+impl From<ParseError> for BookError {
+    fn from(err: ParseError) -> Self {
+        BookError::Parse(err)
     }
-    fn get(&self, key: &u64) -> Option<&(Amount, OrderSide)> {
-        self.get(key)
+}
+
+/// Where the best bid sits against the best ask, ordered from fine to worst.
+/// An aggregated book should never be locked or crossed,
+/// when it is a message was lost or arrived out of order
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum BookState {
+    Normal,
+    /// best bid == best ask
+    Locked,
+    /// best bid > best ask
+    Crossed,
+}
+
+impl Display for BookState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            BookState::Normal => write!(f, "normal"),
+            BookState::Locked => write!(f, "locked"),
+            BookState::Crossed => write!(f, "crossed"),
+        }
+    }
+}
+
+/// The book moved between states on the message at timestamp
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StateChange {
+    pub timestamp: i64,
+    pub from: BookState,
+    pub to: BookState,
+}
+
+/// A stretch of messages the book spent locked or crossed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CrossedEpisode {
+    pub first_timestamp: i64,    // message that locked or crossed the book
+    pub last_timestamp: i64,     // latest message the book was still locked or crossed after
+    pub cleared_at: Option<i64>, // message that put it right, None while it lasts
+    pub worst: BookState,
+}
+
+impl CrossedEpisode {
+    /// From the first message until the one that cleared it, or until the latest one
+    pub fn duration(&self) -> i64 {
+        // timestamps can be anything at all under SeqPolicy::Warn
+        self.cleared_at
+            .unwrap_or(self.last_timestamp)
+            .saturating_sub(self.first_timestamp)
     }
 }
 
-type Depth = i64;
+/// How many cleared episodes episodes() keeps, the oldest go first
+pub const RECENT_EPISODES: usize = 1024;
+
+pub type Depth = i64;
 
 type BidsVec = Vec<(BidAmount, Depth)>;
 type AsksVec = Vec<(Amount, Depth)>;
 
+// sets the depth at price and the side's total along with it, leaving both alone if the
+// total would overflow. Emptied levels stay allocated like reduced ones
+fn set_depth<P: Ord + Copy>(
+    levels: &mut Vec<(P, Depth)>,
+    total: &mut i64,
+    price: P,
+    depth: Depth,
+) -> Result<(), BookError> {
+    let found = levels.binary_search_by_key(&price, |&(price, _depth)| price);
+    let old = found.map_or(0, |idx| levels[idx].1);
+    let new_total = i128::from(*total) - i128::from(old) + i128::from(depth);
+    *total = i64::try_from(new_total).map_err(|_| BookError::Overflow)?;
+    match found {
+        Ok(idx) => levels[idx].1 = depth,
+        Err(idx) => {
+            if depth != 0 {
+                levels.insert(idx, (price, depth));
+            }
+        }
+    }
+    Ok(())
+}
+
+// takes size off a level and its side's total, leaving both alone if either would overflow
+fn reduce_depth(depth: &mut Depth, total: &mut i64, size: i64) -> Result<(), BookError> {
+    match (depth.checked_sub(size), total.checked_sub(size)) {
+        (Some(new_depth), Some(new_total)) => {
+            *depth = new_depth;
+            *total = new_total;
+            Ok(())
+        }
+        _ => Err(BookError::Overflow),
+    }
+}
+
+// Size left on each order, for listing them with their ids
+#[derive(Default)]
+struct RestingOrders {
+    added: u64,
+    orders: FnvHashMap<u64, (u64, i64)>, // key -> (when it was added, size left)
+}
+
 pub struct OrderBook<T: IdPriceCache + Sized> {
     cache: T,
+    ids: OrderIds,
     bids_total_size: i64,
     asks: AsksVec,
     bids: BidsVec,
@@ -43,13 +164,36 @@ pub struct OrderBook<T: IdPriceCache + Sized> {
     // only 1 side is affected on Reduce or Limit order
     pub last_action_side: OrderSide, // which side was touched last
     pub last_action_timestamp: i64,  // timestamp of last touched side
+    state: BookState,
+    state_change: Option<StateChange>, // caused by the last message
+    episode: Option<CrossedEpisode>,   // the one going on now
+    episodes: VecDeque<CrossedEpisode>, // the latest cleared ones
+    cleared_episodes: u64,
+    cleared_duration: i64, // saturating, see CrossedEpisode::duration
+    sequence: SequenceTracker,
+    resting: Option<RestingOrders>, // only kept after track_orders
 }
 
 impl<T: IdPriceCache + Sized> OrderBook<T> {
     pub fn new(target_size: i64, cache: T) -> Self {
-        let cap = 256;
+        OrderBook::with_id_mode(target_size, cache, IdMode::Hash)
+    }
+
+    pub fn with_id_mode(target_size: i64, cache: T, id_mode: IdMode) -> Self {
+        OrderBook::with_capacity(target_size, cache, id_mode, 256)
+    }
+
+    /// level_capacity is how many price levels per side to allocate up front
+    pub fn with_capacity(
+        target_size: i64,
+        cache: T,
+        id_mode: IdMode,
+        level_capacity: usize,
+    ) -> Self {
+        let cap = level_capacity;
         OrderBook {
             cache,
+            ids: OrderIds::new(id_mode),
             asks: AsksVec::with_capacity(cap),
             bids: BidsVec::with_capacity(cap),
             bids_total_size: 0,
@@ -57,77 +201,270 @@ impl<T: IdPriceCache + Sized> OrderBook<T> {
             target_size,
             last_action_side: OrderSide::Ask,
             last_action_timestamp: 000_000_000,
+            state: BookState::Normal,
+            state_change: None,
+            episode: None,
+            episodes: VecDeque::new(),
+            cleared_episodes: 0,
+            cleared_duration: 0,
+            sequence: SequenceTracker::default(),
+            resting: None,
         }
     }
 
-    fn _add_to_asks(&mut self, order: &LimitOrder) {
+    pub fn target_size(&self) -> i64 {
+        self.target_size
+    }
+
+    /// Order ids seen so far, resolves cache keys back to the feed's ids
+    pub fn ids(&self) -> &OrderIds {
+        &self.ids
+    }
+
+    fn _add_to_asks(&mut self, order: &LimitOrder) -> Result<(), BookError> {
+        let total = self
+            .asks_total_size
+            .checked_add(order.size)
+            .ok_or(BookError::Overflow)?;
         match self
             .asks
             .binary_search_by_key(&order.price, |&(price, _size)| price)
         {
             Ok(idx) => {
-                self.asks[idx].1 += order.size;
+                self.asks[idx].1 = self.asks[idx]
+                    .1
+                    .checked_add(order.size)
+                    .ok_or(BookError::Overflow)?;
             }
             Err(idx) => {
                 self.asks.insert(idx, (order.price, order.size));
             }
         }
-        self.asks_total_size += order.size;
+        self.asks_total_size = total;
+        Ok(())
     }
 
-    fn _add_to_bids(&mut self, order: &LimitOrder) {
+    fn _add_to_bids(&mut self, order: &LimitOrder) -> Result<(), BookError> {
+        let total = self
+            .bids_total_size
+            .checked_add(order.size)
+            .ok_or(BookError::Overflow)?;
         match self
             .bids
             .binary_search_by_key(&order.price.into(), |&(price, _size)| price)
         {
             Ok(idx) => {
-                self.bids[idx].1 += order.size;
+                self.bids[idx].1 = self.bids[idx]
+                    .1
+                    .checked_add(order.size)
+                    .ok_or(BookError::Overflow)?;
             }
             Err(idx) => {
                 self.bids.insert(idx, (order.price.into(), order.size));
             }
         }
-        self.bids_total_size += order.size;
+        self.bids_total_size = total;
+        Ok(())
     }
 
+    /// Panics if the order overflows a level or a side, see try_add to handle that
     pub fn add(&mut self, order: LimitOrder) {
+        if let Err(err) = self.try_add(order) {
+            panic!("{}", err);
+        }
+    }
+
+    pub fn try_add(&mut self, order: LimitOrder) -> Result<(), BookError> {
+        self.state_change = None;
         if order.side == OrderSide::Bid {
-            self._add_to_bids(&order);
+            self._add_to_bids(&order)?;
         } else if order.side == OrderSide::Ask {
-            self._add_to_asks(&order);
+            self._add_to_asks(&order)?;
         }
         self.cache.insert(&order);
+        if let Some(ref mut resting) = self.resting {
+            resting.added += 1;
+            resting
+                .orders
+                .entry(order.id)
+                .or_insert((resting.added, 0))
+                .1 += order.size;
+        }
         self.last_action_timestamp = order.timestamp;
         self.last_action_side = order.side;
+        // a normal book only locks or crosses when the order is at or through the other side
+        let reaches = match order.side {
+            OrderSide::Bid => self.best_ask().is_some_and(|(ask, _)| order.price >= ask),
+            OrderSide::Ask => self.best_bid().is_some_and(|(bid, _)| order.price <= bid),
+        };
+        if self.state != BookState::Normal || reaches {
+            self.update_state(order.timestamp);
+        }
+        Ok(())
     }
 
     pub fn reduce_order(&mut self, order: &ReduceOrder) {
+        if let Err(err) = self.try_reduce_order(order) {
+            panic!("{}", err);
+        }
+    }
+
+    pub fn try_reduce_order(&mut self, order: &ReduceOrder) -> Result<(), BookError> {
+        self.state_change = None;
         let (price, side) = match self.cache.get(&order.id) {
-            Some(tup) => (tup),
-            None => panic!("No order under key {}", &order.id),
+            Some(tup) => tup,
+            None => {
+                let id = match self.ids.original(order.id) {
+                    Some(id) => id.into_owned(),
+                    None => order.id.to_string(),
+                };
+                return Err(BookError::UnknownOrder(id));
+            }
         };
         if side == &OrderSide::Ask {
             if let Ok(idx) = self
                 .asks
                 .binary_search_by_key(price, |&(price, _size)| price)
             {
-                self.asks[idx].1 -= &order.size;
-                self.asks_total_size -= order.size;
+                reduce_depth(&mut self.asks[idx].1, &mut self.asks_total_size, order.size)?;
             }
         } else if side == &OrderSide::Bid {
             if let Ok(idx) = self
                 .bids
                 .binary_search_by_key(&price.into(), |&(price, _size)| price)
             {
-                self.bids[idx].1 -= &order.size;
-                self.bids_total_size -= order.size;
+                reduce_depth(&mut self.bids[idx].1, &mut self.bids_total_size, order.size)?;
             }
         }
         self.last_action_timestamp = order.timestamp;
         self.last_action_side = *side;
+        if let Some(ref mut resting) = self.resting {
+            let gone = match resting.orders.get_mut(&order.id) {
+                Some(&mut (_, ref mut size)) => {
+                    *size -= order.size;
+                    *size <= 0
+                }
+                None => false,
+            };
+            // nothing left to reduce, so the cache only holds live orders
+            if gone {
+                resting.orders.remove(&order.id);
+                self.cache.remove(&order.id);
+            }
+        }
+        // taking liquidity away can't cross a book that wasn't crossed
+        if self.state != BookState::Normal {
+            self.update_state(order.timestamp);
+        }
+        Ok(())
+    }
+
+    /// Writes an aggregated level update straight into asks or bids, the cache isn't involved.
+    /// Meant for market by price feeds, mixed with orders on the same book
+    /// a level would lose the depth its orders added up to.
+    /// Panics if the side's total overflows, see try_set_level to handle that
+    pub fn set_level(&mut self, update: &LevelUpdate) {
+        if let Err(err) = self.try_set_level(update) {
+            panic!("{}", err);
+        }
+    }
+
+    pub fn try_set_level(&mut self, update: &LevelUpdate) -> Result<(), BookError> {
+        self.state_change = None;
+        let depth = update.depth();
+        match update.side() {
+            OrderSide::Ask => set_depth(
+                &mut self.asks,
+                &mut self.asks_total_size,
+                update.price(),
+                depth,
+            )?,
+            OrderSide::Bid => set_depth(
+                &mut self.bids,
+                &mut self.bids_total_size,
+                update.price().into(),
+                depth,
+            )?,
+        }
+        self.last_action_timestamp = update.timestamp();
+        self.last_action_side = update.side();
+        self.update_state(update.timestamp());
+        Ok(())
+    }
+
+    fn update_state(&mut self, timestamp: i64) {
+        let state = match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) if bid > ask => BookState::Crossed,
+            (Some((bid, _)), Some((ask, _))) if bid == ask => BookState::Locked,
+            _ => BookState::Normal,
+        };
+        if state != self.state {
+            self.state_change = Some(StateChange {
+                timestamp,
+                from: self.state,
+                to: state,
+            });
+            self.state = state;
+        }
+        if state == BookState::Normal {
+            if let Some(mut episode) = self.episode.take() {
+                episode.cleared_at = Some(timestamp);
+                self.cleared_episodes += 1;
+                self.cleared_duration = self.cleared_duration.saturating_add(episode.duration());
+                if self.episodes.len() == RECENT_EPISODES {
+                    self.episodes.pop_front();
+                }
+                self.episodes.push_back(episode);
+            }
+            return;
+        }
+        let episode = self.episode.get_or_insert(CrossedEpisode {
+            first_timestamp: timestamp,
+            last_timestamp: timestamp,
+            cleared_at: None,
+            worst: state,
+        });
+        episode.last_timestamp = timestamp;
+        episode.worst = max(episode.worst, state);
+    }
+
+    /// Whether the book is locked or crossed after the last message
+    pub fn state(&self) -> BookState {
+        self.state
+    }
+
+    /// Set when the last message locked, crossed or cleared the book
+    pub fn state_change(&self) -> Option<StateChange> {
+        self.state_change
+    }
+
+    /// The last RECENT_EPISODES locked or crossed episodes the book has come out of,
+    /// oldest first
+    pub fn episodes(&self) -> &VecDeque<CrossedEpisode> {
+        &self.episodes
+    }
+
+    /// How many episodes the book has come out of, including those episodes() let go
+    pub fn cleared_episodes(&self) -> u64 {
+        self.cleared_episodes
+    }
+
+    /// Summed duration of every cleared episode, saturating
+    pub fn cleared_duration(&self) -> i64 {
+        self.cleared_duration
+    }
+
+    /// The episode the book is in the middle of, if any
+    pub fn current_episode(&self) -> Option<&CrossedEpisode> {
+        self.episode.as_ref()
     }
 
     pub fn summarise_target(&self) -> Option<Amount> {
+        self.summarise(self.target_size)
+    }
+
+    /// Same as summarise_target, for any target size
+    pub fn summarise(&self, target_size: i64) -> Option<Amount> {
         /*
         Summarises income gained from selling self.target_size of shares or expense of buying self.target_size shares. If last side is Bid/Buy - we need to summarise
 
@@ -135,22 +472,35 @@ impl<T: IdPriceCache + Sized> OrderBook<T> {
         Every add_order and reduce order api call need to update the last_action_side.
 
         Returning None, means there aren't enough bids to sell to
-        or asks to buy.
+        or asks to buy, or that the amount doesn't fit in an i64.
 
          */
-        if self.bids_total_size >= self.target_size && self.last_action_side == OrderSide::Bid {
-            return Some(self.summarise_amount_from_bids());
-        } else if self.asks_total_size >= self.target_size
-            && self.last_action_side == OrderSide::Ask
-        {
-            return Some(self.summarise_amount_from_asks());
+        if self.bids_total_size >= target_size && self.last_action_side == OrderSide::Bid {
+            return self.summarise_amount_from_bids(target_size);
+        } else if self.asks_total_size >= target_size && self.last_action_side == OrderSide::Ask {
+            return self.summarise_amount_from_asks(target_size);
         }
         None
     }
 
-    fn summarise_amount_from_asks(&self) -> Amount {
+    /// Same as summarise, for the given side whatever the last action was:
+    /// Bid is the income from selling into the bids, Ask the expense of buying the asks
+    pub fn summarise_side(&self, side: OrderSide, target_size: i64) -> Option<Amount> {
+        match side {
+            OrderSide::Bid if self.bids_total_size >= target_size => {
+                self.summarise_amount_from_bids(target_size)
+            }
+            OrderSide::Ask if self.asks_total_size >= target_size => {
+                self.summarise_amount_from_asks(target_size)
+            }
+            _ => None,
+        }
+    }
+
+    // None if the amount overflows, past MAX_SIZE shares or on levels over-reduced by billions
+    fn summarise_amount_from_asks(&self, target_size: i64) -> Option<Amount> {
         let mut res = Amount::new();
-        let mut target_left = self.target_size;
+        let mut target_left = target_size;
         for (price, depth) in self.asks.iter() {
             if target_left <= 0 {
                 break;
@@ -160,16 +510,16 @@ impl<T: IdPriceCache + Sized> OrderBook<T> {
             }
             let available_in_this_bucket = min(*depth, target_left);
 
-            res += *price * available_in_this_bucket;
-            target_left -= available_in_this_bucket;
+            res = res.checked_add(price.checked_mul(available_in_this_bucket)?)?;
+            target_left = target_left.checked_sub(available_in_this_bucket)?;
         }
 
-        res
+        Some(res)
     }
 
-    fn summarise_amount_from_bids(&self) -> Amount {
+    fn summarise_amount_from_bids(&self, target_size: i64) -> Option<Amount> {
         let mut res = BidAmount::new();
-        let mut target_left = self.target_size;
+        let mut target_left = target_size;
         for (price, depth) in self.bids.iter() {
             if target_left <= 0 {
                 break;
@@ -179,28 +529,213 @@ impl<T: IdPriceCache + Sized> OrderBook<T> {
             }
             let available_in_this_bucket = min(*depth, target_left);
 
-            res += *price * available_in_this_bucket;
-            target_left -= available_in_this_bucket;
+            res = res.checked_add(price.checked_mul(available_in_this_bucket)?)?;
+            target_left = target_left.checked_sub(available_in_this_bucket)?;
         }
-        res.into()
+        Some(res.into())
     }
 
+    /// Panics on malformed lines and unknown orders, see try_process to handle them
     pub fn process(&mut self, instruction: &str) {
-        let order_vec: Vec<&str> = instruction.trim().split(' ').collect();
-        if order_vec[1] == "A" {
-            self.add(LimitOrder::new(&order_vec));
-        } else if order_vec[1] == "R" {
-            self.reduce_order(&ReduceOrder::new(&order_vec));
-        } else {
-            eprintln!("Error processing {}", instruction);
+        match self.try_process(instruction) {
+            Ok(()) => {}
+            Err(BookError::Parse(ParseError::UnknownMessage(_))) => {
+                eprintln!("Error processing {}", instruction)
+            }
+            Err(err) => panic!("Error processing {}: {}", instruction, err),
+        }
+    }
+
+    pub fn try_process(&mut self, instruction: &str) -> Result<(), BookError> {
+        let (message, seq) = Message::parse_with_seq(instruction)?;
+        self.apply_sequenced(&message, seq)
+    }
+
+    /// Checks the message's sequence number, or its timestamp when it has none,
+    /// before applying it. What happens to messages out of place is up to the
+    /// tracker's policy, duplicates are never applied
+    pub fn apply_sequenced(
+        &mut self,
+        message: &Message,
+        seq: Option<u64>,
+    ) -> Result<(), BookError> {
+        match self.sequence.check(seq, message.timestamp()) {
+            Ok(SeqVerdict::Apply) => self.apply(message),
+            Ok(SeqVerdict::Drop) => {
+                self.state_change = None;
+                Ok(())
+            }
+            Err(issue) => Err(BookError::Sequence(issue)),
+        }
+    }
+
+    /// apply_sequenced for a level update
+    pub fn apply_level_sequenced(
+        &mut self,
+        update: &LevelUpdate,
+        seq: Option<u64>,
+    ) -> Result<(), BookError> {
+        match self.sequence.check(seq, update.timestamp()) {
+            Ok(SeqVerdict::Apply) => self.try_set_level(update),
+            Ok(SeqVerdict::Drop) => {
+                self.state_change = None;
+                Ok(())
+            }
+            Err(issue) => Err(BookError::Sequence(issue)),
+        }
+    }
+
+    /// Keeps the size left on every order added from now on, see resting_orders.
+    /// Orders reduced to nothing are dropped from the cache, reducing them again
+    /// is an UnknownOrder rather than taking the level below what is resting there
+    pub fn track_orders(&mut self) {
+        self.resting.get_or_insert_with(RestingOrders::default);
+    }
+
+    /// Orders with something left on side as (price, size left, cache key),
+    /// best price first and in the order they came in at each price.
+    /// Empty unless track_orders was called, resolve the keys through ids()
+    pub fn resting_orders(&self, side: OrderSide) -> Vec<(Amount, Depth, u64)> {
+        let resting = match self.resting {
+            Some(ref resting) => resting,
+            None => return Vec::new(),
+        };
+        let mut orders: Vec<_> = resting
+            .orders
+            .iter()
+            .filter_map(|(key, &(added, size))| match self.cache.get(key) {
+                Some(&(price, order_side)) if order_side == side => {
+                    Some((price, added, size, *key))
+                }
+                _ => None,
+            })
+            .collect();
+        match side {
+            OrderSide::Ask => orders.sort_by_key(|&(price, added, _, _)| (price, added)),
+            OrderSide::Bid => {
+                orders.sort_by_key(|&(price, added, _, _)| (BidAmount::from(price), added))
+            }
+        }
+        orders
+            .into_iter()
+            .map(|(price, _, size, key)| (price, size, key))
+            .collect()
+    }
+
+    /// Sequence numbers and timestamps seen through apply_sequenced and try_process
+    pub fn sequence(&self) -> &SequenceTracker {
+        &self.sequence
+    }
+
+    /// To change the policy or set up the snapshot request callback
+    pub fn sequence_mut(&mut self) -> &mut SequenceTracker {
+        &mut self.sequence
+    }
+
+    /// Resolves the message's id and applies it to the book, no sequence checks
+    pub fn apply(&mut self, message: &Message) -> Result<(), BookError> {
+        match *message {
+            Message::Add {
+                timestamp,
+                id,
+                side,
+                price,
+                size,
+            } => {
+                let id = self.ids.try_insert(id).map_err(BookError::IdCollision)?;
+                self.try_add(LimitOrder {
+                    timestamp,
+                    id,
+                    side,
+                    price,
+                    size,
+                })
+            }
+            Message::Reduce {
+                timestamp,
+                id,
+                size,
+            } => {
+                let id = match self.ids.key(id) {
+                    Some(key) => key,
+                    None => return Err(BookError::UnknownOrder(id.to_string())),
+                };
+                self.try_reduce_order(&ReduceOrder {
+                    timestamp,
+                    id,
+                    size,
+                })
+            }
+        }
+    }
+
+    /// Highest bid still resting, with its depth
+    pub fn best_bid(&self) -> Option<(Amount, Depth)> {
+        self.bids
+            .iter()
+            .find(|&&(_, depth)| depth != 0)
+            .map(|&(price, depth)| (price.into(), depth))
+    }
+
+    /// Lowest ask still resting, with its depth
+    pub fn best_ask(&self) -> Option<(Amount, Depth)> {
+        self.asks.iter().find(|&&(_, depth)| depth != 0).cloned()
+    }
+
+    /// Price levels with something resting on them, best price first
+    pub fn levels<'a>(&'a self, side: OrderSide) -> Box<dyn Iterator<Item = (Amount, Depth)> + 'a> {
+        match side {
+            OrderSide::Ask => Box::new(
+                self.asks
+                    .iter()
+                    .filter(|&&(_, depth)| depth != 0)
+                    .map(|&(price, depth)| (price, depth)),
+            ),
+            OrderSide::Bid => Box::new(
+                self.bids
+                    .iter()
+                    .filter(|&&(_, depth)| depth != 0)
+                    .map(|&(price, depth)| (price.into(), depth)),
+            ),
         }
     }
+
+    /// How many price levels a side holds, including emptied ones
+    /// the book keeps around for when the price comes back
+    pub fn allocated_levels(&self, side: OrderSide) -> usize {
+        match side {
+            OrderSide::Ask => self.asks.len(),
+            OrderSide::Bid => self.bids.len(),
+        }
+    }
+
+    pub fn total_size(&self, side: OrderSide) -> i64 {
+        match side {
+            OrderSide::Ask => self.asks_total_size,
+            OrderSide::Bid => self.bids_total_size,
+        }
+    }
+
+    pub fn cache(&self) -> &T {
+        &self.cache
+    }
+
+    /// Price and side cached for the order id, None if the book never saw it.
+    /// Fully reduced orders stay cached, like the book itself remembers them,
+    /// unless track_orders is on
+    pub fn order(&self, id: &str) -> Option<(Amount, OrderSide)> {
+        self.ids
+            .key(id)
+            .and_then(|key| self.cache.get(&key).cloned())
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
-    use orders::hash;
+    use idpricecache::IdPriceCacheFnvMap;
+    use orders::{hash, MAX_SIZE};
 
     #[test]
     fn orderbook_constructor_works() {
@@ -352,6 +887,165 @@ mod tests {
         ob.process("28800796 R d 157");
     }
 
+    #[test]
+    fn run_through_basic_interned() {
+        let target_size = 200;
+        let mut ob =
+            OrderBook::with_id_mode(target_size, IdPriceCacheFnvMap::default(), IdMode::Intern);
+        ob.process("28800538 A b S 44.26 100");
+        ob.process("28800562 A c B 44.10 100");
+        ob.process("28800744 R b 100");
+        ob.process("28800758 A d B 44.18 157");
+        assert_eq!(ob.asks_total_size, 0);
+        assert_eq!(ob.bids_total_size, 257);
+        assert!(ob.cache.contains_key(&0));
+        assert!(ob.cache.contains_key(&2));
+        assert_eq!(ob.ids().original(2).as_deref(), Some("d"));
+        assert_eq!(ob.summarise_target(), Some(Amount::new_from_str("8832.56")));
+    }
+
+    #[test]
+    fn summarise_other_target_sizes() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.process("28800538 A b B 44.26 100");
+        ob.process("28800986 A c B 44.07 500");
+        assert_eq!(ob.summarise(100), Some(Amount::new_from_str("4426.00")));
+        assert_eq!(ob.summarise(600), Some(Amount::new_from_str("26461.00")));
+        assert_eq!(ob.summarise(601), None);
+    }
+
+    #[test]
+    fn summarise_either_side_and_look_up_orders() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.process("28800538 A b B 44.26 100");
+        ob.process("28800562 A c S 44.30 100");
+        assert_eq!(
+            ob.summarise_side(OrderSide::Bid, 100),
+            Some(Amount::new_from_str("4426.00"))
+        );
+        assert_eq!(
+            ob.summarise_side(OrderSide::Ask, 50),
+            Some(Amount::new_from_str("2215.00"))
+        );
+        assert_eq!(ob.summarise_side(OrderSide::Ask, 101), None);
+        assert_eq!(
+            ob.order("b"),
+            Some((Amount::new_from_str("44.26"), OrderSide::Bid))
+        );
+        assert_eq!(ob.order("z").map(|(_, side)| side), None);
+    }
+
+    #[test]
+    fn try_process_reports_errors() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        assert_eq!(
+            ob.try_process("28800538 X b B 44.26 100"),
+            Err(BookError::Parse(ParseError::UnknownMessage(
+                "X".to_string()
+            )))
+        );
+        assert_eq!(
+            ob.try_process("28800538"),
+            Err(BookError::Parse(ParseError::UnknownMessage(String::new())))
+        );
+        assert_eq!(
+            ob.try_process("28800538 A b B 44.26"),
+            Err(BookError::Parse(ParseError::WrongFieldCount {
+                expected: 6,
+                found: 5
+            }))
+        );
+        assert_eq!(
+            ob.try_process("28800744 R b 20"),
+            Err(BookError::UnknownOrder(hash("b").to_string()))
+        );
+        // nothing above touched the book
+        assert_eq!(ob.bids_total_size, 0);
+        assert_eq!(ob.asks_total_size, 0);
+    }
+
+    #[test]
+    fn try_process_unknown_interned_id() {
+        let mut ob = OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), IdMode::Intern);
+        assert_eq!(
+            ob.try_process("28800744 R b 20"),
+            Err(BookError::UnknownOrder("b".to_string()))
+        );
+        // malformed adds don't intern their id
+        assert!(ob.try_process("28800538 A b B 44.26 lots").is_err());
+        assert!(ob.ids().is_empty());
+    }
+
+    #[test]
+    fn level_capacity_is_preallocated() {
+        let ob = OrderBook::with_capacity(200, IdPriceCacheFnvMap::default(), IdMode::Hash, 1000);
+        assert!(ob.asks.capacity() >= 1000);
+        assert!(ob.bids.capacity() >= 1000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reduce_unknown_interned_id_panics() {
+        let mut ob = OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), IdMode::Intern);
+        ob.process("28800744 R b 100");
+    }
+
+    #[test]
+    fn checked_ids_keep_originals() {
+        let mut ob =
+            OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), IdMode::CheckedHash);
+        ob.process("28800538 A b S 44.26 100");
+        ob.process("28800744 R b 20");
+        assert_eq!(ob.ids().original(hash("b")).as_deref(), Some("b"));
+        assert!(ob.ids().collisions().is_empty());
+    }
+
+    #[test]
+    fn resting_orders_keep_their_ids() {
+        let mut ob = OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), IdMode::Intern);
+        ob.process("28800530 A a S 44.30 50");
+        ob.track_orders();
+        for line in include_str!("../data/basic.in.txt").lines().take(6) {
+            ob.process(line);
+        }
+        ob.process("28800800 A f B 44.18 20");
+        ob.process("28800801 A g S 44.26 30");
+        ob.process("28800802 A h S 44.38 10");
+        let listed = |side| -> Vec<(String, i64, String)> {
+            ob.resting_orders(side)
+                .into_iter()
+                .map(|(price, size, key)| {
+                    let id = ob.ids().original(key).unwrap().into_owned();
+                    (price.to_string(), size, id)
+                })
+                .collect()
+        };
+        // a came in before tracking started, b and d were reduced to nothing
+        assert_eq!(
+            listed(OrderSide::Ask),
+            vec![
+                ("44.26".to_string(), 30, "g".to_string()),
+                ("44.38".to_string(), 100, "e".to_string()),
+                ("44.38".to_string(), 10, "h".to_string()),
+            ]
+        );
+        assert_eq!(
+            listed(OrderSide::Bid),
+            vec![
+                ("44.18".to_string(), 20, "f".to_string()),
+                ("44.10".to_string(), 100, "c".to_string()),
+            ]
+        );
+        // reduced to nothing, so they left the cache
+        assert_eq!(ob.order("b"), None);
+        assert_eq!(ob.order("d"), None);
+        assert_eq!(ob.cache().len(), 6);
+        assert_eq!(
+            ob.try_process("28800803 R b 10"),
+            Err(BookError::UnknownOrder("b".to_string()))
+        );
+    }
+
     #[test]
     fn prices_vec_with_capacity() {
         let vec: AsksVec = AsksVec::with_capacity(10);
@@ -398,4 +1092,416 @@ mod tests {
         assert_eq!(idx, Err(4));
     }
 
+    #[test]
+    fn best_prices_and_levels() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        assert_eq!(ob.best_bid(), None);
+        assert_eq!(ob.best_ask(), None);
+        ob.process("28800538 A b S 44.26 100");
+        ob.process("28800539 A c S 44.30 50");
+        ob.process("28800540 A d B 44.10 100");
+        ob.process("28800541 A e B 44.18 157");
+        assert_eq!(ob.best_ask(), Some((Amount::new_from_str("44.26"), 100)));
+        assert_eq!(ob.best_bid(), Some((Amount::new_from_str("44.18"), 157)));
+
+        // emptied levels stay allocated but aren't listed
+        ob.process("28800542 R b 100");
+        ob.process("28800543 R e 157");
+        assert_eq!(ob.best_ask(), Some((Amount::new_from_str("44.30"), 50)));
+        assert_eq!(ob.best_bid(), Some((Amount::new_from_str("44.10"), 100)));
+        assert_eq!(ob.levels(OrderSide::Ask).count(), 1);
+        assert_eq!(ob.allocated_levels(OrderSide::Ask), 2);
+        assert_eq!(ob.allocated_levels(OrderSide::Bid), 2);
+        assert_eq!(ob.total_size(OrderSide::Bid), 100);
+        assert_eq!(ob.cache().len(), 4);
+    }
+
+    #[test]
+    fn apply_parsed_message() {
+        let mut ob = OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), IdMode::Intern);
+        ob.apply(&Message::parse("28800538 A b S 44.26 100").unwrap())
+            .unwrap();
+        ob.apply(&Message::Reduce {
+            timestamp: 28800744,
+            id: "b",
+            size: 20,
+        })
+        .unwrap();
+        assert_eq!(ob.asks_total_size, 80);
+        assert_eq!(ob.last_action_timestamp, 28800744);
+        assert_eq!(
+            ob.apply(&Message::Reduce {
+                timestamp: 28800745,
+                id: "x",
+                size: 20,
+            }),
+            Err(BookError::UnknownOrder("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn detects_locked_and_crossed() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.process("28800538 A b S 44.26 100");
+        ob.process("28800539 A c B 44.10 100");
+        assert_eq!(ob.state(), BookState::Normal);
+        assert_eq!(ob.state_change(), None);
+
+        ob.process("28800540 A d B 44.26 100");
+        assert_eq!(ob.state(), BookState::Locked);
+        assert_eq!(
+            ob.state_change(),
+            Some(StateChange {
+                timestamp: 28800540,
+                from: BookState::Normal,
+                to: BookState::Locked
+            })
+        );
+
+        ob.process("28800541 A e B 44.30 100");
+        assert_eq!(ob.state(), BookState::Crossed);
+        assert_eq!(
+            ob.state_change().map(|change| change.from),
+            Some(BookState::Locked)
+        );
+
+        // still crossed, no change to report
+        ob.process("28800542 R c 100");
+        assert_eq!(ob.state(), BookState::Crossed);
+        assert_eq!(ob.state_change(), None);
+
+        ob.process("28800550 R b 100");
+        assert_eq!(ob.state(), BookState::Normal);
+        assert_eq!(
+            ob.state_change(),
+            Some(StateChange {
+                timestamp: 28800550,
+                from: BookState::Crossed,
+                to: BookState::Normal
+            })
+        );
+    }
+
+    #[test]
+    fn episode_duration_from_wild_timestamps() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.process("-9223372036854775808 A a S 10 100");
+        ob.process("-9223372036854775807 A b B 11 100");
+        ob.process("9223372036854775807 R b 100");
+        assert_eq!(ob.episodes()[0].duration(), i64::MAX);
+    }
+
+    #[test]
+    fn amounts_and_depths_that_overflow() {
+        // 10^10 shares at 10^9 cents on one level, pricing all of them overflows
+        let mut ob = OrderBook::new(10_000_000_000, IdPriceCacheFnvMap::default());
+        for n in 0..10 {
+            ob.process(&format!("{} A o{} S 10000000.00 1000000000", n, n));
+        }
+        assert_eq!(ob.summarise_target(), None);
+        assert_eq!(
+            ob.summarise_side(OrderSide::Ask, MAX_SIZE),
+            Some(Amount {
+                as_int: 1_000_000_000_000_000_000
+            })
+        );
+
+        let order = |id, size| LimitOrder {
+            timestamp: 1,
+            id,
+            side: OrderSide::Bid,
+            price: Amount::new_from_str("1.00"),
+            size,
+        };
+        ob.add(order(1, i64::MAX));
+        assert_eq!(ob.try_add(order(2, 1)), Err(BookError::Overflow));
+        assert_eq!(ob.total_size(OrderSide::Bid), i64::MAX);
+        assert_eq!(
+            ob.best_bid(),
+            Some((Amount::new_from_str("1.00"), i64::MAX))
+        );
+        let reduce = ReduceOrder {
+            timestamp: 2,
+            id: 1,
+            size: i64::MAX,
+        };
+        ob.reduce_order(&reduce);
+        ob.reduce_order(&reduce);
+        assert_eq!(
+            ob.try_reduce_order(&ReduceOrder { size: 2, ..reduce }),
+            Err(BookError::Overflow)
+        );
+        assert_eq!(ob.total_size(OrderSide::Bid), -i64::MAX);
+        let update = LevelUpdate::parse("3 L B 1.00 5").unwrap();
+        assert_eq!(ob.try_set_level(&update), Ok(()));
+        assert_eq!(ob.total_size(OrderSide::Bid), 5);
+    }
+
+    #[test]
+    fn records_crossed_episodes() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.process("28800538 A b S 44.26 100");
+        ob.process("28800540 A c B 44.26 100");
+        ob.process("28800545 A d B 44.30 100");
+        assert_eq!(
+            ob.current_episode(),
+            Some(&CrossedEpisode {
+                first_timestamp: 28800540,
+                last_timestamp: 28800545,
+                cleared_at: None,
+                worst: BookState::Crossed,
+            })
+        );
+        assert_eq!(ob.current_episode().unwrap().duration(), 5);
+        assert!(ob.episodes().is_empty());
+
+        ob.process("28800550 R b 100");
+        assert_eq!(ob.current_episode(), None);
+        assert_eq!(ob.episodes().len(), 1);
+        assert_eq!(ob.episodes()[0].cleared_at, Some(28800550));
+        assert_eq!(ob.episodes()[0].duration(), 10);
+
+        // a second, locked only, episode
+        ob.process("28800560 A e S 44.30 10");
+        ob.process("28800561 A f S 44.40 10");
+        ob.process("28800570 R e 10");
+        assert_eq!(
+            ob.episodes()[1],
+            CrossedEpisode {
+                first_timestamp: 28800560,
+                last_timestamp: 28800561,
+                cleared_at: Some(28800570),
+                worst: BookState::Locked,
+            }
+        );
+        assert_eq!(BookState::Locked.to_string(), "locked");
+    }
+
+    #[test]
+    fn only_recent_episodes_are_kept() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.process("0 A a S 44.26 100");
+        for n in 0..RECENT_EPISODES + 10 {
+            let at = n as i64 * 10;
+            ob.process(&format!("{} A b{} B 44.30 100", at + 1, n));
+            ob.process(&format!("{} R b{} 100", at + 4, n));
+        }
+        assert_eq!(ob.episodes().len(), RECENT_EPISODES);
+        assert_eq!(ob.episodes()[0].first_timestamp, 101);
+        assert_eq!(ob.cleared_episodes(), RECENT_EPISODES as u64 + 10);
+        assert_eq!(ob.cleared_duration(), 3 * (RECENT_EPISODES as i64 + 10));
+    }
+
+    #[test]
+    fn basic_feed_never_crosses() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        for line in include_str!("../data/basic.in.txt").lines() {
+            ob.process(line);
+            assert_eq!(ob.state(), BookState::Normal);
+        }
+        assert!(ob.episodes().is_empty());
+    }
+
+    #[test]
+    fn sequenced_lines() {
+        use sequence::SeqPolicy;
+
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.try_process("28800538 A b S 44.26 100 1").unwrap();
+        ob.try_process("28800539 A c S 44.26 100 3").unwrap();
+        assert_eq!(
+            ob.sequence().last_issue(),
+            Some(SeqIssue::Gap {
+                expected: 2,
+                found: 3
+            })
+        );
+        // duplicates leave the book alone
+        ob.try_process("28800539 A c S 44.26 100 3").unwrap();
+        assert_eq!(ob.asks_total_size, 200);
+
+        ob.sequence_mut().set_policy(SeqPolicy::Halt);
+        assert_eq!(
+            ob.try_process("28800540 R b 10 3"),
+            Err(BookError::Sequence(SeqIssue::Duplicate(3)))
+        );
+        ob.try_process("28800540 R b 10 4").unwrap();
+        assert_eq!(ob.asks_total_size, 190);
+    }
+
+    #[test]
+    fn level_updates() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        for line in &[
+            "28800538 L S 44.26 100",
+            "28800539 L S 44.30 300",
+            "28800540 L B 44.10 50",
+            "28800541 L S 44.26 40", // replaces, doesn't add
+        ] {
+            ob.set_level(&LevelUpdate::parse(line).unwrap());
+        }
+        assert_eq!(ob.asks_total_size, 340);
+        assert_eq!(ob.best_ask(), Some((Amount::new_from_str("44.26"), 40)));
+        assert_eq!(ob.best_bid(), Some((Amount::new_from_str("44.10"), 50)));
+        assert_eq!(ob.summarise_target(), Some(Amount::new_from_str("8858.40")));
+        assert!(ob.cache().is_empty());
+
+        ob.set_level(&LevelUpdate::parse("28800542 D S 44.26").unwrap());
+        assert_eq!(ob.asks_total_size, 300);
+        assert_eq!(ob.best_ask(), Some((Amount::new_from_str("44.30"), 300)));
+        // deleting a level that isn't there changes nothing
+        ob.set_level(&LevelUpdate::parse("28800543 D B 43.00").unwrap());
+        assert_eq!(ob.bids_total_size, 50);
+        assert_eq!(ob.allocated_levels(OrderSide::Bid), 1);
+
+        ob.set_level(&LevelUpdate::parse("28800544 L B 44.30 10").unwrap());
+        assert_eq!(ob.state(), BookState::Locked);
+    }
+
+    #[test]
+    fn timestamps_going_back() {
+        let mut ob = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        ob.process("28800538 A b S 44.26 100");
+        ob.process("28800530 A c S 44.26 100");
+        assert_eq!(
+            ob.sequence().last_issue(),
+            Some(SeqIssue::TimestampWentBack {
+                previous: 28800538,
+                found: 28800530
+            })
+        );
+        // still applied under the default policy
+        assert_eq!(ob.asks_total_size, 200);
+    }
+}
+
+// Invariants that have to hold after any run of valid adds and reduces
+#[cfg(test)]
+mod properties {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use proptest::prelude::*;
+
+    struct Live {
+        id: String,
+        side: OrderSide,
+        price: Amount,
+        left: i64,
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Add { bid: bool, ticks: i64, size: i64 },
+        // pick chooses among the live orders, reduces are skipped while there are none
+        Reduce { pick: usize, full: bool, size: i64 },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (any::<bool>(), 1..40i64, 1..50i64).prop_map(|(bid, ticks, size)| Op::Add {
+                bid,
+                ticks,
+                size
+            }),
+            (any::<usize>(), any::<bool>(), 1..50i64).prop_map(|(pick, full, size)| Op::Reduce {
+                pick,
+                full,
+                size
+            }),
+        ]
+    }
+
+    // every share priced on its own, best first, the first target_size of them summed
+    fn brute_force(live: &[Live], side: OrderSide, target_size: i64) -> Option<Amount> {
+        let mut shares: Vec<Amount> = live
+            .iter()
+            .filter(|order| order.side == side)
+            .flat_map(|order| (0..order.left).map(move |_| order.price))
+            .collect();
+        shares.sort();
+        if side == OrderSide::Bid {
+            shares.reverse();
+        }
+        if (shares.len() as i64) < target_size {
+            return None;
+        }
+        let mut amount = Amount::new();
+        for &price in shares.iter().take(target_size as usize) {
+            amount += price;
+        }
+        Some(amount)
+    }
+
+    fn check_invariants(ob: &OrderBook<IdPriceCacheFnvMap>) -> Result<(), TestCaseError> {
+        prop_assert!(ob.asks.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        prop_assert!(ob
+            .bids
+            .windows(2)
+            .all(|pair| Amount::from(pair[0].0) > Amount::from(pair[1].0)));
+        prop_assert_eq!(
+            ob.asks_total_size,
+            ob.asks.iter().map(|&(_, depth)| depth).sum::<Depth>()
+        );
+        prop_assert_eq!(
+            ob.bids_total_size,
+            ob.bids.iter().map(|&(_, depth)| depth).sum::<Depth>()
+        );
+        prop_assert!(ob.asks.iter().all(|&(_, depth)| depth >= 0));
+        prop_assert!(ob.bids.iter().all(|&(_, depth)| depth >= 0));
+        for &(price, side) in ob.cache.values() {
+            let found = match side {
+                OrderSide::Ask => ob.asks.binary_search_by_key(&price, |&(p, _)| p).is_ok(),
+                OrderSide::Bid => ob
+                    .bids
+                    .binary_search_by_key(&price.into(), |&(p, _)| p)
+                    .is_ok(),
+            };
+            prop_assert!(found, "cached {} {:?} has no level", price, side);
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn invariants_hold_after_valid_messages(
+            ops in prop::collection::vec(op(), 0..150),
+            target_size in 1..300i64,
+        ) {
+            let mut ob = OrderBook::new(target_size, IdPriceCacheFnvMap::default());
+            let mut live: Vec<Live> = Vec::new();
+            let mut last_side = OrderSide::Ask;
+            for (n, op) in ops.into_iter().enumerate() {
+                let timestamp = n as i64;
+                match op {
+                    Op::Add { bid, ticks, size } => {
+                        let side = if bid { OrderSide::Bid } else { OrderSide::Ask };
+                        let id = format!("o{}", n);
+                        let price = Amount { as_int: 4400 + ticks * 5 };
+                        ob.apply(&Message::Add { timestamp, id: &id, side, price, size }).unwrap();
+                        live.push(Live { id, side, price, left: size });
+                        last_side = side;
+                    }
+                    Op::Reduce { .. } if live.is_empty() => {}
+                    Op::Reduce { pick, full, size } => {
+                        let idx = pick % live.len();
+                        let size = if full { live[idx].left } else { 1 + size % live[idx].left };
+                        ob.apply(&Message::Reduce { timestamp, id: &live[idx].id, size }).unwrap();
+                        live[idx].left -= size;
+                        last_side = live[idx].side;
+                        if live[idx].left == 0 {
+                            live.swap_remove(idx);
+                        }
+                    }
+                }
+                check_invariants(&ob)?;
+                prop_assert_eq!(ob.summarise_target(), brute_force(&live, last_side, target_size));
+                for &side in &[OrderSide::Bid, OrderSide::Ask] {
+                    prop_assert_eq!(
+                        ob.summarise_side(side, target_size),
+                        brute_force(&live, side, target_size)
+                    );
+                }
+            }
+        }
+    }
 }
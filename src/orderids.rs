@@ -0,0 +1,348 @@
+use std::borrow::Cow;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use fnv::FnvHashMap;
+
+use orders::hash;
+
+/// How string order ids are turned into the u64 keys the book works with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IdMode {
+    /// FNV hash of the id. Fastest, but ids that collide silently share a key
+    Hash,
+    /// FNV hash, keeping the original id bytes. An order whose id collides is refused
+    CheckedHash,
+    /// Dense handles given out in order of first appearance. Never collides
+    Intern,
+    /// Purely numeric ids are used as the key directly, anything else is interned.
+    /// Pair it with IdPriceCacheIdentityMap to skip hashing altogether
+    Numeric,
+}
+
+impl FromStr for IdMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(IdMode::Hash),
+            "checked" => Ok(IdMode::CheckedHash),
+            "intern" => Ok(IdMode::Intern),
+            "numeric" => Ok(IdMode::Numeric),
+            other => Err(format!(
+                "Unknown id mode {}, expected one of hash, checked, intern, numeric",
+                other
+            )),
+        }
+    }
+}
+
+// Interned handles in Numeric mode live above every numeric id
+const INTERNED_BIT: u64 = 1 << 63;
+
+/// Parses ids made only of digits, without leading zeros so "7" and "007" don't share a key
+fn numeric_id(id: &str) -> Option<u64> {
+    let bytes = id.as_bytes();
+    if bytes.is_empty() || bytes.len() > 19 || (bytes[0] == b'0' && bytes.len() > 1) {
+        return None;
+    }
+    let mut res: u64 = 0;
+    for &byte in bytes {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        res = res * 10 + u64::from(byte - b'0');
+    }
+    if res & INTERNED_BIT != 0 {
+        return None;
+    }
+    Some(res)
+}
+
+/// Two different order ids that ended up under the same key
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IdCollision {
+    pub key: u64,
+    pub existing: String,
+    pub incoming: String,
+}
+
+impl Display for IdCollision {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "order ids {} and {} both map to key {}",
+            self.existing, self.incoming, self.key
+        )
+    }
+}
+
+/// Maps order ids from the feed to cache keys.
+/// Outside of Hash mode the original ids are kept, so they can be printed again.
+/// Ids are never forgotten, because the book doesn't know when an order is fully reduced.
+pub struct OrderIds {
+    mode: IdMode,
+    // Intern: id -> handle, handle indexes into names
+    handles: FnvHashMap<String, u64>,
+    names: Vec<String>,
+    // CheckedHash: hash -> first id seen under it
+    originals: FnvHashMap<u64, String>,
+    collisions: Vec<IdCollision>,
+}
+
+impl OrderIds {
+    pub fn new(mode: IdMode) -> Self {
+        OrderIds {
+            mode,
+            handles: FnvHashMap::default(),
+            names: Vec::new(),
+            originals: FnvHashMap::default(),
+            collisions: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> IdMode {
+        self.mode
+    }
+
+    /// Returns the key for the id of a newly added order.
+    /// Panics on a collision in CheckedHash mode, see try_insert to handle that
+    pub fn insert(&mut self, id: &str) -> u64 {
+        match self.try_insert(id) {
+            Ok(key) => key,
+            Err(collision) => panic!("{}", collision),
+        }
+    }
+
+    /// Err when the id collides with one added before, only ever in CheckedHash mode.
+    /// The first id keeps the key, the collision is kept for collisions()
+    pub fn try_insert(&mut self, id: &str) -> Result<u64, IdCollision> {
+        Ok(match self.mode {
+            IdMode::Hash => hash(id),
+            IdMode::CheckedHash => self.check(id, hash(id))?,
+            IdMode::Intern => self.intern(id),
+            IdMode::Numeric => match numeric_id(id) {
+                Some(key) => key,
+                None => self.intern(id) | INTERNED_BIT,
+            },
+        })
+    }
+
+    fn intern(&mut self, id: &str) -> u64 {
+        if let Some(&handle) = self.handles.get(id) {
+            return handle;
+        }
+        let handle = self.names.len() as u64;
+        self.names.push(id.to_string());
+        self.handles.insert(id.to_string(), handle);
+        handle
+    }
+
+    /// Returns the key for the id of an order that should already be in the book.
+    /// None means the id was never added, or was refused for colliding -
+    /// only Hash mode can't tell
+    pub fn key(&self, id: &str) -> Option<u64> {
+        match self.mode {
+            IdMode::Hash => Some(hash(id)),
+            IdMode::CheckedHash => {
+                let key = hash(id);
+                match self.originals.get(&key) {
+                    Some(existing) if existing == id => Some(key),
+                    _ => None,
+                }
+            }
+            IdMode::Intern => self.handles.get(id).cloned(),
+            IdMode::Numeric => match numeric_id(id) {
+                Some(key) => Some(key),
+                None => self.handles.get(id).map(|&handle| handle | INTERNED_BIT),
+            },
+        }
+    }
+
+    fn check(&mut self, id: &str, key: u64) -> Result<u64, IdCollision> {
+        let collision = match self.originals.get(&key) {
+            Some(existing) if existing != id => IdCollision {
+                key,
+                existing: existing.clone(),
+                incoming: id.to_string(),
+            },
+            Some(_) => return Ok(key),
+            None => {
+                self.originals.insert(key, id.to_string());
+                return Ok(key);
+            }
+        };
+        self.collisions.push(collision.clone());
+        Err(collision)
+    }
+
+    /// Original id behind a key, not available in Hash mode
+    pub fn original(&self, key: u64) -> Option<Cow<'_, str>> {
+        match self.mode {
+            IdMode::Hash => None,
+            IdMode::CheckedHash => self.originals.get(&key).map(|id| Cow::from(id.as_str())),
+            IdMode::Intern => self
+                .names
+                .get(key as usize)
+                .map(|id| Cow::from(id.as_str())),
+            IdMode::Numeric if key & INTERNED_BIT == 0 => Some(Cow::from(key.to_string())),
+            IdMode::Numeric => self
+                .names
+                .get((key & !INTERNED_BIT) as usize)
+                .map(|id| Cow::from(id.as_str())),
+        }
+    }
+
+    /// Orders refused so far for colliding, only ever in CheckedHash mode
+    pub fn collisions(&self) -> &[IdCollision] {
+        &self.collisions
+    }
+
+    /// Number of distinct ids remembered, always 0 in Hash mode.
+    /// Numeric mode only remembers the ids it had to intern
+    pub fn len(&self) -> usize {
+        match self.mode {
+            IdMode::Hash => 0,
+            IdMode::CheckedHash => self.originals.len(),
+            IdMode::Intern | IdMode::Numeric => self.names.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for OrderIds {
+    fn default() -> Self {
+        OrderIds::new(IdMode::Hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_mode_matches_hash() {
+        let mut ids = OrderIds::new(IdMode::Hash);
+        assert_eq!(ids.insert("b"), hash("b"));
+        assert_eq!(ids.key("b"), Some(hash("b")));
+        assert_eq!(ids.key("never_added"), Some(hash("never_added")));
+        assert_eq!(ids.original(hash("b")), None);
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn intern_mode_hands_out_dense_handles() {
+        let mut ids = OrderIds::new(IdMode::Intern);
+        assert_eq!(ids.insert("b"), 0);
+        assert_eq!(ids.insert("c"), 1);
+        assert_eq!(ids.insert("b"), 0);
+        assert_eq!(ids.key("c"), Some(1));
+        assert_eq!(ids.key("d"), None);
+        assert_eq!(ids.len(), 2);
+    }
+
+    #[test]
+    fn intern_mode_keeps_original_ids() {
+        let mut ids = OrderIds::new(IdMode::Intern);
+        let key = ids.insert("order-42");
+        assert_eq!(ids.original(key).as_deref(), Some("order-42"));
+        assert_eq!(ids.original(key + 1).as_deref(), None);
+    }
+
+    #[test]
+    fn checked_mode_keeps_original_ids() {
+        let mut ids = OrderIds::new(IdMode::CheckedHash);
+        assert_eq!(ids.insert("b"), hash("b"));
+        assert_eq!(ids.key("b"), Some(hash("b")));
+        assert_eq!(ids.original(hash("b")).as_deref(), Some("b"));
+        assert!(ids.collisions().is_empty());
+        assert_eq!(ids.key("never_added"), None);
+    }
+
+    #[test]
+    fn checked_mode_reports_collisions() {
+        // real 64-bit FNV collisions are hard to come by, so force the key
+        let mut ids = OrderIds::new(IdMode::CheckedHash);
+        assert_eq!(ids.check("b", 7), Ok(7));
+        assert_eq!(ids.check("b", 7), Ok(7));
+        assert!(ids.collisions().is_empty());
+        let collision = IdCollision {
+            key: 7,
+            existing: "b".to_string(),
+            incoming: "c".to_string(),
+        };
+        assert_eq!(ids.check("c", 7), Err(collision.clone()));
+        assert_eq!(ids.collisions(), &[collision]);
+        // the first id seen keeps the key
+        assert_eq!(ids.original(7).as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn checked_mode_refuses_colliding_ids() {
+        let mut ids = OrderIds::new(IdMode::CheckedHash);
+        ids.originals.insert(hash("c"), "b".to_string());
+        assert!(ids.try_insert("c").is_err());
+        assert_eq!(ids.key("c"), None);
+        assert_eq!(ids.collisions().len(), 1);
+    }
+
+    #[test]
+    fn numeric_ids_are_their_own_key() {
+        let mut ids = OrderIds::new(IdMode::Numeric);
+        assert_eq!(ids.insert("12345"), 12345);
+        assert_eq!(ids.key("12345"), Some(12345));
+        assert_eq!(ids.insert("0"), 0);
+        assert_eq!(ids.original(12345).as_deref(), Some("12345"));
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn numeric_mode_interns_everything_else() {
+        let mut ids = OrderIds::new(IdMode::Numeric);
+        let b = ids.insert("b");
+        let padded = ids.insert("007");
+        assert_eq!(b, INTERNED_BIT);
+        assert_eq!(padded, INTERNED_BIT | 1);
+        assert_ne!(padded, ids.insert("7"));
+        assert_eq!(ids.key("b"), Some(b));
+        assert_eq!(ids.key("c"), None);
+        assert_eq!(ids.original(padded).as_deref(), Some("007"));
+        assert_eq!(ids.len(), 2);
+    }
+
+    #[test]
+    fn numeric_id_parsing() {
+        assert_eq!(numeric_id("28800538"), Some(28800538));
+        assert_eq!(numeric_id("9223372036854775807"), Some(u64::MAX >> 1));
+        // would clash with interned handles
+        assert_eq!(numeric_id("9223372036854775808"), None);
+        assert_eq!(numeric_id("99999999999999999999"), None);
+        assert_eq!(numeric_id(""), None);
+        assert_eq!(numeric_id("-1"), None);
+        assert_eq!(numeric_id("+1"), None);
+        assert_eq!(numeric_id("1a"), None);
+        assert_eq!(numeric_id("01"), None);
+    }
+
+    #[test]
+    fn parse_id_modes() {
+        assert_eq!("hash".parse(), Ok(IdMode::Hash));
+        assert_eq!("checked".parse(), Ok(IdMode::CheckedHash));
+        assert_eq!("intern".parse(), Ok(IdMode::Intern));
+        assert_eq!("numeric".parse(), Ok(IdMode::Numeric));
+        assert!("fnv".parse::<IdMode>().is_err());
+    }
+
+    #[test]
+    fn collision_display() {
+        let collision = IdCollision {
+            key: 7,
+            existing: "b".to_string(),
+            incoming: "c".to_string(),
+        };
+        assert_eq!(collision.to_string(), "order ids b and c both map to key 7");
+    }
+}
@@ -1,3 +1,5 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 use std::hash::{Hash, Hasher};
 
 use super::amount::Amount;
@@ -9,6 +11,96 @@ pub fn hash<T: Hash>(x: T) -> u64 {
     hasher.finish()
 }
 
+/// Why a line of the feed couldn't be turned into an order
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    UnknownMessage(String),
+    WrongFieldCount { expected: usize, found: usize },
+    BadTimestamp(String),
+    BadSide(String),
+    BadPrice(String),
+    BadSize(String),
+    BadSequence(String),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnknownMessage(ref msg) => write!(f, "unknown message type {:?}", msg),
+            ParseError::WrongFieldCount { expected, found } => {
+                write!(f, "expected {} fields, found {}", expected, found)
+            }
+            ParseError::BadTimestamp(ref field) => write!(f, "bad timestamp {:?}", field),
+            ParseError::BadSide(ref field) => write!(f, "bad order side {:?}", field),
+            ParseError::BadPrice(ref field) => write!(f, "bad price {:?}", field),
+            ParseError::BadSize(ref field) => write!(f, "bad size {:?}", field),
+            ParseError::BadSequence(ref field) => write!(f, "bad sequence number {:?}", field),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+fn check_field_count(input_vec: &[&str], expected: usize) -> Result<(), ParseError> {
+    if input_vec.len() != expected {
+        return Err(ParseError::WrongFieldCount {
+            expected,
+            found: input_vec.len(),
+        });
+    }
+    Ok(())
+}
+
+fn parse_timestamp(field: &str) -> Result<i64, ParseError> {
+    field
+        .parse::<i64>()
+        .map_err(|_| ParseError::BadTimestamp(field.to_string()))
+}
+
+fn parse_side(field: &str) -> Result<OrderSide, ParseError> {
+    match field {
+        "B" => Ok(OrderSide::Bid),
+        "S" => Ok(OrderSide::Ask),
+        other => Err(ParseError::BadSide(other.to_string())),
+    }
+}
+
+/// Highest price a message may carry, in cents. Times a target size of at most
+/// MAX_SIZE that's 10^18, so pricing a target the command line takes fits in an i64.
+/// Depth piling up on a level is another matter, the book checks its own sums
+pub const MAX_PRICE: i64 = 1_000_000_000;
+/// Most shares a message may add, reduce or rest at a level, and the largest target size
+pub const MAX_SIZE: i64 = 1_000_000_000;
+
+/// Whether a price is within MAX_PRICE either way
+pub fn price_in_range(price: Amount) -> bool {
+    (-MAX_PRICE..=MAX_PRICE).contains(&price.as_int)
+}
+
+/// Whether a size is 0 to MAX_SIZE
+pub fn size_in_range(size: i64) -> bool {
+    (0..=MAX_SIZE).contains(&size)
+}
+
+/// A finite price within MAX_PRICE, like every feed format has to carry
+pub fn parse_price(field: &str) -> Result<Amount, ParseError> {
+    let bad = || ParseError::BadPrice(field.to_string());
+    // NaN would come out as 0 and the infinities as the ends of i64
+    let finite = field.parse::<f64>().map_err(|_| bad())?.is_finite();
+    let price = field.parse::<Amount>().map_err(|_| bad())?;
+    if !finite || !price_in_range(price) {
+        return Err(bad());
+    }
+    Ok(price)
+}
+
+fn parse_size(field: &str) -> Result<i64, ParseError> {
+    match field.parse::<i64>() {
+        Ok(size) if size_in_range(size) => Ok(size),
+        _ => Err(ParseError::BadSize(field.to_string())),
+    }
+}
+
 #[derive(Debug)]
 pub struct ReduceOrder {
     // "28800744 R b 20"
@@ -19,13 +111,25 @@ pub struct ReduceOrder {
 
 impl ReduceOrder {
     pub fn new(input_vec: &[&str]) -> Self {
-        assert!(input_vec.len() <= 4);
-        ReduceOrder {
-            timestamp: input_vec[0].parse::<i64>().unwrap_or(0),
-            id: hash(&input_vec[2]),
-            size: input_vec[3].parse::<i64>().unwrap_or(0),
+        ReduceOrder::with_id(input_vec, hash(input_vec[2]))
+    }
+
+    /// Same as new, but keyed by an id resolved elsewhere (see OrderIds)
+    pub fn with_id(input_vec: &[&str], id: u64) -> Self {
+        match ReduceOrder::parse(input_vec, id) {
+            Ok(order) => order,
+            Err(err) => panic!("Couldn't parse reduce order: {}", err),
         }
     }
+
+    pub fn parse(input_vec: &[&str], id: u64) -> Result<Self, ParseError> {
+        check_field_count(input_vec, 4)?;
+        Ok(ReduceOrder {
+            timestamp: parse_timestamp(input_vec[0])?,
+            id,
+            size: parse_size(input_vec[3])?,
+        })
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -40,17 +144,228 @@ pub struct LimitOrder {
 
 impl LimitOrder {
     pub fn new(input_vec: &[&str]) -> Self {
-        assert!(input_vec.len() <= 6);
-        LimitOrder {
-            timestamp: input_vec[0].parse::<i64>().unwrap_or(0),
-            id: hash(input_vec[2]),
-            side: match input_vec[3] {
-                "B" => OrderSide::Bid,
-                "S" => OrderSide::Ask,
-                _ => panic!("Couldn't parse order side from {}", input_vec[3]),
-            },
-            price: Amount::new_from_str(&input_vec[4]),
-            size: input_vec[5].parse::<i64>().unwrap_or(0),
+        LimitOrder::with_id(input_vec, hash(input_vec[2]))
+    }
+
+    /// Same as new, but keyed by an id resolved elsewhere (see OrderIds)
+    pub fn with_id(input_vec: &[&str], id: u64) -> Self {
+        match LimitOrder::parse(input_vec, id) {
+            Ok(order) => order,
+            Err(err) => panic!("Couldn't parse limit order: {}", err),
+        }
+    }
+
+    pub fn parse(input_vec: &[&str], id: u64) -> Result<Self, ParseError> {
+        check_field_count(input_vec, 6)?;
+        Ok(LimitOrder {
+            timestamp: parse_timestamp(input_vec[0])?,
+            id,
+            side: parse_side(input_vec[3])?,
+            price: parse_price(input_vec[4])?,
+            size: parse_size(input_vec[5])?,
+        })
+    }
+}
+
+// takes a trailing sequence number off a line one field longer than it should be
+fn pop_seq(input_vec: &mut Vec<&str>, field_count: usize) -> Result<Option<u64>, ParseError> {
+    if input_vec.len() != field_count + 1 {
+        return Ok(None);
+    }
+    let field = input_vec.pop().unwrap();
+    field
+        .parse::<u64>()
+        .map(Some)
+        .map_err(|_| ParseError::BadSequence(field.to_string()))
+}
+
+/// One line of the feed, before its id is resolved to a cache key.
+/// Borrows the id from wherever the message was read
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message<'a> {
+    Add {
+        timestamp: i64,
+        id: &'a str,
+        side: OrderSide,
+        price: Amount,
+        size: i64,
+    },
+    Reduce {
+        timestamp: i64,
+        id: &'a str,
+        size: i64,
+    },
+}
+
+impl<'a> Message<'a> {
+    /// Parses a text line, "28800538 A b S 44.26 100" or "28800744 R b 20".
+    /// A trailing sequence number is accepted and ignored, see parse_with_seq
+    pub fn parse(line: &'a str) -> Result<Self, ParseError> {
+        Message::parse_with_seq(line).map(|(message, _seq)| message)
+    }
+
+    /// Parses a text line that may end in a sequence number,
+    /// "28800538 A b S 44.26 100 17" or "28800744 R b 20 18"
+    pub fn parse_with_seq(line: &'a str) -> Result<(Self, Option<u64>), ParseError> {
+        let mut input_vec: Vec<&str> = line.trim().split(' ').collect();
+        let field_count = match input_vec.get(1) {
+            Some(&"A") => 6,
+            Some(&"R") => 4,
+            Some(other) => return Err(ParseError::UnknownMessage(other.to_string())),
+            None => return Err(ParseError::UnknownMessage(String::new())),
+        };
+        let seq = pop_seq(&mut input_vec, field_count)?;
+        let message = if field_count == 6 {
+            let order = LimitOrder::parse(&input_vec, 0)?;
+            Message::Add {
+                timestamp: order.timestamp,
+                id: input_vec[2],
+                side: order.side,
+                price: order.price,
+                size: order.size,
+            }
+        } else {
+            let order = ReduceOrder::parse(&input_vec, 0)?;
+            Message::Reduce {
+                timestamp: order.timestamp,
+                id: input_vec[2],
+                size: order.size,
+            }
+        };
+        Ok((message, seq))
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        match *self {
+            Message::Add { timestamp, .. } | Message::Reduce { timestamp, .. } => timestamp,
+        }
+    }
+
+    pub fn id(&self) -> &'a str {
+        match *self {
+            Message::Add { id, .. } | Message::Reduce { id, .. } => id,
+        }
+    }
+}
+
+/// Writes the message back out as a text line, without the newline
+impl<'a> Display for Message<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Message::Add {
+                timestamp,
+                id,
+                side,
+                price,
+                size,
+            } => write!(f, "{} A {} {} {} {}", timestamp, id, side, price, size),
+            Message::Reduce {
+                timestamp,
+                id,
+                size,
+            } => write!(f, "{} R {} {}", timestamp, id, size),
+        }
+    }
+}
+
+/// One line of an aggregated (market by price) feed, the depth now resting at a price.
+/// Levels aren't made of orders, so there are no ids and no IdPriceCache involved
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LevelUpdate {
+    /// "28800538 L B 44.26 500", depth 0 empties the level
+    Set {
+        timestamp: i64,
+        side: OrderSide,
+        price: Amount,
+        depth: i64,
+    },
+    /// "28800540 D B 44.26"
+    Delete {
+        timestamp: i64,
+        side: OrderSide,
+        price: Amount,
+    },
+}
+
+impl LevelUpdate {
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        LevelUpdate::parse_with_seq(line).map(|(update, _seq)| update)
+    }
+
+    /// Takes a trailing sequence number like Message::parse_with_seq
+    pub fn parse_with_seq(line: &str) -> Result<(Self, Option<u64>), ParseError> {
+        let mut input_vec: Vec<&str> = line.trim().split(' ').collect();
+        let field_count = match input_vec.get(1) {
+            Some(&"L") => 5,
+            Some(&"D") => 4,
+            Some(other) => return Err(ParseError::UnknownMessage(other.to_string())),
+            None => return Err(ParseError::UnknownMessage(String::new())),
+        };
+        let seq = pop_seq(&mut input_vec, field_count)?;
+        check_field_count(&input_vec, field_count)?;
+        let timestamp = parse_timestamp(input_vec[0])?;
+        let side = parse_side(input_vec[2])?;
+        let price = parse_price(input_vec[3])?;
+        let update = if field_count == 5 {
+            let depth = parse_size(input_vec[4])?;
+            LevelUpdate::Set {
+                timestamp,
+                side,
+                price,
+                depth,
+            }
+        } else {
+            LevelUpdate::Delete {
+                timestamp,
+                side,
+                price,
+            }
+        };
+        Ok((update, seq))
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        match *self {
+            LevelUpdate::Set { timestamp, .. } | LevelUpdate::Delete { timestamp, .. } => timestamp,
+        }
+    }
+
+    pub fn side(&self) -> OrderSide {
+        match *self {
+            LevelUpdate::Set { side, .. } | LevelUpdate::Delete { side, .. } => side,
+        }
+    }
+
+    pub fn price(&self) -> Amount {
+        match *self {
+            LevelUpdate::Set { price, .. } | LevelUpdate::Delete { price, .. } => price,
+        }
+    }
+
+    /// Depth the level is left with
+    pub fn depth(&self) -> i64 {
+        match *self {
+            LevelUpdate::Set { depth, .. } => depth,
+            LevelUpdate::Delete { .. } => 0,
+        }
+    }
+}
+
+/// Writes the update back out as a text line, without the newline
+impl Display for LevelUpdate {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            LevelUpdate::Set {
+                timestamp,
+                side,
+                price,
+                depth,
+            } => write!(f, "{} L {} {} {}", timestamp, side, price, depth),
+            LevelUpdate::Delete {
+                timestamp,
+                side,
+                price,
+            } => write!(f, "{} D {} {}", timestamp, side, price),
         }
     }
 }
@@ -79,4 +394,204 @@ mod tests {
         assert_eq!(ro.id, hash("b"));
     }
 
+    #[test]
+    fn limit_order_parse_errors() {
+        let parse = |line: &str| {
+            let lo_vec: Vec<&str> = line.split(' ').collect();
+            LimitOrder::parse(&lo_vec, 0)
+        };
+        assert_eq!(
+            parse("28800538 A b S 44.07").unwrap_err(),
+            ParseError::WrongFieldCount {
+                expected: 6,
+                found: 5
+            }
+        );
+        assert_eq!(
+            parse("2880053x A b S 44.07 100").unwrap_err(),
+            ParseError::BadTimestamp("2880053x".to_string())
+        );
+        assert_eq!(
+            parse("28800538 A b X 44.07 100").unwrap_err(),
+            ParseError::BadSide("X".to_string())
+        );
+        assert_eq!(
+            parse("28800538 A b S 44.0x 100").unwrap_err(),
+            ParseError::BadPrice("44.0x".to_string())
+        );
+        assert_eq!(
+            parse("28800538 A b S 44.07 1e2").unwrap_err(),
+            ParseError::BadSize("1e2".to_string())
+        );
+        // out of range for the book's arithmetic
+        for price in &["NaN", "inf", "-inf", "1e300", "10000000.01"] {
+            let line = format!("28800538 A b S {} 100", price);
+            assert_eq!(
+                parse(&line).unwrap_err(),
+                ParseError::BadPrice(price.to_string())
+            );
+        }
+        assert!(parse("28800538 A b S 10000000.00 100").is_ok());
+        for size in &["-5", "1000000001", "9223372036854775807"] {
+            let line = format!("28800538 A b S 44.07 {}", size);
+            assert_eq!(
+                parse(&line).unwrap_err(),
+                ParseError::BadSize(size.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn reduce_order_parse_errors() {
+        let parse = |line: &str| {
+            let ro_vec: Vec<&str> = line.split(' ').collect();
+            ReduceOrder::parse(&ro_vec, 0)
+        };
+        assert_eq!(
+            parse("28800744 R b 20 1").unwrap_err(),
+            ParseError::WrongFieldCount {
+                expected: 4,
+                found: 5
+            }
+        );
+        assert_eq!(
+            parse("28800744 R b lots").unwrap_err(),
+            ParseError::BadSize("lots".to_string())
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn limit_order_constructor_panics_on_bad_side() {
+        let lo_vec: Vec<&str> = "28800538 A b X 44.07 100".split(' ').collect();
+        LimitOrder::new(&lo_vec);
+    }
+
+    #[test]
+    fn limit_order_with_id() {
+        let lo_vec: Vec<&str> = "28800538 A b S 44.07 100".split(' ').collect();
+        let lo = LimitOrder::with_id(&lo_vec, 3);
+        assert_eq!(lo.id, 3);
+        assert_eq!(lo.side, OrderSide::Ask);
+        assert_eq!(lo.size, 100);
+    }
+
+    #[test]
+    fn reduce_order_with_id() {
+        let ro_vec: Vec<&str> = "28800744 R b 20".split(' ').collect();
+        let ro = ReduceOrder::with_id(&ro_vec, 3);
+        assert_eq!(ro.id, 3);
+        assert_eq!(ro.size, 20);
+    }
+
+    #[test]
+    fn message_parse() {
+        assert_eq!(
+            Message::parse("28800538 A b S 44.26 100\n"),
+            Ok(Message::Add {
+                timestamp: 28800538,
+                id: "b",
+                side: OrderSide::Ask,
+                price: Amount::new_from_str("44.26"),
+                size: 100,
+            })
+        );
+        assert_eq!(
+            Message::parse("28800744 R b 20"),
+            Ok(Message::Reduce {
+                timestamp: 28800744,
+                id: "b",
+                size: 20,
+            })
+        );
+        assert_eq!(
+            Message::parse("28800744 X b 20"),
+            Err(ParseError::UnknownMessage("X".to_string()))
+        );
+        assert_eq!(
+            Message::parse("28800744"),
+            Err(ParseError::UnknownMessage(String::new()))
+        );
+        assert_eq!(
+            Message::parse("28800744 R b"),
+            Err(ParseError::WrongFieldCount {
+                expected: 4,
+                found: 3
+            })
+        );
+    }
+
+    #[test]
+    fn message_display_round_trips() {
+        for line in &[
+            "28800538 A b S 44.26 100",
+            "28800562 A c B 0.05 7",
+            "28800744 R b 20",
+        ] {
+            let msg = Message::parse(line).unwrap();
+            assert_eq!(&msg.to_string(), line);
+            assert_eq!(msg.id(), line.split(' ').nth(2).unwrap());
+        }
+        assert_eq!(
+            Message::parse("28800744 R b 20").unwrap().timestamp(),
+            28800744
+        );
+    }
+
+    #[test]
+    fn message_parse_with_seq() {
+        let (msg, seq) = Message::parse_with_seq("28800538 A b S 44.26 100 17").unwrap();
+        assert_eq!(msg, Message::parse("28800538 A b S 44.26 100").unwrap());
+        assert_eq!(seq, Some(17));
+        assert_eq!(
+            Message::parse_with_seq("28800744 R b 20 18").unwrap(),
+            (Message::parse("28800744 R b 20").unwrap(), Some(18))
+        );
+        assert_eq!(Message::parse_with_seq("28800744 R b 20").unwrap().1, None);
+        assert_eq!(
+            Message::parse_with_seq("28800744 R b 20 x"),
+            Err(ParseError::BadSequence("x".to_string()))
+        );
+        assert_eq!(
+            Message::parse_with_seq("28800744 R b 20 18 19"),
+            Err(ParseError::WrongFieldCount {
+                expected: 4,
+                found: 6
+            })
+        );
+    }
+
+    #[test]
+    fn level_update_parse() {
+        assert_eq!(
+            LevelUpdate::parse_with_seq("28800538 L B 44.26 500 9"),
+            Ok((
+                LevelUpdate::Set {
+                    timestamp: 28800538,
+                    side: OrderSide::Bid,
+                    price: Amount::new_from_str("44.26"),
+                    depth: 500,
+                },
+                Some(9)
+            ))
+        );
+        let delete = LevelUpdate::parse("28800540 D S 44.30").unwrap();
+        assert_eq!(delete.side(), OrderSide::Ask);
+        assert_eq!(delete.depth(), 0);
+        for line in &["28800538 L B 44.26 500", "28800540 D S 44.30"] {
+            assert_eq!(&LevelUpdate::parse(line).unwrap().to_string(), line);
+        }
+        assert_eq!(
+            LevelUpdate::parse("28800538 A b S 44.26 100"),
+            Err(ParseError::UnknownMessage("A".to_string()))
+        );
+        assert_eq!(
+            LevelUpdate::parse("28800538 L B 44.26 -5"),
+            Err(ParseError::BadSize("-5".to_string()))
+        );
+        assert_eq!(
+            LevelUpdate::parse("28800538 D X 44.26"),
+            Err(ParseError::BadSide("X".to_string()))
+        );
+    }
 }
@@ -0,0 +1,198 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use pricer::PricerEvent;
+
+/// How pricer events are written out
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// "<timestamp> <side> <amount|NA>", as in the problem statement,
+    /// followed by the target size when more than one is priced
+    Text,
+    /// one JSON object per line
+    JsonLines,
+    /// comma separated, with a header row
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "csv" => Ok(OutputFormat::Csv),
+            other => Err(format!(
+                "Unknown output format {}, expected one of text, jsonl, csv",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match *self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::JsonLines => write!(f, "jsonl"),
+            OutputFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// Writes whatever has to come before the first event
+pub fn write_header<W: Write>(out: &mut W, format: OutputFormat) -> io::Result<()> {
+    match format {
+        OutputFormat::Csv => writeln!(
+            out,
+            "timestamp,side,amount,amount_minor,target_size,sequence"
+        ),
+        OutputFormat::Text | OutputFormat::JsonLines => Ok(()),
+    }
+}
+
+/// Writes one event as a single line.
+/// Amounts go out both as the exact decimal string and as an integer of cents,
+/// so consumers never have to go through a float.
+/// targets is how many target sizes are priced, text only names them when there are several
+pub fn write_event<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    event: &PricerEvent,
+    targets: usize,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text if targets > 1 => writeln!(out, "{} {}", event, event.target_size),
+        OutputFormat::Text => writeln!(out, "{}", event),
+        OutputFormat::JsonLines => match event.value {
+            Some(amount) => writeln!(
+                out,
+                "{{\"timestamp\":{},\"side\":\"{}\",\"amount\":\"{}\",\"amount_minor\":{},\"target_size\":{},\"sequence\":{}}}",
+                event.timestamp, event.side, amount, amount.as_int, event.target_size, event.sequence
+            ),
+            None => writeln!(
+                out,
+                "{{\"timestamp\":{},\"side\":\"{}\",\"amount\":null,\"amount_minor\":null,\"target_size\":{},\"sequence\":{}}}",
+                event.timestamp, event.side, event.target_size, event.sequence
+            ),
+        },
+        OutputFormat::Csv => match event.value {
+            Some(amount) => writeln!(
+                out,
+                "{},{},{},{},{},{}",
+                event.timestamp, event.side, amount, amount.as_int, event.target_size, event.sequence
+            ),
+            None => writeln!(
+                out,
+                "{},{},,,{},{}",
+                event.timestamp, event.side, event.target_size, event.sequence
+            ),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amount::Amount;
+    use orderside::OrderSide;
+
+    fn event(value: Option<Amount>) -> PricerEvent {
+        PricerEvent {
+            timestamp: 28800758,
+            side: OrderSide::Ask,
+            value,
+            target_size: 200,
+            sequence: 4,
+        }
+    }
+
+    fn written(format: OutputFormat, event: &PricerEvent) -> String {
+        let mut out = Vec::new();
+        write_event(&mut out, format, event, 1).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!("text".parse(), Ok(OutputFormat::Text));
+        assert_eq!("jsonl".parse(), Ok(OutputFormat::JsonLines));
+        assert_eq!("csv".parse(), Ok(OutputFormat::Csv));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for format in &[
+            OutputFormat::Text,
+            OutputFormat::JsonLines,
+            OutputFormat::Csv,
+        ] {
+            assert_eq!(format.to_string().parse(), Ok(*format));
+        }
+    }
+
+    #[test]
+    fn text_matches_display() {
+        let ev = event(Some(Amount::new_from_str("8832.56")));
+        assert_eq!(written(OutputFormat::Text, &ev), "28800758 S 8832.56\n");
+        assert_eq!(written(OutputFormat::Text, &event(None)), "28800758 S NA\n");
+    }
+
+    #[test]
+    fn text_names_the_target_when_there_are_several() {
+        let ev = event(Some(Amount::new_from_str("8832.56")));
+        let mut out = Vec::new();
+        write_event(&mut out, OutputFormat::Text, &ev, 2).unwrap();
+        write_event(&mut out, OutputFormat::Text, &event(None), 2).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "28800758 S 8832.56 200\n28800758 S NA 200\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_with_amount() {
+        let ev = event(Some(Amount::new_from_str("8832.56")));
+        assert_eq!(
+            written(OutputFormat::JsonLines, &ev),
+            "{\"timestamp\":28800758,\"side\":\"S\",\"amount\":\"8832.56\",\"amount_minor\":883256,\"target_size\":200,\"sequence\":4}\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_without_amount() {
+        assert_eq!(
+            written(OutputFormat::JsonLines, &event(None)),
+            "{\"timestamp\":28800758,\"side\":\"S\",\"amount\":null,\"amount_minor\":null,\"target_size\":200,\"sequence\":4}\n"
+        );
+    }
+
+    #[test]
+    fn csv_rows() {
+        let ev = event(Some(Amount::new_from_str("8832.56")));
+        assert_eq!(
+            written(OutputFormat::Csv, &ev),
+            "28800758,S,8832.56,883256,200,4\n"
+        );
+        assert_eq!(
+            written(OutputFormat::Csv, &event(None)),
+            "28800758,S,,,200,4\n"
+        );
+    }
+
+    #[test]
+    fn only_csv_has_a_header() {
+        let mut out = Vec::new();
+        write_header(&mut out, OutputFormat::Text).unwrap();
+        write_header(&mut out, OutputFormat::JsonLines).unwrap();
+        assert!(out.is_empty());
+        write_header(&mut out, OutputFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "timestamp,side,amount,amount_minor,target_size,sequence\n"
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use amount::Amount;
+use idpricecache::IdPriceCache;
+use orderbook::BookError;
+use orders::{Message, ParseError};
+use orderside::OrderSide;
+use pricer::{Pricer, PricerEvent};
+use sequence::SeqIssue;
+use spsc::{self, Consumer, Producer};
+
+// Runs the pricer as three threads joined by spsc queues: one parsing the feed,
+// one applying it to the book and one writing out what came of it. Ids are
+// resolved on the book thread, the book's OrderIds is the only place they live.
+
+/// How long a quiet feed keeps the book thread waiting before it gets to look up,
+/// see price_pipelined and read_apart
+pub const IDLE_WAIT: Duration = Duration::from_millis(50);
+
+/// A message parsed off the feed, with its id copied out of the line so it can
+/// cross over to the book thread
+#[derive(Clone, Debug, PartialEq)]
+pub enum OwnedMessage {
+    Add {
+        timestamp: i64,
+        id: String,
+        side: OrderSide,
+        price: Amount,
+        size: i64,
+    },
+    Reduce {
+        timestamp: i64,
+        id: String,
+        size: i64,
+    },
+}
+
+impl OwnedMessage {
+    pub fn as_message(&self) -> Message<'_> {
+        match *self {
+            OwnedMessage::Add {
+                timestamp,
+                ref id,
+                side,
+                price,
+                size,
+            } => Message::Add {
+                timestamp,
+                id,
+                side,
+                price,
+                size,
+            },
+            OwnedMessage::Reduce {
+                timestamp,
+                ref id,
+                size,
+            } => Message::Reduce {
+                timestamp,
+                id,
+                size,
+            },
+        }
+    }
+}
+
+impl<'a> From<Message<'a>> for OwnedMessage {
+    fn from(message: Message<'a>) -> Self {
+        match message {
+            Message::Add {
+                timestamp,
+                id,
+                side,
+                price,
+                size,
+            } => OwnedMessage::Add {
+                timestamp,
+                id: id.to_string(),
+                side,
+                price,
+                size,
+            },
+            Message::Reduce {
+                timestamp,
+                id,
+                size,
+            } => OwnedMessage::Reduce {
+                timestamp,
+                id: id.to_string(),
+                size,
+            },
+        }
+    }
+}
+
+/// What the parser thread hands the book thread, idx counts messages from 0
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoded {
+    pub idx: usize,
+    pub parsed: Result<(OwnedMessage, Option<u64>), ParseError>,
+}
+
+/// What the book thread hands the writer, for one message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Priced {
+    pub idx: usize,
+    pub events: Result<Vec<PricerEvent>, BookError>,
+    /// what the sequence tracker made of the message
+    pub issue: Option<SeqIssue>,
+}
+
+/// Feeds decoded messages to the book thread, false once nobody's listening
+/// and the parser can stop
+pub struct Feeder(Producer<Decoded>);
+
+impl Feeder {
+    pub fn feed(&mut self, idx: usize, parsed: Result<(Message, Option<u64>), ParseError>) -> bool {
+        let parsed = parsed.map(|(message, seq)| (OwnedMessage::from(message), seq));
+        self.0.push(Decoded { idx, parsed }).is_ok()
+    }
+}
+
+/// Prices on a thread of its own between a parser thread running parse and the
+/// calling thread running write, with capacity messages queued between each.
+/// after_each sees the book after every message, and every IDLE_WAIT the feed is quiet,
+/// on the book thread.
+/// Events come out exactly as single threaded pricing would give them.
+/// An error from write stops all three, an error from parse once everything
+/// before it is written
+pub fn price_pipelined<T, P, A, W>(
+    mut pricer: Pricer<T>,
+    capacity: usize,
+    parse: P,
+    mut after_each: A,
+    mut write: W,
+) -> Result<Pricer<T>, String>
+where
+    T: IdPriceCache + Send,
+    P: FnOnce(&mut Feeder) -> Result<(), String> + Send,
+    A: FnMut(&Pricer<T>) + Send,
+    W: FnMut(Priced) -> Result<(), String>,
+{
+    let (to_book, decoded) = spsc::channel(capacity);
+    let (to_writer, priced) = spsc::channel(capacity);
+    thread::scope(|scope| {
+        let parser = scope.spawn(move || parse(&mut Feeder(to_book)));
+        let book = scope.spawn(move || {
+            price_decoded(&mut pricer, decoded, to_writer, &mut after_each);
+            pricer
+        });
+        let written = write_priced(priced, &mut write);
+        let parsed = parser.join().expect("parser thread panicked");
+        let pricer = book.join().expect("book thread panicked");
+        written.and(parsed).map(|()| pricer)
+    })
+}
+
+fn price_decoded<T: IdPriceCache, A: FnMut(&Pricer<T>)>(
+    pricer: &mut Pricer<T>,
+    mut decoded: Consumer<Decoded>,
+    mut to_writer: Producer<Priced>,
+    after_each: &mut A,
+) {
+    while let Some(Decoded { idx, parsed }) = decoded.pop_or_idle(IDLE_WAIT, || after_each(pricer))
+    {
+        let events = parsed.map_err(BookError::from).and_then(|(message, seq)| {
+            pricer
+                .book_mut()
+                .apply_sequenced(&message.as_message(), seq)?;
+            // numbered by place in the whole input, bad lines included
+            let sequence = seq.unwrap_or(idx as u64 + 1);
+            Ok(pricer.reprice(sequence).to_vec())
+        });
+        let issue = pricer.book().sequence().last_issue();
+        after_each(pricer);
+        if to_writer.push(Priced { idx, events, issue }).is_err() {
+            return; // the writer gave up
+        }
+    }
+}
+
+/// Runs read on a thread of its own and hands on_read whatever it sends, on this one,
+/// and None every IDLE_WAIT nothing comes. So a read that blocks on a quiet feed
+/// doesn't hold up what has to be done between messages. Stops once on_read says false,
+/// without waiting on read, which may be stuck on a feed that never says anything again
+pub fn read_apart<M, R, F>(capacity: usize, read: R, mut on_read: F) -> Result<(), String>
+where
+    M: Send + 'static,
+    R: FnOnce(&mut dyn FnMut(M) -> bool) -> Result<(), String> + Send + 'static,
+    F: FnMut(Option<M>) -> Result<bool, String>,
+{
+    let (to_book, from_reader) = mpsc::sync_channel(capacity);
+    let reader = thread::spawn(move || read(&mut |item| to_book.send(item).is_ok()));
+    loop {
+        let item = match from_reader.recv_timeout(IDLE_WAIT) {
+            Ok(item) => Some(item),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if !on_read(item)? {
+            return Ok(());
+        }
+    }
+    reader.join().expect("reader thread panicked")
+}
+
+// on the calling thread, dropping priced on an error stops the book thread
+fn write_priced<W: FnMut(Priced) -> Result<(), String>>(
+    priced: Consumer<Priced>,
+    write: &mut W,
+) -> Result<(), String> {
+    for message in priced {
+        write(message)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use orderbook::OrderBook;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+
+    fn pricer() -> Pricer<IdPriceCacheFnvMap> {
+        Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()))
+    }
+
+    fn parse_lines<'a>(lines: &'a [String]) -> impl FnOnce(&mut Feeder) -> Result<(), String> + 'a {
+        move |feeder| {
+            for (idx, line) in lines.iter().enumerate() {
+                if !feeder.feed(idx, Message::parse_with_seq(line)) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn same_events_as_single_threaded() {
+        let lines: Vec<String> = BufReader::new(File::open("data/basic.in.txt").unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        let mut expected = Vec::new();
+        let mut single = pricer();
+        for line in &lines {
+            expected.extend_from_slice(single.try_process(line).unwrap());
+        }
+
+        let mut found = Vec::new();
+        let mut after = 0;
+        let pipelined = price_pipelined(
+            pricer(),
+            4,
+            parse_lines(&lines),
+            |_| after += 1,
+            |priced| {
+                found.extend(priced.events.unwrap());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(found, expected);
+        assert_eq!(after, lines.len());
+        assert_eq!(pipelined.messages(), single.messages());
+    }
+
+    #[test]
+    fn errors_come_through_in_order() {
+        let lines: Vec<String> = vec![
+            "28800538 A b S 44.26 100".to_string(),
+            "28800562 X".to_string(),
+            "28800744 R z 100".to_string(),
+            "28800758 A d B 44.18 157".to_string(),
+        ];
+        let mut seen = Vec::new();
+        price_pipelined(
+            pricer(),
+            2,
+            parse_lines(&lines),
+            |_| {},
+            |priced| {
+                seen.push((priced.idx, priced.events.is_ok()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(seen, vec![(0, true), (1, false), (2, false), (3, true)]);
+    }
+
+    #[test]
+    fn writer_error_stops_the_pipeline() {
+        let lines: Vec<String> = (0..10000)
+            .map(|n| format!("{} A o{} B 44.{:02} 100", 28800000 + n, n, n % 100))
+            .collect();
+        let mut written = 0;
+        let result = price_pipelined(
+            pricer(),
+            8,
+            parse_lines(&lines),
+            |_| {},
+            |priced| {
+                written += 1;
+                if priced.idx == 5 {
+                    return Err("line 6: halt".to_string());
+                }
+                Ok(())
+            },
+        );
+        assert_eq!(result.err(), Some("line 6: halt".to_string()));
+        assert_eq!(written, 6);
+    }
+}
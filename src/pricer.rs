@@ -0,0 +1,465 @@
+use std::fmt::{self, Display, Formatter};
+
+use amount::Amount;
+
+use orderside::OrderSide;
+
+use orders::{LevelUpdate, LimitOrder, Message, ParseError, ReduceOrder};
+
+use idpricecache::IdPriceCache;
+
+use orderbook::{BookError, BookState, OrderBook};
+
+/// Change in the amount earned or spent on target_size shares.
+/// side is the side the amount is reported for - selling into the bids is reported as S
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PricerEvent {
+    pub timestamp: i64,
+    pub side: OrderSide,
+    pub value: Option<Amount>, // None once there isn't enough depth to fill the target
+    pub target_size: i64,
+    // feed sequence number of the message that caused the change,
+    // or its 1-based place in the input when it has none
+    pub sequence: u64,
+}
+
+impl Display for PricerEvent {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.value {
+            Some(amount) => write!(f, "{} {} {}", self.timestamp, self.side, amount),
+            None => write!(f, "{} {} NA", self.timestamp, self.side),
+        }
+    }
+}
+
+struct TargetReports {
+    target_size: i64,
+    last_ask_report: Option<Amount>,
+    last_bid_report: Option<Amount>,
+}
+
+/// Wraps an OrderBook and only emits an event when the value reported for a side changes.
+/// Can price several target sizes off the same book
+pub struct Pricer<T: IdPriceCache + Sized> {
+    book: OrderBook<T>,
+    targets: Vec<TargetReports>,
+    events: Vec<PricerEvent>, // caused by the last message
+    messages: u64,
+    inputs: u64, // every message handed in, refused ones too
+    suppress_crossed: bool,
+}
+
+impl<T: IdPriceCache + Sized> Pricer<T> {
+    /// Prices the book's own target size
+    pub fn new(book: OrderBook<T>) -> Self {
+        let target_size = book.target_size();
+        Pricer::with_targets(book, &[target_size])
+    }
+
+    pub fn with_targets(book: OrderBook<T>, target_sizes: &[i64]) -> Self {
+        Pricer {
+            book,
+            targets: target_sizes
+                .iter()
+                .map(|&target_size| TargetReports {
+                    target_size,
+                    last_ask_report: None,
+                    last_bid_report: None,
+                })
+                .collect(),
+            events: Vec::with_capacity(target_sizes.len()),
+            messages: 0,
+            inputs: 0,
+            suppress_crossed: false,
+        }
+    }
+
+    /// Stop reporting while the book is crossed, the amounts it gives then are meaningless.
+    /// Once it clears, whatever changed meanwhile on either side is reported with the message that cleared it
+    pub fn suppress_while_crossed(&mut self, suppress: bool) {
+        self.suppress_crossed = suppress;
+    }
+
+    pub fn book(&self) -> &OrderBook<T> {
+        &self.book
+    }
+
+    pub fn into_book(self) -> OrderBook<T> {
+        self.book
+    }
+
+    /// Number of messages processed so far
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
+
+    /// Amount last reported for side at the first target size,
+    /// side as in PricerEvent: S is selling into the bids
+    pub fn last_reported(&self, side: OrderSide) -> Option<Amount> {
+        let target = &self.targets[0];
+        match side {
+            OrderSide::Ask => target.last_ask_report,
+            OrderSide::Bid => target.last_bid_report,
+        }
+    }
+
+    /// Events caused by the last message, one per target size at most
+    pub fn events(&self) -> &[PricerEvent] {
+        &self.events
+    }
+
+    /// Returns the event for the first target size, see events() for the others.
+    /// Panics like OrderBook::process
+    pub fn process(&mut self, instruction: &str) -> Option<PricerEvent> {
+        match self.try_process(instruction) {
+            Ok(_) => {}
+            Err(BookError::Parse(ParseError::UnknownMessage(_))) => {
+                eprintln!("Error processing {}", instruction)
+            }
+            Err(err) => panic!("Error processing {}: {}", instruction, err),
+        }
+        self.events.first().cloned()
+    }
+
+    pub fn try_process(&mut self, instruction: &str) -> Result<&[PricerEvent], BookError> {
+        self.events.clear();
+        self.inputs += 1;
+        let (message, seq) = Message::parse_with_seq(instruction)?;
+        self.book.apply_sequenced(&message, seq)?;
+        self.report(seq.unwrap_or(self.inputs));
+        Ok(&self.events)
+    }
+
+    /// Same as try_process, for a message that's already parsed
+    pub fn try_apply(&mut self, message: &Message) -> Result<&[PricerEvent], BookError> {
+        self.try_apply_sequenced(message, None)
+    }
+
+    /// Goes through OrderBook::apply_sequenced, see there for what's checked
+    pub fn try_apply_sequenced(
+        &mut self,
+        message: &Message,
+        seq: Option<u64>,
+    ) -> Result<&[PricerEvent], BookError> {
+        self.events.clear();
+        self.inputs += 1;
+        self.book.apply_sequenced(message, seq)?;
+        self.report(seq.unwrap_or(self.inputs));
+        Ok(&self.events)
+    }
+
+    /// Prices a market by price feed, see OrderBook::set_level
+    pub fn try_apply_level(&mut self, update: &LevelUpdate) -> Result<&[PricerEvent], BookError> {
+        self.try_apply_level_sequenced(update, None)
+    }
+
+    pub fn try_apply_level_sequenced(
+        &mut self,
+        update: &LevelUpdate,
+        seq: Option<u64>,
+    ) -> Result<&[PricerEvent], BookError> {
+        self.events.clear();
+        self.inputs += 1;
+        self.book.apply_level_sequenced(update, seq)?;
+        self.report(seq.unwrap_or(self.inputs));
+        Ok(&self.events)
+    }
+
+    /// For the sequence tracker's policy and snapshot request callback
+    pub fn book_mut(&mut self) -> &mut OrderBook<T> {
+        &mut self.book
+    }
+
+    /// The pricing half of try_apply_sequenced, for a message applied through book_mut().
+    /// Lets the two be timed apart. The events carry sequence, the message's sequence number
+    /// or its place in the input, which only the caller knows
+    pub fn reprice(&mut self, sequence: u64) -> &[PricerEvent] {
+        self.inputs += 1;
+        self.report(sequence);
+        &self.events
+    }
+
+    pub fn add(&mut self, order: LimitOrder) -> Option<PricerEvent> {
+        self.inputs += 1;
+        self.book.add(order);
+        self.report(self.inputs);
+        self.events.first().cloned()
+    }
+
+    pub fn reduce_order(&mut self, order: &ReduceOrder) -> Option<PricerEvent> {
+        self.inputs += 1;
+        self.book.reduce_order(order);
+        self.report(self.inputs);
+        self.events.first().cloned()
+    }
+
+    pub fn try_reduce_order(&mut self, order: &ReduceOrder) -> Result<&[PricerEvent], BookError> {
+        self.events.clear();
+        self.inputs += 1;
+        self.book.try_reduce_order(order)?;
+        self.report(self.inputs);
+        Ok(&self.events)
+    }
+
+    fn report(&mut self, sequence: u64) {
+        self.messages += 1;
+        self.events.clear();
+        if self.suppress_crossed && self.book.state() == BookState::Crossed {
+            return;
+        }
+        // touching the asks changes what buying target_size costs and vice versa
+        let side = !self.book.last_action_side;
+        // both sides may have moved while nothing was reported
+        let cleared = self.suppress_crossed
+            && self
+                .book
+                .state_change()
+                .is_some_and(|change| change.from == BookState::Crossed);
+        self.report_side(side, sequence);
+        if cleared {
+            self.report_side(!side, sequence);
+        }
+    }
+
+    fn report_side(&mut self, side: OrderSide, sequence: u64) {
+        for target in self.targets.iter_mut() {
+            let cur = self.book.summarise_side(!side, target.target_size);
+            let prev = match side {
+                OrderSide::Ask => &mut target.last_ask_report,
+                OrderSide::Bid => &mut target.last_bid_report,
+            };
+            if *prev == cur {
+                continue;
+            }
+            *prev = cur;
+            self.events.push(PricerEvent {
+                timestamp: self.book.last_action_timestamp,
+                side,
+                value: cur,
+                target_size: target.target_size,
+                sequence,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use std::collections::HashMap;
+
+    #[test]
+    fn event_display_with_amount() {
+        let event = PricerEvent {
+            timestamp: 28800758,
+            side: OrderSide::Ask,
+            value: Some(Amount::new_from_str("8832.56")),
+            target_size: 200,
+            sequence: 4,
+        };
+        assert_eq!(event.to_string(), "28800758 S 8832.56");
+    }
+
+    #[test]
+    fn event_display_without_amount() {
+        let event = PricerEvent {
+            timestamp: 28800796,
+            side: OrderSide::Ask,
+            value: None,
+            target_size: 200,
+            sequence: 6,
+        };
+        assert_eq!(event.to_string(), "28800796 S NA");
+    }
+
+    #[test]
+    fn no_event_until_target_is_reachable() {
+        let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        assert_eq!(pricer.process("28800538 A b S 44.26 100"), None);
+        assert_eq!(pricer.process("28800562 A c B 44.10 100"), None);
+        assert_eq!(
+            pricer.process("28800758 A d B 44.18 157"),
+            Some(PricerEvent {
+                timestamp: 28800758,
+                side: OrderSide::Ask,
+                value: Some(Amount::new_from_str("8832.56")),
+                target_size: 200,
+                sequence: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn unchanged_value_is_not_reported_twice() {
+        let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        pricer.process("28800538 A b B 44.26 300");
+        // deeper levels don't change the cost of the top 200
+        assert_eq!(pricer.process("28800539 A c B 44.10 100"), None);
+        assert_eq!(
+            pricer.process("28800540 R b 300"),
+            Some(PricerEvent {
+                timestamp: 28800540,
+                side: OrderSide::Ask,
+                value: None,
+                target_size: 200,
+                sequence: 3,
+            })
+        );
+        assert_eq!(pricer.process("28800541 R c 100"), None);
+        assert_eq!(pricer.messages(), 4);
+    }
+
+    #[test]
+    fn several_target_sizes() {
+        let book = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        let mut pricer = Pricer::with_targets(book, &[100, 200]);
+        let events = pricer.try_process("28800538 A b B 44.26 100").unwrap();
+        assert_eq!(
+            events,
+            &[PricerEvent {
+                timestamp: 28800538,
+                side: OrderSide::Ask,
+                value: Some(Amount::new_from_str("4426.00")),
+                target_size: 100,
+                sequence: 1,
+            }]
+        );
+        let events = pricer.try_process("28800539 A c B 44.10 100").unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].target_size, 200);
+        assert_eq!(events[0].value, Some(Amount::new_from_str("8836.00")));
+    }
+
+    #[test]
+    fn try_process_errors_leave_no_events() {
+        let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        pricer.process("28800538 A b B 44.26 300");
+        assert_eq!(pricer.events().len(), 1);
+        assert!(pricer.try_process("28800539 R zz 100").is_err());
+        assert!(pricer.events().is_empty());
+        assert_eq!(pricer.messages(), 1);
+    }
+
+    #[test]
+    fn events_carry_the_input_position_or_sequence_number() {
+        let mut pricer = Pricer::new(OrderBook::new(100, IdPriceCacheFnvMap::default()));
+        assert!(pricer.try_process("28800537 R zz 100").is_err());
+        assert!(pricer.try_process("28800537 X").is_err());
+        let events = pricer.try_process("28800538 A b B 44.26 100").unwrap();
+        assert_eq!(events[0].sequence, 3);
+        let events = pricer.try_process("28800539 A c B 44.30 100 17").unwrap();
+        assert_eq!(events[0].sequence, 17);
+        assert_eq!(pricer.reprice(42), &[]);
+        assert_eq!(pricer.messages(), 3);
+    }
+
+    #[test]
+    fn basic_feed_matches_expected_output() {
+        let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        let output: Vec<String> = include_str!("../data/basic.in.txt")
+            .lines()
+            .filter_map(|line| pricer.process(line))
+            .map(|event| event.to_string())
+            .collect();
+        let expected: Vec<&str> = include_str!("../data/basic.out.txt").lines().collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn try_apply_matches_try_process() {
+        let mut by_line = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        let mut by_message = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        for line in include_str!("../data/basic.in.txt").lines() {
+            let expected = by_line.try_process(line).unwrap().to_vec();
+            let message = Message::parse(line).unwrap();
+            assert_eq!(by_message.try_apply(&message).unwrap(), &expected[..]);
+        }
+    }
+
+    #[test]
+    fn level_updates_price_like_orders() {
+        // turns the basic feed into the level updates a market by price feed would send
+        let mut by_order = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        let mut by_level = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        let mut prices = HashMap::new();
+        for line in include_str!("../data/basic.in.txt").lines() {
+            let expected = by_order.try_process(line).unwrap().to_vec();
+            let (side, price) = match Message::parse(line).unwrap() {
+                Message::Add {
+                    id, side, price, ..
+                } => *prices.entry(id).or_insert((side, price)),
+                Message::Reduce { id, .. } => prices[id],
+            };
+            let depth = by_order
+                .book()
+                .levels(side)
+                .find(|&(level, _)| level == price)
+                .map_or(0, |(_, depth)| depth);
+            let update = LevelUpdate::Set {
+                timestamp: Message::parse(line).unwrap().timestamp(),
+                side,
+                price,
+                depth,
+            };
+            assert_eq!(
+                by_level.try_apply_level(&update).unwrap(),
+                &expected[..],
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn suppresses_output_while_crossed() {
+        let feed = [
+            "28800538 A b S 44.26 100",
+            "28800539 A c B 44.10 300",
+            "28800540 A d B 44.30 100", // crosses
+            "28800541 A e S 44.40 300",
+            "28800542 R b 100", // clears
+        ];
+        let run = |suppress: bool| {
+            let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+            pricer.suppress_while_crossed(suppress);
+            feed.iter()
+                .map(|line| pricer.try_process(line).unwrap().len())
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(run(false), vec![0, 1, 1, 1, 1]);
+        // clearing also reports the bids d was added to while crossed
+        assert_eq!(run(true), vec![0, 1, 0, 0, 2]);
+    }
+
+    #[test]
+    fn clearing_reports_the_side_changed_while_crossed() {
+        let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        pricer.suppress_while_crossed(true);
+        pricer.try_process("28800538 A b S 44.26 100").unwrap();
+        pricer.try_process("28800539 A c B 44.10 300").unwrap();
+        // crosses
+        pricer.try_process("28800540 A d B 44.30 100").unwrap();
+        // buying 200 becomes possible, but the book is crossed
+        assert!(pricer
+            .try_process("28800541 A e S 44.40 300")
+            .unwrap()
+            .is_empty());
+        // clearing touches the bids, the asks' new cost is reported along with them
+        let events = pricer.try_process("28800542 R d 100").unwrap();
+        assert_eq!(
+            events,
+            &[PricerEvent {
+                timestamp: 28800542,
+                side: OrderSide::Bid,
+                value: Some(Amount::new_from_str("8866.00")),
+                target_size: 200,
+                sequence: 5,
+            }]
+        );
+        assert_eq!(
+            pricer.last_reported(OrderSide::Bid),
+            Some(Amount::new_from_str("8866.00"))
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use idpricecache::IdPriceCache;
+use orderbook::OrderBook;
+use orderside::OrderSide;
+
+// Lets the running book be looked at over a Unix domain socket, one query per
+// line and one answer line back:
+//   best           -> bid 44.26 100 ask 44.30 200   (NA for an empty side)
+//   levels B 3     -> 44.26 100 44.10 500            (best first, NA if none)
+//   order b        -> B 44.26                        (cached side and price, NA if unknown)
+//   totals         -> bid 600 ask 200
+//   price S 200    -> 8832.56                        (as the pricer would, NA if short)
+// Anything else gets "error <why>". Client connections are read on threads of
+// their own, the book answers whatever has queued up between messages, and
+// while the feed is quiet as long as it's read apart (see pipeline::read_apart).
+
+/// A question for the book, parsed from a line
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Query {
+    Best,
+    /// top levels of a side, B for bids and S for asks
+    Levels(OrderSide, usize),
+    /// price and side cached for an order id
+    Order(String),
+    Totals,
+    /// income from selling (S) or expense of buying (B) size shares
+    Price(OrderSide, i64),
+}
+
+fn side(field: Option<&str>) -> Result<OrderSide, String> {
+    match field {
+        Some("B") => Ok(OrderSide::Bid),
+        Some("S") => Ok(OrderSide::Ask),
+        Some(other) => Err(format!("Bad side {}, expected B or S", other)),
+        None => Err("Missing side, expected B or S".to_string()),
+    }
+}
+
+fn count<N: FromStr + PartialOrd + Default>(field: Option<&str>) -> Result<N, String> {
+    match field.map(|field| (field, field.parse::<N>())) {
+        Some((_, Ok(n))) if n > N::default() => Ok(n),
+        Some((field, _)) => Err(format!("Bad count {}, expected a positive number", field)),
+        None => Err("Missing count".to_string()),
+    }
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let query = match fields.next() {
+            Some("best") => Query::Best,
+            Some("levels") => Query::Levels(side(fields.next())?, count(fields.next())?),
+            Some("order") => match fields.next() {
+                Some(id) => Query::Order(id.to_string()),
+                None => return Err("Missing order id".to_string()),
+            },
+            Some("totals") => Query::Totals,
+            Some("price") => Query::Price(side(fields.next())?, count(fields.next())?),
+            other => {
+                return Err(format!(
+                    "Unknown query {}, expected one of best, levels, order, totals, price",
+                    other.unwrap_or("")
+                ))
+            }
+        };
+        match fields.next() {
+            Some(extra) => Err(format!("Unexpected {} after the query", extra)),
+            None => Ok(query),
+        }
+    }
+}
+
+/// The answer line for the query, without the newline
+pub fn answer<T: IdPriceCache>(book: &OrderBook<T>, query: &Query) -> String {
+    let level = |level: Option<(_, _)>| match level {
+        Some((price, depth)) => format!("{} {}", price, depth),
+        None => "NA".to_string(),
+    };
+    match *query {
+        Query::Best => format!(
+            "bid {} ask {}",
+            level(book.best_bid()),
+            level(book.best_ask())
+        ),
+        Query::Levels(side, n) => {
+            let levels: Vec<String> = book
+                .levels(side)
+                .take(n)
+                .map(|(price, depth)| format!("{} {}", price, depth))
+                .collect();
+            if levels.is_empty() {
+                "NA".to_string()
+            } else {
+                levels.join(" ")
+            }
+        }
+        Query::Order(ref id) => match book.order(id) {
+            Some((price, side)) => format!("{} {}", side, price),
+            None => "NA".to_string(),
+        },
+        Query::Totals => format!(
+            "bid {} ask {}",
+            book.total_size(OrderSide::Bid),
+            book.total_size(OrderSide::Ask)
+        ),
+        // selling fills against the bids, buying against the asks
+        Query::Price(side, size) => match book.summarise_side(!side, size) {
+            Some(amount) => amount.to_string(),
+            None => "NA".to_string(),
+        },
+    }
+}
+
+type Request = (Query, Sender<String>);
+
+/// Takes queries on a Unix domain socket and hands them to whoever owns the book,
+/// see poll. The socket file goes away with the server
+pub struct QueryServer {
+    path: PathBuf,
+    requests: Receiver<Request>,
+}
+
+impl QueryServer {
+    /// Listens on path, replacing a socket left behind by a server that's gone
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        thread::spawn(move || serve_client(stream, sender));
+                    }
+                    Err(err) => eprintln!("order_book: cannot take query client: {}", err),
+                }
+            }
+        });
+        Ok(QueryServer { path, requests })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Answers every query waiting, from the book as it stands. Never blocks,
+    /// returns how many were answered
+    pub fn poll<T: IdPriceCache>(&self, book: &OrderBook<T>) -> usize {
+        let mut answered = 0;
+        while let Ok((query, reply)) = self.requests.try_recv() {
+            let _ = reply.send(answer(book, &query)); // the client may have hung up
+            answered += 1;
+        }
+        answered
+    }
+}
+
+impl Drop for QueryServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// a socket nobody answers on is left over from an earlier run
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is being served already", path.display()),
+                ));
+            }
+            fs::remove_file(path)
+        }
+        _ => Ok(()),
+    }
+}
+
+// client thread, waits on the book for each answer in turn
+fn serve_client(stream: UnixStream, requests: Sender<Request>) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match line.parse::<Query>() {
+            Ok(query) => {
+                let (reply, answer) = mpsc::channel();
+                let closed = "error book closed".to_string();
+                if requests.send((query, reply)).is_err() {
+                    closed
+                } else {
+                    answer.recv().unwrap_or(closed)
+                }
+            }
+            Err(err) => format!("error {}", err),
+        };
+        writeln!(out, "{}", response)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use pipeline::read_apart;
+    use std::env;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn book() -> OrderBook<IdPriceCacheFnvMap> {
+        let mut book = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        book.process("28800538 A b B 44.26 100");
+        book.process("28800562 A c B 44.10 500");
+        book.process("28800744 A d S 44.30 200");
+        book
+    }
+
+    fn ask(book: &OrderBook<IdPriceCacheFnvMap>, query: &str) -> String {
+        answer(book, &query.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_queries() {
+        assert_eq!("best".parse(), Ok(Query::Best));
+        assert_eq!(" levels S 3 ".parse(), Ok(Query::Levels(OrderSide::Ask, 3)));
+        assert_eq!("order b".parse(), Ok(Query::Order("b".to_string())));
+        assert_eq!("price B 200".parse(), Ok(Query::Price(OrderSide::Bid, 200)));
+        assert!("levels X 3".parse::<Query>().is_err());
+        assert!("levels B 0".parse::<Query>().is_err());
+        assert!("price S".parse::<Query>().is_err());
+        assert!("totals please".parse::<Query>().is_err());
+        assert!("depth".parse::<Query>().is_err());
+    }
+
+    #[test]
+    fn answers() {
+        let book = book();
+        assert_eq!(ask(&book, "best"), "bid 44.26 100 ask 44.30 200");
+        assert_eq!(ask(&book, "levels B 5"), "44.26 100 44.10 500");
+        assert_eq!(ask(&book, "levels S 1"), "44.30 200");
+        assert_eq!(ask(&book, "order c"), "B 44.10");
+        assert_eq!(ask(&book, "order z"), "NA");
+        assert_eq!(ask(&book, "totals"), "bid 600 ask 200");
+        assert_eq!(ask(&book, "price S 200"), "8836.00");
+        assert_eq!(ask(&book, "price B 200"), "8860.00");
+        assert_eq!(ask(&book, "price B 201"), "NA");
+
+        let empty = OrderBook::new(200, IdPriceCacheFnvMap::default());
+        assert_eq!(ask(&empty, "best"), "bid NA ask NA");
+        assert_eq!(ask(&empty, "levels S 2"), "NA");
+    }
+
+    #[test]
+    fn socket_round_trip() {
+        let path = env::temp_dir().join(format!("order_book_query_{}.sock", process::id()));
+        let server = QueryServer::bind(&path).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let finished = done.clone();
+        // stands in for the ingestion loop, polling between messages
+        let book = thread::spawn(move || {
+            let book = book();
+            while !finished.load(Ordering::SeqCst) {
+                server.poll(&book);
+                thread::yield_now();
+            }
+        });
+
+        let client = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(client.try_clone().unwrap()).lines();
+        let mut query = |line: &str| {
+            writeln!(&client, "{}", line).unwrap();
+            lines.next().unwrap().unwrap()
+        };
+        assert_eq!(query("best"), "bid 44.26 100 ask 44.30 200");
+        assert_eq!(query("order b"), "B 44.26");
+        assert!(query("levels").starts_with("error "));
+        assert_eq!(query("totals"), "bid 600 ask 200");
+
+        done.store(true, Ordering::SeqCst);
+        book.join().unwrap();
+        assert_eq!(query("best"), "error book closed");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn answers_while_the_feed_is_quiet() {
+        let path = env::temp_dir().join(format!("order_book_query_quiet_{}.sock", process::id()));
+        let server = QueryServer::bind(&path).unwrap();
+        let (quiet, gone_quiet) = mpsc::channel();
+        let (resume, resumed) = mpsc::channel::<()>();
+        // the feed sends two messages, then nothing until the client is done
+        let read = move |send: &mut dyn FnMut(&'static str) -> bool| {
+            send("28800538 A b B 44.26 100");
+            send("28800744 A d S 44.30 200");
+            quiet.send(()).unwrap();
+            let _ = resumed.recv();
+            Ok(())
+        };
+        let book = thread::spawn(move || {
+            let mut book = OrderBook::new(200, IdPriceCacheFnvMap::default());
+            read_apart(16, read, |line| {
+                match line {
+                    Some(line) => book.process(line),
+                    None => {
+                        server.poll(&book);
+                    }
+                }
+                Ok(true)
+            })
+        });
+
+        gone_quiet.recv().unwrap();
+        let client = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(client.try_clone().unwrap()).lines();
+        let mut query = |line: &str| {
+            writeln!(&client, "{}", line).unwrap();
+            lines.next().unwrap().unwrap()
+        };
+        assert_eq!(query("best"), "bid 44.26 100 ask 44.30 200");
+        assert_eq!(query("levels S 5"), "44.30 200");
+
+        resume.send(()).unwrap();
+        assert_eq!(book.join().unwrap(), Ok(()));
+    }
+}
@@ -0,0 +1,240 @@
+use std::cmp::Reverse;
+
+use amount::Amount;
+use orderbook::{BookError, Depth};
+use orders::Message;
+use orderside::OrderSide;
+
+// A book written to be obviously right rather than fast, to check OrderBook
+// against. It keeps every resting order in a plain list and works everything
+// out from that list each time it's asked: no levels, no running totals, no
+// cache. Reduces are taken to never ask for more than the order has left, the
+// books part ways on feeds where they do.
+
+#[derive(Clone, Debug)]
+struct RestingOrder {
+    id: String,
+    side: OrderSide,
+    price: Amount,
+    left: i64,
+}
+
+pub struct ReferenceBook {
+    target_size: i64,
+    orders: Vec<RestingOrder>, // in the order they came in
+    last_action_side: OrderSide,
+}
+
+impl ReferenceBook {
+    pub fn new(target_size: i64) -> Self {
+        ReferenceBook {
+            target_size,
+            orders: Vec::new(),
+            last_action_side: OrderSide::Ask, // same as OrderBook
+        }
+    }
+
+    pub fn apply(&mut self, message: &Message) -> Result<(), BookError> {
+        match *message {
+            Message::Add {
+                id,
+                side,
+                price,
+                size,
+                ..
+            } => {
+                self.orders.push(RestingOrder {
+                    id: id.to_string(),
+                    side,
+                    price,
+                    left: size,
+                });
+                self.last_action_side = side;
+            }
+            Message::Reduce { id, size, .. } => {
+                // a reused id means the latest order under it
+                let idx = match self.orders.iter().rposition(|order| order.id == id) {
+                    Some(idx) => idx,
+                    None => return Err(BookError::UnknownOrder(id.to_string())),
+                };
+                self.orders[idx].left -= size;
+                self.last_action_side = self.orders[idx].side;
+                if self.orders[idx].left <= 0 {
+                    self.orders.remove(idx);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Orders on the side, best price first and oldest first at the same price
+    fn side(&self, side: OrderSide) -> Vec<&RestingOrder> {
+        let mut orders: Vec<_> = self
+            .orders
+            .iter()
+            .filter(|order| order.side == side)
+            .collect();
+        match side {
+            OrderSide::Bid => orders.sort_by_key(|order| Reverse(order.price)),
+            OrderSide::Ask => orders.sort_by_key(|order| order.price),
+        }
+        orders
+    }
+
+    pub fn total_size(&self, side: OrderSide) -> i64 {
+        self.side(side).iter().map(|order| order.left).sum()
+    }
+
+    /// Price levels with their depth, best price first
+    pub fn levels(&self, side: OrderSide) -> Vec<(Amount, Depth)> {
+        let mut levels: Vec<(Amount, Depth)> = Vec::new();
+        for order in self.side(side) {
+            match levels.last_mut() {
+                Some(level) if level.0 == order.price => level.1 += order.left,
+                _ => levels.push((order.price, order.left)),
+            }
+        }
+        levels
+    }
+
+    /// What OrderBook::summarise_target should come to
+    pub fn summarise_target(&self) -> Option<Amount> {
+        self.summarise_side(self.last_action_side, self.target_size)
+    }
+
+    /// Bid is the income from selling target_size into the bids, Ask the expense of
+    /// buying target_size off the asks, None if the side doesn't hold that many
+    pub fn summarise_side(&self, side: OrderSide, target_size: i64) -> Option<Amount> {
+        let mut amount = Amount::new();
+        let mut wanted = target_size;
+        for order in self.side(side) {
+            let taken = wanted.min(order.left);
+            amount += order.price * taken;
+            wanted -= taken;
+        }
+        if wanted > 0 {
+            None
+        } else {
+            Some(amount)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generate::{FeedConfig, FeedGenerator};
+    use idpricecache::IdPriceCacheFnvMap;
+    use orderbook::OrderBook;
+
+    const SIDES: [OrderSide; 2] = [OrderSide::Bid, OrderSide::Ask];
+
+    // runs the lines through both books, checking they agree after every one
+    fn differential<I: IntoIterator<Item = String>>(target_size: i64, lines: I) -> usize {
+        let mut book = OrderBook::new(target_size, IdPriceCacheFnvMap::default());
+        let mut reference = ReferenceBook::new(target_size);
+        let mut checked = 0;
+        for line in lines {
+            let message = Message::parse(&line).unwrap();
+            // both books take the message, or both refuse it
+            let applied = (
+                book.apply(&message).is_ok(),
+                reference.apply(&message).is_ok(),
+            );
+            assert_eq!(applied.0, applied.1, "{}", line);
+            assert_eq!(
+                book.summarise_target(),
+                reference.summarise_target(),
+                "{}",
+                line
+            );
+            for &side in &SIDES {
+                assert_eq!(book.total_size(side), reference.total_size(side));
+                assert_eq!(
+                    book.levels(side).collect::<Vec<_>>(),
+                    reference.levels(side)
+                );
+                for &size in &[1, target_size / 3, target_size * 5] {
+                    assert_eq!(
+                        book.summarise_side(side, size),
+                        reference.summarise_side(side, size)
+                    );
+                }
+            }
+            checked += 1;
+        }
+        checked
+    }
+
+    fn generated(config: FeedConfig) -> impl Iterator<Item = String> {
+        FeedGenerator::new(config).map(|message| message.as_message().to_string())
+    }
+
+    #[test]
+    fn agrees_on_the_basic_feed() {
+        let lines = include_str!("../data/basic.in.txt")
+            .lines()
+            .filter(|line| Message::parse(line).is_ok())
+            .map(|line| line.to_string());
+        assert!(differential(200, lines) > 0);
+    }
+
+    #[test]
+    fn agrees_on_generated_feeds() {
+        let configs = [
+            FeedConfig::default(),
+            // few orders spread thin, so the target often can't be met
+            FeedConfig {
+                live_orders: 20,
+                depth_ticks: 50,
+                max_size: 40,
+                ..FeedConfig::default()
+            },
+            // a busy narrow book, mostly partial reduces and a restless mid
+            FeedConfig {
+                live_orders: 400,
+                depth_ticks: 3,
+                walk: 0.5,
+                add_ratio: 0.7,
+                partial_ratio: 0.9,
+                tick: Amount { as_int: 25 },
+                ..FeedConfig::default()
+            },
+        ];
+        for (n, config) in configs.iter().enumerate() {
+            for seed in 1..3 {
+                let config = FeedConfig {
+                    messages: 2000,
+                    seed: seed + 10 * n as u64,
+                    ..config.clone()
+                };
+                assert_eq!(differential(200, generated(config)), 2000);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_orders_and_reused_ids() {
+        let lines = [
+            "1 A a B 10.00 100",
+            "2 R b 10",
+            "3 A a S 11.00 50",
+            "4 R a 20",
+            "5 A c S 10.50 100",
+            "6 R a 30",
+        ];
+        differential(60, lines.iter().map(|line| line.to_string()));
+        let mut reference = ReferenceBook::new(60);
+        for line in &lines {
+            let _ = reference.apply(&Message::parse(line).unwrap());
+        }
+        assert_eq!(
+            reference.levels(OrderSide::Ask),
+            vec![(Amount::new_from_str("10.50"), 100)]
+        );
+        assert_eq!(
+            reference.summarise_target(),
+            Some(Amount::new_from_str("630.00"))
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+// How many gaps to remember, so late arrivals can be told apart from duplicates.
+// Anything from older gaps arriving late is taken for a duplicate
+const MAX_TRACKED_GAPS: usize = 4096;
+
+/// Something off about where a message sits in the feed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeqIssue {
+    /// messages expected..found never arrived (yet)
+    Gap { expected: u64, found: u64 },
+    /// seen this sequence number before, the message is dropped
+    Duplicate(u64),
+    /// a message that was skipped over by a gap turned up late
+    OutOfOrder { expected: u64, found: u64 },
+    /// the last sequence number a u64 holds, nothing could follow it so the message is dropped
+    OutOfRange(u64),
+    /// feeds without sequence numbers should at least keep time moving forward
+    TimestampWentBack { previous: i64, found: i64 },
+}
+
+impl Display for SeqIssue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            SeqIssue::Gap { expected, found } => write!(
+                f,
+                "sequence gap, expected {} got {} ({} missing)",
+                expected,
+                found,
+                found - expected
+            ),
+            SeqIssue::Duplicate(seq) => write!(f, "duplicate sequence number {}", seq),
+            SeqIssue::OutOfOrder { expected, found } => write!(
+                f,
+                "sequence number {} arrived late, expected {}",
+                found, expected
+            ),
+            SeqIssue::OutOfRange(seq) => {
+                write!(f, "sequence number {} leaves no room for the next one", seq)
+            }
+            SeqIssue::TimestampWentBack { previous, found } => {
+                write!(f, "timestamp {} is before {}", found, previous)
+            }
+        }
+    }
+}
+
+/// Asks whoever feeds the book for a fresh snapshot after a gap
+pub type SnapshotRequest = Box<dyn FnMut(&SeqIssue) + Send>;
+
+/// What the book does about a SeqIssue
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeqPolicy {
+    /// refuse the message with BookError::Sequence
+    Halt,
+    /// carry on, the issue is left in last_issue for whoever is watching
+    Warn,
+    /// as Warn, and gaps call the snapshot request callback
+    RequestSnapshot,
+}
+
+impl FromStr for SeqPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halt" => Ok(SeqPolicy::Halt),
+            "warn" => Ok(SeqPolicy::Warn),
+            "snapshot" => Ok(SeqPolicy::RequestSnapshot),
+            other => Err(format!(
+                "Unknown sequence policy {}, expected one of halt, warn, snapshot",
+                other
+            )),
+        }
+    }
+}
+
+/// What to do with the message that was checked
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeqVerdict {
+    Apply,
+    /// already applied once
+    Drop,
+}
+
+/// Follows sequence numbers, or timestamps when the feed has none, message by message
+pub struct SequenceTracker {
+    policy: SeqPolicy,
+    next_seq: Option<u64>,
+    last_timestamp: Option<i64>,
+    missing: BTreeMap<u64, u64>, // start -> end of each run of sequence numbers not seen yet
+    last_issue: Option<SeqIssue>, // raised by the last message
+    issues: u64,
+    request_snapshot: Option<SnapshotRequest>,
+}
+
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        SequenceTracker::new(SeqPolicy::Warn)
+    }
+}
+
+impl SequenceTracker {
+    pub fn new(policy: SeqPolicy) -> Self {
+        SequenceTracker {
+            policy,
+            next_seq: None,
+            last_timestamp: None,
+            missing: BTreeMap::new(),
+            last_issue: None,
+            issues: 0,
+            request_snapshot: None,
+        }
+    }
+
+    pub fn policy(&self) -> SeqPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: SeqPolicy) {
+        self.policy = policy;
+    }
+
+    /// Called with the gap whenever one shows up under SeqPolicy::RequestSnapshot
+    pub fn on_snapshot_request<F: FnMut(&SeqIssue) + Send + 'static>(&mut self, request: F) {
+        self.request_snapshot = Some(Box::new(request));
+    }
+
+    /// Issue raised by the last message checked, if any
+    pub fn last_issue(&self) -> Option<SeqIssue> {
+        self.last_issue
+    }
+
+    /// How many issues were raised so far
+    pub fn issues(&self) -> u64 {
+        self.issues
+    }
+
+    /// Sequence number the next message should carry
+    pub fn next_seq(&self) -> Option<u64> {
+        self.next_seq
+    }
+
+    /// Checks the next message. Err is the issue when the policy is Halt
+    pub fn check(&mut self, seq: Option<u64>, timestamp: i64) -> Result<SeqVerdict, SeqIssue> {
+        self.last_issue = None;
+        let (issue, verdict) = match seq {
+            Some(seq) => self.check_seq(seq),
+            None => (self.check_timestamp(timestamp), SeqVerdict::Apply),
+        };
+        let issue = match issue {
+            Some(issue) => issue,
+            None => {
+                self.advance(seq, timestamp);
+                return Ok(verdict);
+            }
+        };
+        self.issues += 1;
+        self.last_issue = Some(issue);
+        match self.policy {
+            SeqPolicy::Halt => return Err(issue),
+            SeqPolicy::Warn => {}
+            SeqPolicy::RequestSnapshot => {
+                if let SeqIssue::Gap { .. } = issue {
+                    if let Some(ref mut request) = self.request_snapshot {
+                        request(&issue);
+                    }
+                }
+            }
+        }
+        if verdict == SeqVerdict::Apply {
+            self.advance(seq, timestamp);
+        }
+        Ok(verdict)
+    }
+
+    fn check_seq(&mut self, seq: u64) -> (Option<SeqIssue>, SeqVerdict) {
+        if seq.checked_add(1).is_none() {
+            return (Some(SeqIssue::OutOfRange(seq)), SeqVerdict::Drop);
+        }
+        let expected = match self.next_seq {
+            Some(expected) => expected,
+            None => return (None, SeqVerdict::Apply), // first message sets the start
+        };
+        if seq == expected {
+            (None, SeqVerdict::Apply)
+        } else if seq > expected {
+            (
+                Some(SeqIssue::Gap {
+                    expected,
+                    found: seq,
+                }),
+                SeqVerdict::Apply,
+            )
+        } else if self.missing_range(seq).is_some() {
+            (
+                Some(SeqIssue::OutOfOrder {
+                    expected,
+                    found: seq,
+                }),
+                SeqVerdict::Apply,
+            )
+        } else {
+            (Some(SeqIssue::Duplicate(seq)), SeqVerdict::Drop)
+        }
+    }
+
+    // the run of missing sequence numbers seq is in
+    fn missing_range(&self, seq: u64) -> Option<(u64, u64)> {
+        match self.missing.range(..=seq).next_back() {
+            Some((&start, &end)) if seq < end => Some((start, end)),
+            _ => None,
+        }
+    }
+
+    fn check_timestamp(&self, timestamp: i64) -> Option<SeqIssue> {
+        match self.last_timestamp {
+            Some(previous) if timestamp < previous => Some(SeqIssue::TimestampWentBack {
+                previous,
+                found: timestamp,
+            }),
+            _ => None,
+        }
+    }
+
+    // only called for messages that get applied, which check_seq keeps below u64::MAX
+    fn advance(&mut self, seq: Option<u64>, timestamp: i64) {
+        if self
+            .last_timestamp
+            .map_or(true, |previous| timestamp > previous)
+        {
+            self.last_timestamp = Some(timestamp);
+        }
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return,
+        };
+        match self.next_seq {
+            Some(expected) if seq < expected => {
+                // a late arrival splits its run in two
+                if let Some((start, end)) = self.missing_range(seq) {
+                    self.missing.remove(&start);
+                    if start < seq {
+                        self.missing.insert(start, seq);
+                    }
+                    if seq + 1 < end {
+                        self.missing.insert(seq + 1, end);
+                    }
+                }
+            }
+            Some(expected) => {
+                if expected < seq {
+                    self.missing.insert(expected, seq);
+                }
+                while self.missing.len() > MAX_TRACKED_GAPS {
+                    self.missing.pop_first();
+                }
+                self.next_seq = Some(seq + 1);
+            }
+            None => self.next_seq = Some(seq + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn check_all(tracker: &mut SequenceTracker, seqs: &[u64]) -> Vec<Option<SeqIssue>> {
+        seqs.iter()
+            .map(|&seq| {
+                tracker.check(Some(seq), 0).unwrap();
+                tracker.last_issue()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn in_order_is_clean() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(check_all(&mut tracker, &[5, 6, 7]), vec![None, None, None]);
+        assert_eq!(tracker.next_seq(), Some(8));
+        assert_eq!(tracker.issues(), 0);
+    }
+
+    #[test]
+    fn gap_late_arrival_and_duplicate() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(
+            check_all(&mut tracker, &[1, 2, 5, 3, 3, 6]),
+            vec![
+                None,
+                None,
+                Some(SeqIssue::Gap {
+                    expected: 3,
+                    found: 5
+                }),
+                Some(SeqIssue::OutOfOrder {
+                    expected: 6,
+                    found: 3
+                }),
+                Some(SeqIssue::Duplicate(3)),
+                None,
+            ]
+        );
+        assert_eq!(tracker.issues(), 3);
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(Some(1), 0), Ok(SeqVerdict::Apply));
+        assert_eq!(tracker.check(Some(1), 0), Ok(SeqVerdict::Drop));
+        assert_eq!(tracker.check(Some(2), 0), Ok(SeqVerdict::Apply));
+    }
+
+    #[test]
+    fn halt_refuses_and_stays_put() {
+        let mut tracker = SequenceTracker::new(SeqPolicy::Halt);
+        tracker.check(Some(1), 0).unwrap();
+        let gap = SeqIssue::Gap {
+            expected: 2,
+            found: 4,
+        };
+        assert_eq!(tracker.check(Some(4), 0), Err(gap));
+        assert_eq!(tracker.next_seq(), Some(2));
+        assert_eq!(tracker.check(Some(2), 0), Ok(SeqVerdict::Apply));
+    }
+
+    #[test]
+    fn gaps_request_snapshots() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let mut tracker = SequenceTracker::new(SeqPolicy::RequestSnapshot);
+        tracker.on_snapshot_request(move |issue| seen.lock().unwrap().push(*issue));
+        check_all(&mut tracker, &[1, 3, 3, 10]);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                SeqIssue::Gap {
+                    expected: 2,
+                    found: 3
+                },
+                SeqIssue::Gap {
+                    expected: 4,
+                    found: 10
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps_without_sequence_numbers() {
+        let mut tracker = SequenceTracker::default();
+        tracker.check(None, 28800538).unwrap();
+        tracker.check(None, 28800538).unwrap();
+        assert_eq!(tracker.last_issue(), None);
+        tracker.check(None, 28800537).unwrap();
+        assert_eq!(
+            tracker.last_issue(),
+            Some(SeqIssue::TimestampWentBack {
+                previous: 28800538,
+                found: 28800537
+            })
+        );
+        // compared against the latest time seen, not the message that went back
+        tracker.check(None, 28800537).unwrap();
+        assert!(tracker.last_issue().is_some());
+    }
+
+    #[test]
+    fn huge_gaps_are_tracked_whole() {
+        let mut tracker = SequenceTracker::default();
+        check_all(&mut tracker, &[1, 1_000_000]);
+        assert_eq!(tracker.missing.len(), 1);
+        let late = |found| {
+            Some(SeqIssue::OutOfOrder {
+                expected: 1_000_001,
+                found,
+            })
+        };
+        assert_eq!(
+            check_all(&mut tracker, &[2, 500_000, 999_999, 500_000]),
+            vec![
+                late(2),
+                late(500_000),
+                late(999_999),
+                Some(SeqIssue::Duplicate(500_000)),
+            ]
+        );
+        assert_eq!(
+            tracker.missing.iter().collect::<Vec<_>>(),
+            vec![(&3, &500_000), (&500_001, &999_999)]
+        );
+    }
+
+    #[test]
+    fn only_the_latest_gaps_are_remembered() {
+        let mut tracker = SequenceTracker::default();
+        let seqs: Vec<u64> = (0..MAX_TRACKED_GAPS as u64 + 2).map(|n| n * 2).collect();
+        check_all(&mut tracker, &seqs);
+        assert_eq!(tracker.missing.len(), MAX_TRACKED_GAPS);
+        // the first gap, 1, was forgotten
+        assert_eq!(tracker.check(Some(1), 0), Ok(SeqVerdict::Drop));
+        assert_eq!(tracker.check(Some(3), 0), Ok(SeqVerdict::Apply));
+    }
+
+    #[test]
+    fn last_sequence_number_is_dropped() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(Some(u64::MAX), 0), Ok(SeqVerdict::Drop));
+        assert_eq!(tracker.last_issue(), Some(SeqIssue::OutOfRange(u64::MAX)));
+        assert_eq!(tracker.next_seq(), None);
+        check_all(&mut tracker, &[1]);
+        assert_eq!(tracker.check(Some(u64::MAX), 0), Ok(SeqVerdict::Drop));
+        assert_eq!(tracker.next_seq(), Some(2));
+        let mut tracker = SequenceTracker::new(SeqPolicy::Halt);
+        assert_eq!(
+            tracker.check(Some(u64::MAX), 0),
+            Err(SeqIssue::OutOfRange(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn issue_display_and_policies() {
+        assert_eq!(
+            SeqIssue::Gap {
+                expected: 3,
+                found: 5
+            }
+            .to_string(),
+            "sequence gap, expected 3 got 5 (2 missing)"
+        );
+        assert_eq!("snapshot".parse(), Ok(SeqPolicy::RequestSnapshot));
+        assert!("ignore".parse::<SeqPolicy>().is_err());
+    }
+}
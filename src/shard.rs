@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+use std::thread;
+
+use idpricecache::IdPriceCache;
+use orderbook::BookError;
+use orders::{hash, Message};
+use pipeline::{OwnedMessage, Priced};
+use pricer::{Pricer, PricerEvent};
+use sequence::{SeqIssue, SeqVerdict, SequenceTracker};
+use spsc::{self, Consumer, Producer};
+
+// Prices a feed of many instruments on several workers. Lines are the usual
+// text lines with the symbol up front, "AAPL 28800538 A b S 44.26 100".
+// A dispatcher parses them, checks sequence numbers for the feed as a whole and
+// hands each message to the worker its symbol hashes to. Every worker keeps a
+// Pricer (so a book and a cache) per symbol. The merge follows the dispatcher's
+// routing, so results come out in input order however many workers there are.
+
+/// Splits the symbol off the front of a line
+pub fn split_symbol(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    match line.find(' ') {
+        Some(idx) => (&line[..idx], &line[idx + 1..]),
+        None => (line, ""),
+    }
+}
+
+/// Worker the symbol belongs to, the same on every run
+pub fn shard_of(symbol: &str, shards: usize) -> usize {
+    (hash(symbol) % shards as u64) as usize
+}
+
+/// What came of one line of the feed
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolPriced {
+    /// empty if the line was too broken to have one
+    pub symbol: String,
+    pub priced: Priced,
+}
+
+struct ShardMessage {
+    idx: usize,
+    seq: Option<u64>,
+    symbol: String,
+    message: OwnedMessage,
+}
+
+// tells the merge where to find each message's result
+enum Route {
+    Shard(usize, Option<SeqIssue>),
+    Done(SymbolPriced), // never made it to a book
+}
+
+/// Routes lines to the workers, false once nobody's listening and the reader can stop
+pub struct ShardFeeder {
+    tracker: SequenceTracker,
+    workers: Vec<Producer<ShardMessage>>,
+    routes: Producer<Route>,
+}
+
+impl ShardFeeder {
+    pub fn feed(&mut self, idx: usize, line: &str) -> bool {
+        let (symbol, rest) = split_symbol(line);
+        let (message, seq) = match Message::parse_with_seq(rest) {
+            Ok(parsed) => parsed,
+            Err(err) => return self.done(idx, symbol, Err(BookError::from(err)), None),
+        };
+        let issue = match self.tracker.check(seq, message.timestamp()) {
+            Ok(SeqVerdict::Apply) => self.tracker.last_issue(),
+            Ok(SeqVerdict::Drop) => {
+                let issue = self.tracker.last_issue();
+                return self.done(idx, symbol, Ok(Vec::new()), issue);
+            }
+            Err(issue) => {
+                let refused = Err(BookError::Sequence(issue));
+                return self.done(idx, symbol, refused, Some(issue));
+            }
+        };
+        let shard = shard_of(symbol, self.workers.len());
+        let message = ShardMessage {
+            idx,
+            seq,
+            symbol: symbol.to_string(),
+            message: OwnedMessage::from(message),
+        };
+        // the route goes first, so the merge never waits on a worker that's waiting on it
+        self.routes.push(Route::Shard(shard, issue)).is_ok()
+            && self.workers[shard].push(message).is_ok()
+    }
+
+    fn done(
+        &mut self,
+        idx: usize,
+        symbol: &str,
+        events: Result<Vec<PricerEvent>, BookError>,
+        issue: Option<SeqIssue>,
+    ) -> bool {
+        let priced = SymbolPriced {
+            symbol: symbol.to_string(),
+            priced: Priced { idx, events, issue },
+        };
+        self.routes.push(Route::Done(priced)).is_ok()
+    }
+}
+
+type Books<T> = HashMap<String, Pricer<T>>;
+
+/// Prices on shards worker threads, reading on a thread of its own through parse and
+/// writing on the calling thread through write, with capacity messages queued between
+/// each. new_pricer makes the pricer for a symbol the first time it shows up, tracker
+/// checks sequence numbers (or timestamps) across the whole feed. Errors stop
+/// everything like price_pipelined. Returns every symbol's pricer
+pub fn price_sharded<T, N, P, W>(
+    shards: usize,
+    capacity: usize,
+    new_pricer: N,
+    tracker: SequenceTracker,
+    parse: P,
+    mut write: W,
+) -> Result<BTreeMap<String, Pricer<T>>, String>
+where
+    T: IdPriceCache + Send,
+    N: Fn() -> Pricer<T> + Sync,
+    P: FnOnce(&mut ShardFeeder) -> Result<(), String> + Send,
+    W: FnMut(SymbolPriced) -> Result<(), String>,
+{
+    let shards = shards.max(1);
+    let (route_tx, routes) = spsc::channel(capacity);
+    let mut inputs = Vec::with_capacity(shards);
+    let mut outputs = Vec::with_capacity(shards);
+    thread::scope(|scope| {
+        let mut workers = Vec::with_capacity(shards);
+        for _ in 0..shards {
+            let (input, messages) = spsc::channel(capacity);
+            let (to_merge, output) = spsc::channel(capacity);
+            inputs.push(input);
+            outputs.push(output);
+            let new_pricer = &new_pricer;
+            workers.push(scope.spawn(move || price_shard(messages, to_merge, new_pricer)));
+        }
+        let mut feeder = ShardFeeder {
+            tracker,
+            workers: inputs,
+            routes: route_tx,
+        };
+        let reader = scope.spawn(move || parse(&mut feeder));
+        let written = merge(routes, outputs, &mut write);
+        let parsed = reader.join().expect("reader thread panicked");
+        let mut books = BTreeMap::new();
+        for worker in workers {
+            books.extend(worker.join().expect("shard worker panicked"));
+        }
+        written.and(parsed).map(|()| books)
+    })
+}
+
+fn price_shard<T: IdPriceCache, N: Fn() -> Pricer<T>>(
+    messages: Consumer<ShardMessage>,
+    mut to_merge: Producer<SymbolPriced>,
+    new_pricer: &N,
+) -> Books<T> {
+    let mut books = Books::new();
+    for ShardMessage {
+        idx,
+        seq,
+        symbol,
+        message,
+    } in messages
+    {
+        let events = {
+            let pricer = books.entry(symbol.clone()).or_insert_with(new_pricer);
+            // the feed's sequence was checked already, see ShardFeeder.
+            // Without sequence numbers events go by place in the whole feed, not the symbol's
+            let sequence = seq.unwrap_or(idx as u64 + 1);
+            pricer
+                .book_mut()
+                .apply(&message.as_message())
+                .map(|()| pricer.reprice(sequence).to_vec())
+        };
+        let priced = SymbolPriced {
+            symbol,
+            priced: Priced {
+                idx,
+                events,
+                issue: None,
+            },
+        };
+        if to_merge.push(priced).is_err() {
+            break; // the merge gave up
+        }
+    }
+    books
+}
+
+// on the calling thread, dropping the queues on an error stops everyone else
+fn merge<W: FnMut(SymbolPriced) -> Result<(), String>>(
+    routes: Consumer<Route>,
+    mut outputs: Vec<Consumer<SymbolPriced>>,
+    write: &mut W,
+) -> Result<(), String> {
+    for route in routes {
+        let priced = match route {
+            Route::Done(priced) => priced,
+            Route::Shard(shard, issue) => match outputs[shard].pop() {
+                Some(mut priced) => {
+                    priced.priced.issue = issue;
+                    priced
+                }
+                None => break, // the reader stopped before handing it over
+            },
+        };
+        write(priced)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use orderbook::OrderBook;
+    use sequence::SeqPolicy;
+
+    fn pricer() -> Pricer<IdPriceCacheFnvMap> {
+        Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()))
+    }
+
+    // the basic feed for three symbols, each with its prices shifted a bit
+    fn feed() -> Vec<String> {
+        let basic = include_str!("../data/basic.in.txt");
+        let mut lines = Vec::new();
+        for line in basic.lines() {
+            for (symbol, shift) in &[("AAPL", "1"), ("MSFT", "2"), ("IBM", "3")] {
+                // same ids on every symbol, each book has its own
+                let line = line.replace(" 44.", &format!(" 4{}.", shift));
+                let seq = lines.len() + 1;
+                lines.push(format!("{} {} {}", symbol, line, seq));
+            }
+        }
+        lines
+    }
+
+    fn run(shards: usize, lines: &[String]) -> Vec<(usize, String, Vec<PricerEvent>)> {
+        let mut out = Vec::new();
+        price_sharded(
+            shards,
+            4,
+            pricer,
+            SequenceTracker::new(SeqPolicy::Warn),
+            |feeder| {
+                for (idx, line) in lines.iter().enumerate() {
+                    if !feeder.feed(idx, line) {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+            |priced| {
+                let events = priced.priced.events.unwrap_or_default();
+                out.push((priced.priced.idx, priced.symbol, events));
+                Ok(())
+            },
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn split_symbols() {
+        assert_eq!(
+            split_symbol("AAPL 28800538 A b S 44.26 100"),
+            ("AAPL", "28800538 A b S 44.26 100")
+        );
+        assert_eq!(split_symbol("AAPL"), ("AAPL", ""));
+        assert!(shard_of("AAPL", 4) < 4);
+        assert_eq!(shard_of("AAPL", 4), shard_of("AAPL", 4));
+    }
+
+    #[test]
+    fn same_output_whatever_the_shard_count() {
+        let lines = feed();
+        let one = run(1, &lines);
+        assert_eq!(one.len(), lines.len());
+        assert!(one.iter().enumerate().all(|(n, &(idx, _, _))| n == idx));
+        for shards in 2..5 {
+            assert_eq!(run(shards, &lines), one);
+        }
+
+        // each symbol priced as if it had the feed to itself
+        let mut reference = HashMap::new();
+        for (idx, line) in lines.iter().enumerate() {
+            let (symbol, rest) = split_symbol(line);
+            let pricer = reference.entry(symbol).or_insert_with(pricer);
+            let events = pricer.try_process(rest).unwrap().to_vec();
+            assert_eq!(one[idx].2, events);
+        }
+    }
+
+    #[test]
+    fn sequence_is_checked_across_the_feed() {
+        // each symbol only sees every third sequence number, which is no gap at all
+        let mut lines = feed();
+        lines.push("IBM 28816300 R l 10 200".to_string());
+        lines.push("IBM 28816301 X".to_string());
+        let mut seen = Vec::new();
+        price_sharded(
+            2,
+            4,
+            pricer,
+            SequenceTracker::new(SeqPolicy::Warn),
+            |feeder| {
+                for (idx, line) in lines.iter().enumerate() {
+                    feeder.feed(idx, line);
+                }
+                Ok(())
+            },
+            |priced| {
+                if priced.priced.issue.is_some() || priced.priced.events.is_err() {
+                    seen.push((priced.priced.idx, priced.priced.issue));
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        let last = lines.len() - 1;
+        assert_eq!(
+            seen,
+            vec![
+                (
+                    last - 1,
+                    Some(SeqIssue::Gap {
+                        expected: lines.len() as u64 - 1,
+                        found: 200
+                    })
+                ),
+                (last, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use std::cell::UnsafeCell;
+use std::hint;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Bounded single producer single consumer queue. Slots are a power of two,
+// head and tail count up forever and are masked into them. Each side keeps
+// its own copy of where it is and only reads the other side's counter when
+// it looks full or empty, so the two threads rarely touch the same cache line.
+
+// keeps head and tail off each other's cache line
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    head: CachePadded<AtomicUsize>, // next slot to pop, only the consumer moves it
+    tail: CachePadded<AtomicUsize>, // next slot to push, only the producer moves it
+    producer_gone: AtomicBool,
+    consumer_gone: AtomicBool,
+}
+
+// slots between head and tail belong to the consumer, the rest to the producer
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.0.get_mut();
+        let mut head = *self.head.0.get_mut();
+        while head != tail {
+            unsafe { ptr::drop_in_place((*self.slots[head & self.mask].get()).as_mut_ptr()) };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// Pushing end of a channel
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    tail: usize,
+    head_seen: usize,
+}
+
+/// Popping end of a channel
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    head: usize,
+    tail_seen: usize,
+}
+
+/// Queue holding at least capacity values, rounded up to a power of two
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let ring = Arc::new(Ring {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        mask: capacity - 1,
+        head: CachePadded(AtomicUsize::new(0)),
+        tail: CachePadded(AtomicUsize::new(0)),
+        producer_gone: AtomicBool::new(false),
+        consumer_gone: AtomicBool::new(false),
+    });
+    (
+        Producer {
+            ring: ring.clone(),
+            tail: 0,
+            head_seen: 0,
+        },
+        Consumer {
+            ring,
+            head: 0,
+            tail_seen: 0,
+        },
+    )
+}
+
+// spins for a while before handing the core over
+struct Backoff(u32);
+
+impl Backoff {
+    fn wait(&mut self) {
+        if self.0 < 64 {
+            hint::spin_loop();
+            self.0 += 1;
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+impl<T> Producer<T> {
+    /// Gives the value back if the queue is full
+    pub fn try_push(&mut self, value: T) -> Result<(), T> {
+        let capacity = self.ring.mask + 1;
+        if self.tail.wrapping_sub(self.head_seen) == capacity {
+            self.head_seen = self.ring.head.0.load(Ordering::Acquire);
+            if self.tail.wrapping_sub(self.head_seen) == capacity {
+                return Err(value);
+            }
+        }
+        unsafe {
+            (*self.ring.slots[self.tail & self.ring.mask].get())
+                .as_mut_ptr()
+                .write(value)
+        };
+        self.tail = self.tail.wrapping_add(1);
+        self.ring.tail.0.store(self.tail, Ordering::Release);
+        Ok(())
+    }
+
+    /// Waits for room, gives the value back if the consumer has gone
+    pub fn push(&mut self, mut value: T) -> Result<(), T> {
+        let mut backoff = Backoff(0);
+        loop {
+            match self.try_push(value) {
+                Ok(()) => return Ok(()),
+                Err(back) => value = back,
+            }
+            if self.ring.consumer_gone.load(Ordering::Acquire) {
+                return Err(value);
+            }
+            backoff.wait();
+        }
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.ring.producer_gone.store(true, Ordering::Release);
+    }
+}
+
+impl<T> Consumer<T> {
+    pub fn try_pop(&mut self) -> Option<T> {
+        if self.head == self.tail_seen {
+            self.tail_seen = self.ring.tail.0.load(Ordering::Acquire);
+            if self.head == self.tail_seen {
+                return None;
+            }
+        }
+        let value = unsafe {
+            (*self.ring.slots[self.head & self.ring.mask].get())
+                .as_ptr()
+                .read()
+        };
+        self.head = self.head.wrapping_add(1);
+        self.ring.head.0.store(self.head, Ordering::Release);
+        Some(value)
+    }
+
+    /// Waits for a value, None once the producer has gone and everything it pushed is popped
+    pub fn pop(&mut self) -> Option<T> {
+        let mut backoff = Backoff(0);
+        loop {
+            if let Some(value) = self.try_pop() {
+                return Some(value);
+            }
+            if self.ring.producer_gone.load(Ordering::Acquire) {
+                // it may have pushed one last value before going
+                return self.try_pop();
+            }
+            backoff.wait();
+        }
+    }
+
+    /// pop, calling on_idle every idle spent waiting
+    pub fn pop_or_idle<F: FnMut()>(&mut self, idle: Duration, mut on_idle: F) -> Option<T> {
+        let mut backoff = Backoff(0);
+        let mut since = Instant::now();
+        loop {
+            if let Some(value) = self.try_pop() {
+                return Some(value);
+            }
+            if self.ring.producer_gone.load(Ordering::Acquire) {
+                return self.try_pop();
+            }
+            if since.elapsed() >= idle {
+                on_idle();
+                since = Instant::now();
+            }
+            backoff.wait();
+        }
+    }
+}
+
+impl<T> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.pop()
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.ring.consumer_gone.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_up_and_drains_in_order() {
+        let (mut tx, mut rx) = channel(3);
+        for n in 0..4 {
+            assert_eq!(tx.try_push(n), Ok(()));
+        }
+        assert_eq!(tx.try_push(4), Err(4));
+        assert_eq!(rx.try_pop(), Some(0));
+        assert_eq!(tx.try_push(4), Ok(()));
+        drop(tx);
+        assert_eq!(rx.collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn across_threads() {
+        let (mut tx, rx) = channel(16);
+        let producer = thread::spawn(move || {
+            for n in 0..100000u64 {
+                tx.push(n).unwrap();
+            }
+        });
+        let mut expected = 0;
+        for n in rx {
+            assert_eq!(n, expected);
+            expected += 1;
+        }
+        producer.join().unwrap();
+        assert_eq!(expected, 100000);
+    }
+
+    #[test]
+    fn push_fails_once_consumer_is_gone() {
+        let (mut tx, rx) = channel(1);
+        tx.push(1).unwrap();
+        drop(rx);
+        assert_eq!(tx.push(2), Err(2));
+    }
+
+    #[test]
+    fn values_left_behind_are_dropped() {
+        let value = Arc::new(());
+        let (mut tx, mut rx) = channel(4);
+        for _ in 0..3 {
+            tx.push(value.clone()).unwrap();
+        }
+        rx.pop();
+        assert_eq!(Arc::strong_count(&value), 3);
+        drop((tx, rx));
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt::{self, Display, Formatter};
+
+use idpricecache::IdPriceCache;
+use orderbook::{BookError, CrossedEpisode, OrderBook};
+use orders::{Message, ParseError};
+use orderside::OrderSide;
+
+/// What a feed looked like once it went through the book
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FeedStats {
+    pub messages: u64,
+    pub adds: u64,
+    pub reduces: u64,
+    pub malformed: u64,
+    pub unknown_orders: u64,
+    /// most price levels with orders resting on them at once
+    pub max_bid_levels: usize,
+    pub max_ask_levels: usize,
+    /// levels the book ended up holding, emptied ones included
+    pub bid_levels_allocated: usize,
+    pub ask_levels_allocated: usize,
+    pub cache_entries: usize,
+    /// ids OrderIds had to remember, zero when hashing them
+    pub stored_ids: usize,
+    /// times the book went locked or crossed, and for how long in total
+    pub crossed_episodes: usize,
+    pub crossed_duration: i64,
+    /// gaps, duplicates, late messages and timestamps going back
+    pub sequence_issues: u64,
+}
+
+/// One "key value" line per counter
+impl Display for FeedStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "messages {}", self.messages)?;
+        writeln!(f, "adds {}", self.adds)?;
+        writeln!(f, "reduces {}", self.reduces)?;
+        writeln!(f, "malformed {}", self.malformed)?;
+        writeln!(f, "unknown_orders {}", self.unknown_orders)?;
+        writeln!(f, "max_bid_levels {}", self.max_bid_levels)?;
+        writeln!(f, "max_ask_levels {}", self.max_ask_levels)?;
+        writeln!(f, "bid_levels_allocated {}", self.bid_levels_allocated)?;
+        writeln!(f, "ask_levels_allocated {}", self.ask_levels_allocated)?;
+        writeln!(f, "cache_entries {}", self.cache_entries)?;
+        writeln!(f, "stored_ids {}", self.stored_ids)?;
+        writeln!(f, "crossed_episodes {}", self.crossed_episodes)?;
+        writeln!(f, "crossed_duration {}", self.crossed_duration)?;
+        writeln!(f, "sequence_issues {}", self.sequence_issues)
+    }
+}
+
+/// Feeds messages to a book and counts as it goes
+pub struct StatsCollector<T: IdPriceCache + Sized> {
+    book: OrderBook<T>,
+    stats: FeedStats,
+}
+
+impl<T: IdPriceCache + Sized> StatsCollector<T> {
+    pub fn new(book: OrderBook<T>) -> Self {
+        StatsCollector {
+            book,
+            stats: FeedStats::default(),
+        }
+    }
+
+    pub fn book(&self) -> &OrderBook<T> {
+        &self.book
+    }
+
+    pub fn record_line(&mut self, line: &str) {
+        self.record(Message::parse_with_seq(line))
+    }
+
+    /// Takes a message with its sequence number, if it has one
+    pub fn record(&mut self, parsed: Result<(Message, Option<u64>), ParseError>) {
+        self.stats.messages += 1;
+        let (message, seq) = match parsed {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                self.stats.malformed += 1;
+                return;
+            }
+        };
+        match message {
+            Message::Add { .. } => self.stats.adds += 1,
+            Message::Reduce { .. } => self.stats.reduces += 1,
+        }
+        match self.book.apply_sequenced(&message, seq) {
+            Ok(()) => {}
+            Err(BookError::UnknownOrder(_)) => {
+                self.stats.unknown_orders += 1;
+                return;
+            }
+            // counted by the tracker, and kept by OrderIds::collisions
+            Err(BookError::Sequence(_)) | Err(BookError::IdCollision(_)) => return,
+            Err(BookError::Parse(_)) => unreachable!("message was already parsed"),
+            // out of range, like the sizes parsing turns away
+            Err(BookError::Overflow) => {
+                self.stats.malformed += 1;
+                return;
+            }
+        }
+        // only the side the message touched can have changed
+        let side = self.book.last_action_side;
+        let levels = self.book.levels(side).count();
+        let max_levels = match side {
+            OrderSide::Bid => &mut self.stats.max_bid_levels,
+            OrderSide::Ask => &mut self.stats.max_ask_levels,
+        };
+        if levels > *max_levels {
+            *max_levels = levels;
+        }
+    }
+
+    pub fn stats(&self) -> FeedStats {
+        let current = self.book.current_episode();
+        FeedStats {
+            bid_levels_allocated: self.book.allocated_levels(OrderSide::Bid),
+            ask_levels_allocated: self.book.allocated_levels(OrderSide::Ask),
+            cache_entries: self.book.cache().len(),
+            stored_ids: self.book.ids().len(),
+            crossed_episodes: self.book.cleared_episodes() as usize + current.iter().count(),
+            // durations can be anything at all when timestamps are, see CrossedEpisode
+            crossed_duration: current
+                .map_or(0, CrossedEpisode::duration)
+                .saturating_add(self.book.cleared_duration()),
+            sequence_issues: self.book.sequence().issues(),
+            ..self.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use orderids::IdMode;
+
+    fn collect(lines: &[&str], id_mode: IdMode) -> FeedStats {
+        let book = OrderBook::with_id_mode(200, IdPriceCacheFnvMap::default(), id_mode);
+        let mut collector = StatsCollector::new(book);
+        for line in lines {
+            collector.record_line(line);
+        }
+        collector.stats()
+    }
+
+    #[test]
+    fn counts_basic_feed() {
+        let lines: Vec<&str> = include_str!("../data/basic.in.txt").lines().collect();
+        let stats = collect(&lines, IdMode::Hash);
+        assert_eq!(stats.messages, lines.len() as u64);
+        assert_eq!(stats.adds + stats.reduces, stats.messages);
+        assert_eq!(stats.malformed, 0);
+        assert_eq!(stats.unknown_orders, 0);
+        assert_eq!(stats.cache_entries as u64, stats.adds);
+        assert_eq!(stats.stored_ids, 0);
+        assert!(stats.max_bid_levels <= stats.bid_levels_allocated);
+        assert!(stats.max_ask_levels <= stats.ask_levels_allocated);
+        assert_eq!(stats.crossed_episodes, 0);
+        assert_eq!(stats.sequence_issues, 0);
+    }
+
+    #[test]
+    fn counts_bad_messages_and_levels() {
+        let stats = collect(
+            &[
+                "28800538 A b S 44.26 100",
+                "28800539 A c S 44.27 100",
+                "28800540 A d B 44.10 100",
+                "28800541 R b 100",
+                "28800542 A e S 44.28 100",
+                "28800543 R x 1",
+                "28800544 A f",
+                "28800545 Q",
+                "28800546 A g B 44.27 10", // locks against c
+                "28800550 R g 10",         // clears
+                "28800555 A h B 44.27 10", // locks again until the end
+                "28800557 A i B 44.00 10",
+            ],
+            IdMode::Intern,
+        );
+        assert_eq!(
+            stats,
+            FeedStats {
+                messages: 12,
+                adds: 7,
+                reduces: 3,
+                malformed: 2,
+                unknown_orders: 1,
+                max_bid_levels: 3,
+                max_ask_levels: 2,
+                bid_levels_allocated: 3,
+                ask_levels_allocated: 3,
+                cache_entries: 7,
+                stored_ids: 7,
+                crossed_episodes: 2,
+                crossed_duration: 6,
+                sequence_issues: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn counts_sequence_issues() {
+        let stats = collect(
+            &[
+                "28800538 A b S 44.26 100 1",
+                "28800539 A c S 44.27 100 3",
+                "28800539 A c S 44.27 100 3",
+            ],
+            IdMode::Hash,
+        );
+        assert_eq!(stats.sequence_issues, 2);
+        assert_eq!(stats.cache_entries, 2);
+    }
+
+    #[test]
+    fn crossed_duration_saturates() {
+        let stats = collect(
+            &[
+                "0 A b S 44.26 100",
+                "0 A c B 44.30 100", // crosses
+                "9223372036854775807 R c 100",
+                "0 A d B 44.30 100", // crosses again
+                "9223372036854775807 A e S 45.00 100",
+            ],
+            IdMode::Hash,
+        );
+        assert_eq!(stats.crossed_episodes, 2);
+        assert_eq!(stats.crossed_duration, i64::MAX);
+    }
+
+    #[test]
+    fn display_lists_every_counter() {
+        let text = FeedStats::default().to_string();
+        assert_eq!(text.lines().count(), 14);
+        assert!(text.starts_with("messages 0\nadds 0\n"));
+    }
+}
@@ -0,0 +1,248 @@
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use amount::Amount;
+use idpricecache::IdPriceCache;
+use orderbook::Depth;
+use orderside::OrderSide;
+use pricer::Pricer;
+
+// Top of the book published by the thread applying messages for any number of
+// readers, through a seqlock. The summary is kept as atomic words: the writer
+// makes the version odd, stores the words and makes it even again, a reader
+// copies the words and tries again if the version moved meanwhile. The writer
+// never waits on a reader.
+
+/// What readers get to see of the book
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BookSummary {
+    /// messages the pricer had been through when this was published
+    pub messages: u64,
+    /// of the last message applied
+    pub timestamp: i64,
+    pub best_bid: Option<(Amount, Depth)>,
+    pub best_ask: Option<(Amount, Depth)>,
+    pub bid_size: i64,
+    pub ask_size: i64,
+    pub target_size: i64,
+    /// last income reported for selling target_size shares (S)
+    pub sell_amount: Option<Amount>,
+    /// last expense reported for buying target_size shares (B)
+    pub buy_amount: Option<Amount>,
+}
+
+impl BookSummary {
+    /// The pricer's book as it stands, amounts for its first target size
+    pub fn of<T: IdPriceCache>(pricer: &Pricer<T>) -> Self {
+        let book = pricer.book();
+        BookSummary {
+            messages: pricer.messages(),
+            timestamp: book.last_action_timestamp,
+            best_bid: book.best_bid(),
+            best_ask: book.best_ask(),
+            bid_size: book.total_size(OrderSide::Bid),
+            ask_size: book.total_size(OrderSide::Ask),
+            target_size: book.target_size(),
+            sell_amount: pricer.last_reported(OrderSide::Ask),
+            buy_amount: pricer.last_reported(OrderSide::Bid),
+        }
+    }
+
+    fn to_words(self) -> [u64; WORDS] {
+        let mut present = 0;
+        let mut flag = |bit: u64, set: bool| {
+            if set {
+                present |= bit;
+            }
+        };
+        flag(BEST_BID, self.best_bid.is_some());
+        flag(BEST_ASK, self.best_ask.is_some());
+        flag(SELL, self.sell_amount.is_some());
+        flag(BUY, self.buy_amount.is_some());
+        let (bid, bid_depth) = self.best_bid.unwrap_or_default();
+        let (ask, ask_depth) = self.best_ask.unwrap_or_default();
+        [
+            present,
+            self.messages,
+            self.timestamp as u64,
+            bid.as_int as u64,
+            bid_depth as u64,
+            ask.as_int as u64,
+            ask_depth as u64,
+            self.bid_size as u64,
+            self.ask_size as u64,
+            self.target_size as u64,
+            self.sell_amount.unwrap_or_default().as_int as u64,
+            self.buy_amount.unwrap_or_default().as_int as u64,
+        ]
+    }
+
+    fn from_words(words: &[u64; WORDS]) -> Self {
+        let present = |bit: u64| words[0] & bit != 0;
+        let amount = |word: u64| Amount {
+            as_int: word as i64,
+        };
+        BookSummary {
+            messages: words[1],
+            timestamp: words[2] as i64,
+            best_bid: Some((amount(words[3]), words[4] as Depth)).filter(|_| present(BEST_BID)),
+            best_ask: Some((amount(words[5]), words[6] as Depth)).filter(|_| present(BEST_ASK)),
+            bid_size: words[7] as i64,
+            ask_size: words[8] as i64,
+            target_size: words[9] as i64,
+            sell_amount: Some(amount(words[10])).filter(|_| present(SELL)),
+            buy_amount: Some(amount(words[11])).filter(|_| present(BUY)),
+        }
+    }
+}
+
+const WORDS: usize = 12;
+// bits of the first word, saying which of the options are there
+const BEST_BID: u64 = 1;
+const BEST_ASK: u64 = 1 << 1;
+const SELL: u64 = 1 << 2;
+const BUY: u64 = 1 << 3;
+
+struct Published {
+    version: AtomicU64, // odd while the writer is halfway through
+    words: [AtomicU64; WORDS],
+}
+
+/// The one thread that publishes, see published
+pub struct SummaryWriter(Arc<Published>);
+
+/// Loads the latest summary, clone one for every reading thread
+#[derive(Clone)]
+pub struct SummaryReader(Arc<Published>);
+
+/// A writer and a reader for the same summary, starting out as BookSummary::default()
+pub fn published() -> (SummaryWriter, SummaryReader) {
+    let shared = Arc::new(Published {
+        version: AtomicU64::new(0),
+        words: Default::default(),
+    });
+    (SummaryWriter(shared.clone()), SummaryReader(shared))
+}
+
+impl SummaryWriter {
+    pub fn publish(&mut self, summary: &BookSummary) {
+        let shared = &self.0;
+        let version = shared.version.load(Ordering::Relaxed);
+        shared.version.store(version + 1, Ordering::Relaxed);
+        // the odd version has to be visible before any of the words change
+        fence(Ordering::Release);
+        for (slot, word) in shared.words.iter().zip(summary.to_words().iter()) {
+            slot.store(*word, Ordering::Relaxed);
+        }
+        shared.version.store(version + 2, Ordering::Release);
+    }
+
+    /// publish(&BookSummary::of(pricer))
+    pub fn publish_pricer<T: IdPriceCache>(&mut self, pricer: &Pricer<T>) {
+        self.publish(&BookSummary::of(pricer));
+    }
+}
+
+impl SummaryReader {
+    /// The last summary published in full, never half of one and half of the next
+    pub fn load(&self) -> BookSummary {
+        let shared = &self.0;
+        let mut spins = 0u32;
+        loop {
+            let before = shared.version.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                let mut words = [0; WORDS];
+                for (word, slot) in words.iter_mut().zip(shared.words.iter()) {
+                    *word = slot.load(Ordering::Relaxed);
+                }
+                // the words have to be read before the version is checked again
+                fence(Ordering::Acquire);
+                if shared.version.load(Ordering::Relaxed) == before {
+                    return BookSummary::from_words(&words);
+                }
+            }
+            spins += 1;
+            if spins % 64 == 0 {
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// How many summaries were published so far
+    pub fn version(&self) -> u64 {
+        self.0.version.load(Ordering::Acquire) / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idpricecache::IdPriceCacheFnvMap;
+    use orderbook::OrderBook;
+
+    #[test]
+    fn summary_of_the_basic_feed() {
+        let mut pricer = Pricer::new(OrderBook::new(200, IdPriceCacheFnvMap::default()));
+        let (mut writer, reader) = published();
+        assert_eq!(reader.load(), BookSummary::default());
+        for line in include_str!("../data/basic.in.txt").lines().take(4) {
+            pricer.process(line);
+            writer.publish_pricer(&pricer);
+        }
+        assert_eq!(reader.version(), 4);
+        assert_eq!(
+            reader.load(),
+            BookSummary {
+                messages: 4,
+                timestamp: 28800758,
+                best_bid: Some((Amount::new_from_str("44.18"), 157)),
+                best_ask: None,
+                bid_size: 257,
+                ask_size: 0,
+                target_size: 200,
+                sell_amount: Some(Amount::new_from_str("8832.56")),
+                buy_amount: None,
+            }
+        );
+    }
+
+    #[test]
+    fn readers_never_see_a_torn_summary() {
+        let summary = |n: i64| BookSummary {
+            messages: n as u64,
+            timestamp: n,
+            bid_size: -n,
+            best_bid: Some((Amount { as_int: n }, n)).filter(|_| n % 2 == 1),
+            buy_amount: Some(Amount { as_int: n * 3 }),
+            ..BookSummary::default()
+        };
+        let (mut writer, reader) = published();
+        writer.publish(&summary(0));
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let reader = reader.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 20000 {
+                        let summary = reader.load();
+                        // every field of a summary is written from the same n
+                        let n = summary.messages as i64;
+                        assert_eq!(summary.timestamp, n);
+                        assert_eq!(summary.bid_size, -n);
+                        assert_eq!(summary.best_bid.is_some(), n % 2 == 1);
+                        assert_eq!(summary.buy_amount.map(|amount| amount.as_int), Some(n * 3));
+                        assert!(summary.messages >= last);
+                        last = summary.messages;
+                    }
+                })
+            })
+            .collect();
+        for n in 1..=20000 {
+            writer.publish(&summary(n));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::fmt::{self, Display, Formatter};
+
+use fnv::FnvHashMap;
+
+use amount::Amount;
+use idpricecache::IdPriceCacheFnvMap;
+use orderbook::{BookState, OrderBook};
+use orderids::IdMode;
+use orders::{Message, ParseError};
+use sequence::{SeqIssue, SeqPolicy, SeqVerdict, SequenceTracker};
+
+/// Something wrong with a feed. Validation carries on past every one of them
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Issue {
+    Malformed(ParseError),
+    /// reduce for an order that was never added, or is already gone
+    UnknownOrder(String),
+    /// add for an order that is still resting
+    DuplicateOrder(String),
+    /// reduce for more than the order has left
+    OverReduction {
+        id: String,
+        remaining: i64,
+        requested: i64,
+    },
+    /// best bid went above best ask, reported once each time it happens
+    Crossed {
+        bid: Amount,
+        ask: Amount,
+    },
+    /// sequence numbers or timestamps out of place, duplicates are kept out
+    Sequence(SeqIssue),
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Issue::Malformed(ref err) => write!(f, "malformed message: {}", err),
+            Issue::UnknownOrder(ref id) => write!(f, "reduce for unknown order {}", id),
+            Issue::DuplicateOrder(ref id) => write!(f, "order {} added while still resting", id),
+            Issue::OverReduction {
+                ref id,
+                remaining,
+                requested,
+            } => write!(
+                f,
+                "order {} reduced by {} with only {} left",
+                id, requested, remaining
+            ),
+            Issue::Crossed { bid, ask } => write!(f, "book crossed, bid {} above ask {}", bid, ask),
+            Issue::Sequence(ref issue) => write!(f, "{}", issue),
+        }
+    }
+}
+
+/// Replays a feed into an OrderBook, tracking what every order has left
+/// so it can tell when the feed stops making sense.
+/// Bad messages are reported and kept out of the book
+pub struct Validator {
+    book: OrderBook<IdPriceCacheFnvMap>,
+    remaining: FnvHashMap<String, i64>,
+    sequence: SequenceTracker,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Validator::new()
+    }
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator {
+            // interned ids, so colliding hashes can't hide a problem
+            book: OrderBook::with_id_mode(1, IdPriceCacheFnvMap::default(), IdMode::Intern),
+            remaining: FnvHashMap::default(),
+            sequence: SequenceTracker::new(SeqPolicy::Warn),
+        }
+    }
+
+    pub fn book(&self) -> &OrderBook<IdPriceCacheFnvMap> {
+        &self.book
+    }
+
+    /// Size left on every resting order
+    pub fn resting(&self) -> &FnvHashMap<String, i64> {
+        &self.remaining
+    }
+
+    pub fn check_line(&mut self, line: &str) -> Vec<Issue> {
+        match Message::parse_with_seq(line) {
+            Ok((message, seq)) => self.check_sequenced(&message, seq),
+            Err(err) => vec![Issue::Malformed(err)],
+        }
+    }
+
+    pub fn check(&mut self, message: &Message) -> Vec<Issue> {
+        self.check_sequenced(message, None)
+    }
+
+    /// Checks the sequence number, or the timestamp without one, then the message itself
+    pub fn check_sequenced(&mut self, message: &Message, seq: Option<u64>) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let verdict = self.sequence.check(seq, message.timestamp());
+        if let Some(issue) = self.sequence.last_issue() {
+            issues.push(Issue::Sequence(issue));
+        }
+        if verdict != Ok(SeqVerdict::Apply) {
+            return issues;
+        }
+        match *message {
+            Message::Add { id, size, .. } => {
+                if self.remaining.contains_key(id) {
+                    issues.push(Issue::DuplicateOrder(id.to_string()));
+                    return issues;
+                }
+                self.remaining.insert(id.to_string(), size);
+                self.apply(message);
+            }
+            Message::Reduce {
+                timestamp,
+                id,
+                size,
+            } => {
+                let remaining = match self.remaining.get(id) {
+                    Some(&remaining) => remaining,
+                    None => {
+                        issues.push(Issue::UnknownOrder(id.to_string()));
+                        return issues;
+                    }
+                };
+                let size = if size > remaining {
+                    issues.push(Issue::OverReduction {
+                        id: id.to_string(),
+                        remaining,
+                        requested: size,
+                    });
+                    remaining
+                } else {
+                    size
+                };
+                if size == remaining {
+                    self.remaining.remove(id);
+                } else {
+                    self.remaining.insert(id.to_string(), remaining - size);
+                }
+                self.apply(&Message::Reduce {
+                    timestamp,
+                    id,
+                    size,
+                });
+            }
+        }
+        let crossed = self
+            .book
+            .state_change()
+            .is_some_and(|change| change.to == BookState::Crossed);
+        if crossed {
+            if let (Some((bid, _)), Some((ask, _))) = (self.book.best_bid(), self.book.best_ask()) {
+                issues.push(Issue::Crossed { bid, ask });
+            }
+        }
+        issues
+    }
+
+    fn apply(&mut self, message: &Message) {
+        // remaining already vouched for the id, so the book can't refuse it
+        if let Err(err) = self.book.apply(message) {
+            panic!("validated message {} rejected: {}", message, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orderside::OrderSide;
+
+    fn check_all(lines: &[&str]) -> Vec<Issue> {
+        let mut validator = Validator::new();
+        lines
+            .iter()
+            .flat_map(|line| validator.check_line(line))
+            .collect()
+    }
+
+    #[test]
+    fn basic_feed_is_clean() {
+        let mut validator = Validator::new();
+        for line in include_str!("../data/basic.in.txt").lines() {
+            assert_eq!(validator.check_line(line), vec![], "{}", line);
+        }
+    }
+
+    #[test]
+    fn reports_malformed_and_unknown() {
+        assert_eq!(
+            check_all(&["28800538 A b S 44.26", "28800744 R b 20"]),
+            vec![
+                Issue::Malformed(ParseError::WrongFieldCount {
+                    expected: 6,
+                    found: 5
+                }),
+                Issue::UnknownOrder("b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn over_reduction_removes_what_is_left() {
+        let mut validator = Validator::new();
+        validator.check_line("28800538 A b S 44.26 100");
+        assert_eq!(
+            validator.check_line("28800744 R b 150"),
+            vec![Issue::OverReduction {
+                id: "b".to_string(),
+                remaining: 100,
+                requested: 150
+            }]
+        );
+        assert_eq!(validator.book().total_size(OrderSide::Ask), 0);
+        assert!(validator.resting().is_empty());
+        // the order is gone now
+        assert_eq!(
+            validator.check_line("28800745 R b 1"),
+            vec![Issue::UnknownOrder("b".to_string())]
+        );
+    }
+
+    #[test]
+    fn duplicate_add_is_kept_out() {
+        let mut validator = Validator::new();
+        validator.check_line("28800538 A b S 44.26 100");
+        assert_eq!(
+            validator.check_line("28800539 A b S 44.26 100"),
+            vec![Issue::DuplicateOrder("b".to_string())]
+        );
+        assert_eq!(validator.book().total_size(OrderSide::Ask), 100);
+        // ids can come back once the order is fully reduced
+        validator.check_line("28800540 R b 100");
+        assert_eq!(validator.check_line("28800541 A b B 44.00 10"), vec![]);
+    }
+
+    #[test]
+    fn crossed_book_reported_once() {
+        let mut validator = Validator::new();
+        let crossed = Issue::Crossed {
+            bid: Amount::new_from_str("44.30"),
+            ask: Amount::new_from_str("44.26"),
+        };
+        assert_eq!(validator.check_line("28800538 A b S 44.26 100"), vec![]);
+        // locked isn't crossed
+        assert_eq!(validator.check_line("28800539 A c B 44.26 100"), vec![]);
+        assert_eq!(
+            validator.check_line("28800540 A d B 44.30 100"),
+            vec![crossed.clone()]
+        );
+        assert_eq!(validator.check_line("28800541 A e B 44.31 100"), vec![]);
+        validator.check_line("28800542 R b 100");
+        assert_eq!(
+            validator.check_line("28800543 A f S 44.28 10"),
+            vec![Issue::Crossed {
+                bid: Amount::new_from_str("44.31"),
+                ask: Amount::new_from_str("44.28"),
+            }]
+        );
+        assert_eq!(
+            crossed.to_string(),
+            "book crossed, bid 44.30 above ask 44.26"
+        );
+    }
+
+    #[test]
+    fn sequence_issues() {
+        assert_eq!(
+            check_all(&[
+                "28800538 A b S 44.26 100 1",
+                "28800539 R b 10 3",
+                "28800539 R b 10 3",
+            ]),
+            vec![
+                Issue::Sequence(SeqIssue::Gap {
+                    expected: 2,
+                    found: 3
+                }),
+                Issue::Sequence(SeqIssue::Duplicate(3)),
+            ]
+        );
+        // the duplicate wasn't applied
+        let mut validator = Validator::new();
+        validator.check_line("28800538 A b S 44.26 100 1");
+        validator.check_line("28800539 R b 10 2");
+        validator.check_line("28800539 R b 10 2");
+        assert_eq!(validator.resting().get("b"), Some(&90));
+
+        assert_eq!(
+            check_all(&["28800538 A b S 44.26 100", "28800537 R b 10"]),
+            vec![Issue::Sequence(SeqIssue::TimestampWentBack {
+                previous: 28800538,
+                found: 28800537
+            })]
+        );
+    }
+}